@@ -1,10 +1,36 @@
-use std::{net::Ipv4Addr, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io,
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use tokio::{
+    sync::{broadcast, mpsc, oneshot, watch},
+    time,
+};
 
-use log::debug;
-use tokio::sync::{mpsc, oneshot, watch};
+use crate::{
+    api::model::Compression,
+    tls::TlsIdentity,
+    util::{default_interface_v4, interface_ipv4_by_name, IpNet},
+};
 
 use super::{
-    device::DeviceActorHandle, http::HttpServerHandle, mission::MissionHandle, model::NodeDevice,
+    device::{DeviceActorHandle, DeviceEvent, DEFAULT_MAX_DEVICES},
+    discovery::{
+        self, AnnounceErrorHandler, DiscoveryCounters, DiscoveryMetrics, PeerFilter,
+        RegisterClients,
+    },
+    favorite::FavoriteHandle,
+    http::HttpServerHandle,
+    mission::{transfer::TransferEvent, MissionHandle, TransferPolicy},
+    model::{sanitize_alias, NodeDevice, WireFormat},
+    send,
+    share::ShareHandle,
 };
 
 #[derive(Clone)]
@@ -16,17 +42,308 @@ pub struct CoreConfig {
     pub store_path: String,
 }
 
+/// How often an announce is multicast each time we make ourselves known.
+#[derive(Debug, Clone, Copy)]
+pub struct AnnounceConfig {
+    pub repeat: u8,
+    /// Delay between two consecutive sends.
+    pub interval: Duration,
+    /// The delay is moved by a random amount up to this either way, so
+    /// devices started together don't keep announcing in lockstep. Keep it
+    /// at most `interval`, delays that would go below zero are cut to zero
+    /// and end up in lockstep again.
+    pub jitter: Duration,
+    /// Multicast TTL (hop limit for v6 groups). Keep it at 1 unless the
+    /// network routes multicast between subnets, otherwise announces leak
+    /// past the local segment for nothing.
+    pub multicast_ttl: u32,
+    /// Whether our multicast is looped back to this host. Turning it off
+    /// saves receiving our own announces, but also hides them from other
+    /// LocalSend instances on the same machine. Not every platform honors
+    /// it, and with `all_interfaces` echoes can still come in over another
+    /// interface, so our own announces are filtered by fingerprint either
+    /// way.
+    pub multicast_loop: bool,
+}
+
+impl Default for AnnounceConfig {
+    fn default() -> Self {
+        AnnounceConfig {
+            repeat: 2,
+            interval: Duration::from_millis(200),
+            jitter: Duration::from_millis(200),
+            multicast_ttl: 1,
+            multicast_loop: true,
+        }
+    }
+}
+
+/// Retry policy for registering with a peer over http.
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterConfig {
+    /// Total attempts, `1` disables retrying.
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for every following one.
+    pub backoff: Duration,
+}
+
+impl Default for RegisterConfig {
+    fn default() -> Self {
+        RegisterConfig {
+            attempts: 3,
+            backoff: Duration::from_millis(200),
+        }
+    }
+}
+
+/// Shared secret sent as a header with every register request. A server
+/// with one configured ignores peers that don't present it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecretHeader {
+    pub name: String,
+    pub value: String,
+}
+
+/// Fingerprints `on_new_node` remembers as seen.
+const NEW_NODES_REMEMBERED: usize = 4 * DEFAULT_MAX_DEVICES;
+
+/// What the official app's http client sends as user agent.
+pub const DEFAULT_USER_AGENT: &str = "Dart/3.5 (dart:io)";
+
+/// Headers sent with every request to a peer, registers and uploads alike.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestHeaders {
+    pub user_agent: String,
+    /// A user agent among them is ignored, `user_agent` is what's sent.
+    pub extra: Vec<(String, String)>,
+}
+
+impl RequestHeaders {
+    pub fn pairs(&self) -> Vec<(String, String)> {
+        let mut pairs = vec![("User-Agent".to_string(), self.user_agent.clone())];
+        pairs.extend(
+            self.extra
+                .iter()
+                .filter(|(name, _)| !name.eq_ignore_ascii_case("user-agent"))
+                .cloned(),
+        );
+        pairs
+    }
+}
+
+impl Default for RequestHeaders {
+    fn default() -> Self {
+        RequestHeaders {
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            extra: Vec::new(),
+        }
+    }
+}
+
+/// Discovery tuning that stays on the rust side, picked up each time the
+/// server starts.
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// Devices that haven't announced within this window are evicted.
+    pub node_ttl: Duration,
+    pub announce: AnnounceConfig,
+    pub register: RegisterConfig,
+    pub secret: Option<SecretHeader>,
+    /// Accept https peers whose certificate doesn't match their fingerprint.
+    /// Only meant for debugging, it defeats the pinning.
+    pub insecure_tls: bool,
+    /// Largest announce datagram we accept, bigger ones are dropped.
+    pub recv_buffer_size: usize,
+    /// Join the group and announce on every interface instead of only
+    /// `CoreConfig::interface_addr`.
+    pub all_interfaces: bool,
+    /// Bind the receive socket to the unspecified address while still
+    /// joining the group on `CoreConfig::interface_addr`, so announces
+    /// arriving on other interfaces aren't filtered by the bind address.
+    pub recv_bind_any: bool,
+    /// Repeated announces from one fingerprint within this window only
+    /// refresh its last seen time.
+    pub announce_debounce: Duration,
+    /// Multicast replies to new peers are coalesced to at most one per
+    /// window, so many devices joining at once don't set off a storm. The
+    /// first goes out right away. Zero disables the limit.
+    pub reannounce_interval: Duration,
+    /// How often to check for network interface changes, which rebuild the
+    /// multicast sockets and re-announce. `None` disables the check, which
+    /// only runs on unix.
+    pub interface_poll: Option<Duration>,
+    /// Only announces from these ranges are handled, to keep devices on
+    /// other networks like a guest vlan out. Empty accepts every source.
+    pub allowed_subnets: Vec<IpNet>,
+    /// Use IPv4 link local addresses, 169.254.x.x, for devices cabled
+    /// together or on an ad-hoc network without dhcp. Link local interfaces
+    /// are joined, picked as the default one when there's no private
+    /// address, and pass `allowed_subnets`. Off since on a regular LAN they
+    /// are usually leftovers of a failed dhcp.
+    pub link_local: bool,
+    /// Follow the interface of this name, like `eth0`, instead of a fixed
+    /// address: its current IPv4 address becomes
+    /// `CoreConfig::interface_addr` whenever `interface_poll` sees it
+    /// change. See [`CoreActorHandle::start_on_interface`]. Unix only.
+    pub interface_name: Option<String>,
+    /// Cap on listed devices, so announces with ever new fingerprints can't
+    /// grow the list without bound. Past it the device seen longest ago
+    /// makes room, favorites excepted. `None` lifts the cap.
+    pub max_devices: Option<usize>,
+    /// Log the raw content of datagrams that don't parse as an announce,
+    /// as text or hex when it isn't UTF-8, with their source. For looking
+    /// into clients we don't interoperate with, too noisy otherwise.
+    pub log_malformed: bool,
+    /// Only listen: devices are still listed from the announces we hear,
+    /// but we never announce, answer announces or registers, or register
+    /// with anyone. For passive inventory of the LocalSend devices on a
+    /// network.
+    pub observe_only: bool,
+    /// Also run discovery on the LocalSend group of the other address
+    /// family, `ff02::167` next to `224.0.0.167` or the reverse, on the
+    /// same port. Peers seen over both stay one device.
+    pub dual_stack: bool,
+    /// Approves peers before they're added or registered with. `None`
+    /// accepts everyone.
+    pub peer_filter: Option<PeerFilter>,
+    /// Called for every multicast announce that fails to send.
+    pub on_announce_error: Option<AnnounceErrorHandler>,
+    /// Encoding of our announces. Keep JSON unless every device on the
+    /// group runs this library, LocalSend apps only read JSON.
+    pub wire_format: WireFormat,
+    /// Directory the certificate our fingerprint is derived from is kept
+    /// in, generated there on first start. The device then keeps its
+    /// fingerprint across restarts, over http too. `None` makes a new
+    /// certificate each run.
+    pub identity_path: Option<PathBuf>,
+    /// Sent with registers, and with the requests of outgoing transfers.
+    pub headers: RequestHeaders,
+}
+
+impl DiscoveryConfig {
+    pub fn allows_peer(&self, device: &NodeDevice) -> bool {
+        self.peer_filter
+            .as_ref()
+            .is_none_or(|filter| filter.allows(device))
+    }
+
+    /// `headers` plus the secret, for register requests.
+    pub fn register_headers(&self) -> Vec<(String, String)> {
+        let mut headers = self.headers.pairs();
+        if let Some(secret) = &self.secret {
+            headers.push((secret.name.clone(), secret.value.clone()));
+        }
+        headers
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            node_ttl: Duration::from_secs(90),
+            announce: AnnounceConfig::default(),
+            register: RegisterConfig::default(),
+            secret: None,
+            insecure_tls: false,
+            recv_buffer_size: 8192,
+            all_interfaces: false,
+            recv_bind_any: false,
+            announce_debounce: Duration::from_secs(2),
+            reannounce_interval: Duration::from_secs(2),
+            interface_poll: Some(Duration::from_secs(5)),
+            allowed_subnets: Vec::new(),
+            link_local: false,
+            interface_name: None,
+            max_devices: Some(DEFAULT_MAX_DEVICES),
+            log_malformed: false,
+            observe_only: false,
+            dual_stack: false,
+            peer_filter: None,
+            on_announce_error: None,
+            wire_format: WireFormat::Json,
+            identity_path: None,
+            headers: RequestHeaders::default(),
+        }
+    }
+}
+
+/// How incoming transfers are handled, rust side only like
+/// `DiscoveryConfig`.
+#[derive(Debug, Clone)]
+pub struct ReceiveConfig {
+    /// Senders must pass this pin to prepare-upload.
+    pub pin: Option<String>,
+    /// Hash received files and fail those not matching their declared
+    /// sha256. Costs cpu, so it's off by default.
+    pub verify_sha256: bool,
+    /// Sessions allowed at once, counted from prepare-upload until they end.
+    /// Further senders are told to retry later. One by default, like the
+    /// LocalSend app. `None` means no limit.
+    pub max_concurrent_sessions: Option<usize>,
+    /// Space left free on the store volume. Transfers that would eat into
+    /// it are refused up front.
+    pub free_space_margin: u64,
+    /// Decides on incoming transfers. Without one they wait for
+    /// `accept_pending`.
+    pub policy: Option<TransferPolicy>,
+    /// How long a transfer may wait for a decision before it's rejected.
+    /// `None` waits forever.
+    pub decision_timeout: Option<Duration>,
+    /// How long the partial file of an interrupted upload is kept for the
+    /// sender to resume before the file fails.
+    pub resume_window: Duration,
+    /// Port the http server listens on when it isn't the announced
+    /// `CoreConfig::port`, like behind the port mapping of a container.
+    /// `None` listens on the announced one.
+    pub bind_port: Option<u16>,
+    /// A session in which nothing happened for this long while no upload
+    /// is running fails, so a sender that never sends all its files doesn't
+    /// hold it forever. `None` waits forever.
+    pub session_timeout: Option<Duration>,
+}
+
+impl Default for ReceiveConfig {
+    fn default() -> Self {
+        ReceiveConfig {
+            pin: None,
+            verify_sha256: false,
+            max_concurrent_sessions: Some(1),
+            free_space_margin: 64 * 1024 * 1024,
+            policy: None,
+            decision_timeout: Some(Duration::from_secs(60)),
+            resume_window: Duration::from_secs(30),
+            bind_port: None,
+            session_timeout: Some(Duration::from_secs(300)),
+        }
+    }
+}
+
+/// How outgoing transfers are sent, rust side only like `ReceiveConfig`.
+#[derive(Debug, Clone, Default)]
+pub struct SendConfig {
+    /// Upload rate limit shared by all uploads of a session. `None` or `0`
+    /// sends as fast as the network allows.
+    pub max_upload_bytes_per_sec: Option<u64>,
+    /// Pin passed to prepare-upload, for receivers that require one. Peers
+    /// without a pin ignore it.
+    pub pin: Option<String>,
+}
+
 struct AppContext {
     config: CoreConfig,
+    discovery: DiscoveryConfig,
+    receive: ReceiveConfig,
+    send: SendConfig,
+    tls: Option<Arc<TlsIdentity>>,
 }
 
-impl CoreConfig {
+impl Default for CoreConfig {
     fn default() -> Self {
         CoreConfig {
             port: 8080,
             interface_addr: "0.0.0.0".to_string(),
-            multicast_addr: "224.0.0.167".to_string(),
-            multicast_port: 53317,
+            multicast_addr: discovery::DEFAULT_MULTICAST.ip().to_string(),
+            multicast_port: discovery::DEFAULT_MULTICAST.port(),
             store_path: "./".to_string(),
         }
     }
@@ -47,6 +364,30 @@ enum CoreMessage {
         new_config: CoreConfig,
         respond_to: oneshot::Sender<()>,
     },
+    GetDiscoveryConfig {
+        respond_to: oneshot::Sender<DiscoveryConfig>,
+    },
+    ChangeDiscoveryConfig {
+        new_config: Box<DiscoveryConfig>,
+        respond_to: oneshot::Sender<()>,
+    },
+    GetReceiveConfig {
+        respond_to: oneshot::Sender<ReceiveConfig>,
+    },
+    ChangeReceiveConfig {
+        new_config: ReceiveConfig,
+        respond_to: oneshot::Sender<()>,
+    },
+    GetSendConfig {
+        respond_to: oneshot::Sender<SendConfig>,
+    },
+    ChangeSendConfig {
+        new_config: SendConfig,
+        respond_to: oneshot::Sender<()>,
+    },
+    GetTlsIdentity {
+        respond_to: oneshot::Sender<Result<Arc<TlsIdentity>, String>>,
+    },
     Start {
         core: CoreActorHandle,
         respond_to: oneshot::Sender<()>,
@@ -69,7 +410,13 @@ impl CoreActor {
         config.port = device.port;
         CoreActor {
             receiver,
-            context: AppContext { config },
+            context: AppContext {
+                config,
+                discovery: DiscoveryConfig::default(),
+                receive: ReceiveConfig::default(),
+                send: SendConfig::default(),
+                tls: None,
+            },
             server: None,
             server_state_sender: tx,
             server_state_listener: rx,
@@ -88,6 +435,55 @@ impl CoreActor {
                 self.context.config = new_config;
                 _ = respond_to.send(());
             }
+            CoreMessage::GetDiscoveryConfig { respond_to } => {
+                let config = self.context.discovery.clone();
+                _ = respond_to.send(config);
+            }
+            CoreMessage::ChangeDiscoveryConfig {
+                new_config,
+                respond_to,
+            } => {
+                if new_config.identity_path != self.context.discovery.identity_path {
+                    self.context.tls = None;
+                }
+                self.context.discovery = *new_config;
+                _ = respond_to.send(());
+            }
+            CoreMessage::GetReceiveConfig { respond_to } => {
+                let config = self.context.receive.clone();
+                _ = respond_to.send(config);
+            }
+            CoreMessage::ChangeReceiveConfig {
+                new_config,
+                respond_to,
+            } => {
+                self.context.receive = new_config;
+                _ = respond_to.send(());
+            }
+            CoreMessage::GetSendConfig { respond_to } => {
+                let config = self.context.send.clone();
+                _ = respond_to.send(config);
+            }
+            CoreMessage::ChangeSendConfig {
+                new_config,
+                respond_to,
+            } => {
+                self.context.send = new_config;
+                _ = respond_to.send(());
+            }
+            CoreMessage::GetTlsIdentity { respond_to } => {
+                let identity = match &self.context.tls {
+                    Some(identity) => Ok(identity.clone()),
+                    None => match &self.context.discovery.identity_path {
+                        Some(dir) => TlsIdentity::load_or_generate(dir).map(Arc::new),
+                        None => TlsIdentity::generate().map(Arc::new),
+                    },
+                };
+                if let Ok(identity) = &identity {
+                    self.context.tls.replace(identity.clone());
+                }
+                _ = respond_to.send(identity);
+            }
             CoreMessage::Start { core, respond_to } => {
                 if self.server.is_some() {
                     self.server.take().unwrap().shutdown().await;
@@ -99,9 +495,8 @@ impl CoreActor {
                 let _ = self.server_state_sender.send(true);
             }
             CoreMessage::Shutdown { respond_to } => {
-                let handler = self.server.take();
-                if handler.is_some() {
-                    handler.unwrap().shutdown().await;
+                if let Some(handler) = self.server.take() {
+                    handler.shutdown().await;
                 } else {
                     debug!("server not started")
                 }
@@ -126,6 +521,25 @@ pub struct CoreActorHandle {
     sender: mpsc::Sender<CoreMessage>,
     pub device: DeviceActorHandle,
     pub mission: MissionHandle,
+    pub favorites: FavoriteHandle,
+    pub share: ShareHandle,
+    pub discovery_counters: Arc<DiscoveryCounters>,
+    pub(crate) register_clients: Arc<RegisterClients>,
+}
+
+/// Keeps the favorites pinned in the device list, so making room for new
+/// devices never evicts them.
+async fn pin_favorites(
+    mut fingerprints: watch::Receiver<HashSet<String>>,
+    device: DeviceActorHandle,
+) {
+    loop {
+        let pinned = fingerprints.borrow_and_update().clone();
+        device.set_pinned(pinned).await;
+        if fingerprints.changed().await.is_err() {
+            break;
+        }
+    }
 }
 
 impl CoreActorHandle {
@@ -136,11 +550,21 @@ impl CoreActorHandle {
 
         let device = DeviceActorHandle::new(device);
         let mission = MissionHandle::new();
+        let favorites = FavoriteHandle::new();
+        let share = ShareHandle::new();
+        tokio::spawn(pin_favorites(
+            favorites.watch_fingerprints(),
+            device.clone(),
+        ));
 
         Self {
             sender,
             device,
             mission,
+            favorites,
+            share,
+            discovery_counters: Arc::default(),
+            register_clients: Arc::default(),
         }
     }
     pub async fn listen(&self) -> watch::Receiver<bool> {
@@ -151,6 +575,7 @@ impl CoreActorHandle {
     }
 
     pub async fn start(&self) {
+        self.use_tls_fingerprint().await;
         let (send, recv) = oneshot::channel();
         let msg = CoreMessage::Start {
             core: self.clone(),
@@ -160,6 +585,61 @@ impl CoreActorHandle {
         recv.await.expect("Actor task has been killed");
         self.device.clear_devices().await;
     }
+    /// Starts the server on the given multicast group, joining it on the
+    /// detected LAN interface. An explicitly configured interface is kept.
+    /// Off unix the interfaces can't be listed, the detected one is then
+    /// whichever the internet is routed through.
+    pub async fn start_auto(&self, multicast_addr: String, multicast_port: u16) {
+        let mut config = self.get_config().await;
+        config.multicast_addr = multicast_addr;
+        config.multicast_port = multicast_port;
+        let unspecified = config
+            .interface_addr
+            .parse::<IpAddr>()
+            .map_or(true, |ip| ip.is_unspecified());
+        if unspecified {
+            let link_local = self.get_discovery_config().await.link_local;
+            match default_interface_v4(link_local) {
+                Some(ip) => config.interface_addr = ip.to_string(),
+                None => warn!("no usable interface found, using {}", config.interface_addr),
+            }
+        }
+        self.change_config(config).await;
+        self.start().await;
+    }
+
+    /// Starts the server on the given multicast group, joining it on the
+    /// interface called `name` at its current address, which is followed
+    /// when it changes. While the interface has no IPv4 address the
+    /// configured one is used, as it always is off unix, where interfaces
+    /// can't be looked up by name.
+    pub async fn start_on_interface(
+        &self,
+        name: String,
+        multicast_addr: String,
+        multicast_port: u16,
+    ) {
+        let mut config = self.get_config().await;
+        config.multicast_addr = multicast_addr;
+        config.multicast_port = multicast_port;
+        match interface_ipv4_by_name(&name) {
+            Some(ip) => config.interface_addr = ip.to_string(),
+            None if cfg!(not(unix)) => warn!(
+                "interfaces can't be looked up by name on this platform, using {}",
+                config.interface_addr
+            ),
+            None => warn!(
+                "interface {} has no IPv4 address, using {}",
+                name, config.interface_addr
+            ),
+        }
+        let mut discovery = self.get_discovery_config().await;
+        discovery.interface_name = Some(name);
+        self.change_discovery_config(discovery).await;
+        self.change_config(config).await;
+        self.start().await;
+    }
+
     pub async fn shutdown(&self) {
         let (send, recv) = oneshot::channel();
         let msg = CoreMessage::Shutdown { respond_to: send };
@@ -187,6 +667,20 @@ impl CoreActorHandle {
         self.change_config(value).await;
     }
 
+    /// Renames this device and announces the new name right away, leaving
+    /// the rest of the current device as it is. See [`sanitize_alias`].
+    pub async fn set_alias(&self, alias: String) -> Result<(), String> {
+        let alias = sanitize_alias(&alias)?;
+        let mut current = self.device.get_current_device().await;
+        if current.alias == alias {
+            return Ok(());
+        }
+        current.alias = alias;
+        self.device.set_current_device(current).await;
+        self.announce().await;
+        Ok(())
+    }
+
     pub async fn change_config(&self, config: CoreConfig) {
         let (send, recv) = oneshot::channel();
         let msg = CoreMessage::ChangeConfig {
@@ -201,4 +695,353 @@ impl CoreActorHandle {
         let _ = self.sender.send(msg).await;
         recv.await.expect("Actor task has been killed")
     }
+
+    /// Certificate used when serving https, generated on first use or
+    /// loaded from `DiscoveryConfig::identity_path`.
+    pub async fn tls_identity(&self) -> Result<Arc<TlsIdentity>, String> {
+        let (send, recv) = oneshot::channel();
+        let msg = CoreMessage::GetTlsIdentity { respond_to: send };
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// https peers check that our fingerprint is the hash of the certificate
+    /// we serve, so adopt it when the current device speaks https. A saved
+    /// identity is adopted either way, that's what keeps it stable.
+    async fn use_tls_fingerprint(&self) {
+        let mut current = self.device.get_current_device().await;
+        let saved = self.get_discovery_config().await.identity_path.is_some();
+        if current.protocol != "https" && !saved {
+            return;
+        }
+        match self.tls_identity().await {
+            Ok(identity) if identity.fingerprint != current.fingerprint => {
+                info!("using certificate fingerprint {}", identity.fingerprint);
+                current.fingerprint = identity.fingerprint.clone();
+                self.device.set_current_device(current).await;
+            }
+            Ok(_) => {}
+            Err(err) => warn!("failed to create certificate: {}", err),
+        }
+    }
+
+    pub async fn get_discovery_config(&self) -> DiscoveryConfig {
+        let (send, recv) = oneshot::channel();
+        let msg = CoreMessage::GetDiscoveryConfig { respond_to: send };
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    pub async fn change_discovery_config(&self, config: DiscoveryConfig) {
+        let (send, recv) = oneshot::channel();
+        let msg = CoreMessage::ChangeDiscoveryConfig {
+            new_config: Box::new(config),
+            respond_to: send,
+        };
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    pub async fn get_receive_config(&self) -> ReceiveConfig {
+        let (send, recv) = oneshot::channel();
+        let msg = CoreMessage::GetReceiveConfig { respond_to: send };
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    pub async fn change_receive_config(&self, config: ReceiveConfig) {
+        let (send, recv) = oneshot::channel();
+        let msg = CoreMessage::ChangeReceiveConfig {
+            new_config: config,
+            respond_to: send,
+        };
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    pub async fn get_send_config(&self) -> SendConfig {
+        let (send, recv) = oneshot::channel();
+        let msg = CoreMessage::GetSendConfig { respond_to: send };
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    pub async fn change_send_config(&self, config: SendConfig) {
+        let (send, recv) = oneshot::channel();
+        let msg = CoreMessage::ChangeSendConfig {
+            new_config: config,
+            respond_to: send,
+        };
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    pub async fn set_max_upload_rate(&self, bytes_per_sec: Option<u64>) {
+        let mut value = self.get_send_config().await;
+        value.max_upload_bytes_per_sec = bytes_per_sec;
+        self.change_send_config(value).await;
+    }
+
+    pub async fn set_send_pin(&self, pin: Option<String>) {
+        let mut value = self.get_send_config().await;
+        value.pin = pin;
+        self.change_send_config(value).await;
+    }
+
+    pub async fn set_peer_filter(&self, filter: Option<PeerFilter>) {
+        let mut value = self.get_discovery_config().await;
+        value.peer_filter = filter;
+        self.change_discovery_config(value).await;
+    }
+
+    /// Calls `callback` with the error of every announce that fails to send
+    /// from the next announce on.
+    pub async fn on_announce_error<F>(&self, callback: F)
+    where
+        F: Fn(&io::Error) + Send + Sync + 'static,
+    {
+        let mut value = self.get_discovery_config().await;
+        value.on_announce_error = Some(AnnounceErrorHandler::new(callback));
+        self.change_discovery_config(value).await;
+    }
+
+    pub async fn set_pin(&self, pin: Option<String>) {
+        let mut value = self.get_receive_config().await;
+        value.pin = pin;
+        self.change_receive_config(value).await;
+    }
+
+    pub async fn set_transfer_policy(&self, policy: Option<TransferPolicy>) {
+        let mut value = self.get_receive_config().await;
+        value.policy = policy;
+        self.change_receive_config(value).await;
+    }
+
+    pub async fn set_max_concurrent_sessions(&self, max: Option<usize>) {
+        let mut value = self.get_receive_config().await;
+        value.max_concurrent_sessions = max;
+        self.change_receive_config(value).await;
+    }
+
+    /// Listens on `port` while still announcing `CoreConfig::port`. Takes
+    /// effect on the next start.
+    pub async fn set_bind_port(&self, port: Option<u16>) {
+        let mut value = self.get_receive_config().await;
+        value.bind_port = port;
+        self.change_receive_config(value).await;
+    }
+
+    pub async fn set_multicast_ttl(&self, ttl: u32) {
+        let mut value = self.get_discovery_config().await;
+        value.announce.multicast_ttl = ttl;
+        self.change_discovery_config(value).await;
+    }
+
+    /// Clears the known devices and multicasts our announce so peers answer
+    /// again.
+    pub async fn discover(&self) {
+        self.device.clear_devices().await;
+        self.announce().await;
+    }
+
+    /// Re-registers with every known device, keeping the list instead of
+    /// clearing it like `discover`. Returns the devices dropped for not
+    /// answering.
+    pub async fn refresh(&self) -> Vec<NodeDevice> {
+        discovery::refresh(self.clone()).await
+    }
+
+    /// Devices answering within `window`, without running the server, see
+    /// [`discovery::scan`].
+    pub async fn scan(
+        &self,
+        window: Duration,
+    ) -> Result<HashMap<String, NodeDevice>, discovery::DiscoveryError> {
+        discovery::scan(self, window).await
+    }
+
+    /// Whether `target` is reachable, see [`discovery::probe`].
+    pub async fn probe(&self, target: &NodeDevice, timeout: Duration) -> bool {
+        discovery::probe(self, target, timeout).await
+    }
+
+    /// Multicasts our announce and waits `window` for peers to answer, then
+    /// returns every known device. Devices kept by a running server are only
+    /// dropped beforehand when `clear` is set.
+    pub async fn discover_collect(
+        &self,
+        window: Duration,
+        clear: bool,
+    ) -> HashMap<String, NodeDevice> {
+        if clear {
+            self.device.clear_devices().await;
+        }
+        self.announce().await;
+        time::sleep(window).await;
+        self.device.get_device_map().await
+    }
+
+    /// Discovery activity so far, with the number of devices known now.
+    pub async fn discovery_metrics(&self) -> DiscoveryMetrics {
+        let nodes_known = self.device.get_device_map().await.len();
+        self.discovery_counters.snapshot(nodes_known)
+    }
+
+    /// Asks every favorite for its info at its last known address, which
+    /// finds them even where multicast doesn't get through.
+    pub async fn discover_favorites(&self) -> Vec<NodeDevice> {
+        let targets = self
+            .favorites
+            .get_favorites()
+            .await
+            .iter()
+            .filter_map(|favorite| favorite.socket_addr())
+            .collect();
+        discovery::discover_http(self.clone(), targets).await
+    }
+
+    /// Sends `text` to `target` as a text message, see [`send::send_text`].
+    pub async fn send_text(&self, target: &NodeDevice, text: String) -> Result<(), String> {
+        send::send_text(self, target, text).await
+    }
+
+    /// Sends `files` to `target`, see [`send::send_files`].
+    pub async fn send_files(
+        &self,
+        target: &NodeDevice,
+        files: Vec<send::OutgoingFile>,
+    ) -> Result<(), String> {
+        send::send_files(self, target, files).await
+    }
+
+    /// Sends `files` to `target` compressed, see
+    /// [`send::send_files_with_compression`].
+    pub async fn send_files_with_compression(
+        &self,
+        target: &NodeDevice,
+        files: Vec<send::OutgoingFile>,
+        compression: Compression,
+    ) -> Result<(), String> {
+        send::send_files_with_compression(self, target, files, Some(compression)).await
+    }
+
+    /// Offers `files` for peers to download, see [`ShareHandle::offer`], and
+    /// advertises the download capability in our announce.
+    pub async fn offer_files(&self, files: Vec<send::OutgoingFile>, pin: Option<String>) {
+        self.share.offer(files, pin).await;
+        self.set_download(true).await;
+    }
+
+    /// Withdraws the offered files. Returns whether anything was offered.
+    pub async fn revoke_offer(&self) -> bool {
+        let revoked = self.share.revoke().await;
+        self.set_download(false).await;
+        revoked
+    }
+
+    async fn set_download(&self, download: bool) {
+        let mut current = self.device.get_current_device().await;
+        if current.download != download {
+            current.download = download;
+            self.device.set_current_device(current).await;
+        }
+    }
+
+    /// Calls `callback` with the sender and text of every text message
+    /// received from now on.
+    pub async fn on_text_received<F>(&self, callback: F)
+    where
+        F: Fn(NodeDevice, String) + Send + 'static,
+    {
+        let mut events = self.mission.transfer.subscribe().await;
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(TransferEvent::TextReceived { from, text, .. }) => callback(from, text),
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("missed {} transfer events", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Calls `callback` with every device seen for the first time from now
+    /// on, once per fingerprint. Devices listed already, and ones coming
+    /// back after they expired, don't count. Only the last
+    /// `NEW_NODES_REMEMBERED` fingerprints are remembered, so announce spam
+    /// can't grow the set without bound; older ones count as new again.
+    pub async fn on_new_node<F>(&self, callback: F)
+    where
+        F: Fn(NodeDevice) + Send + 'static,
+    {
+        let mut events = self.device.subscribe().await;
+        let mut order: VecDeque<String> = self.device.get_device_map().await.into_keys().collect();
+        let mut seen: HashSet<String> = order.iter().cloned().collect();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(DeviceEvent::Added(device)) => {
+                        if !seen.insert(device.fingerprint.clone()) {
+                            continue;
+                        }
+                        order.push_back(device.fingerprint.clone());
+                        while order.len() > NEW_NODES_REMEMBERED {
+                            if let Some(oldest) = order.pop_front() {
+                                seen.remove(&oldest);
+                            }
+                        }
+                        callback(device);
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("missed {} device events", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Re-resolves our address when no interface is configured, as the
+    /// machine may have got a new one since `set_current_device`. Peers take
+    /// the source address of our announces, but ours is what we recognize
+    /// our own echoes by. Returns the new address if it changed.
+    pub async fn refresh_address(&self) -> Option<IpAddr> {
+        let config = self.get_config().await;
+        let unspecified = config
+            .interface_addr
+            .parse::<IpAddr>()
+            .map_or(true, |ip| ip.is_unspecified());
+        if !unspecified {
+            return None;
+        }
+        let link_local = self.get_discovery_config().await.link_local;
+        let address = IpAddr::V4(default_interface_v4(link_local)?);
+        self.device.update_address(address).await.then_some(address)
+    }
+
+    pub(crate) async fn announce(&self) {
+        if self.get_discovery_config().await.observe_only {
+            debug!("observe only, not announcing");
+            return;
+        }
+        if let Some(address) = self.refresh_address().await {
+            info!("own address is now {}", address);
+        }
+        let config = self.get_config().await;
+        let discovery_config = self.get_discovery_config().await;
+
+        let message = self
+            .device
+            .get_current_announce(discovery_config.wire_format)
+            .await;
+
+        let counters = &self.discovery_counters;
+        if let Err(err) = discovery::announce(config, discovery_config, &message, counters).await {
+            warn!("announce failed: {}", err);
+        }
+    }
 }
@@ -1,21 +1,115 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use log::debug;
-use tokio::sync::{mpsc, oneshot, watch};
+use futures::Stream;
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 
-use super::model::NodeDevice;
+use crate::util::parse_scoped_ip;
+
+use super::model::{NodeDevice, WireFormat};
+
+/// Departures kept by default, see `DeviceActorHandle::recent_departures`.
+pub const DEFAULT_DEPARTURES_KEPT: usize = 16;
+
+/// Addresses kept per device, the most recently seen ones.
+const MAX_ADDRESSES: usize = 8;
+
+/// Devices listed at most by default, see `DeviceActorHandle::set_max_devices`.
+pub const DEFAULT_MAX_DEVICES: usize = 256;
+
+/// Change to the device list, for consumers that want deltas rather than
+/// the full snapshot sent through `listen`.
+#[derive(Debug, Clone)]
+pub enum DeviceEvent {
+    Added(NodeDevice),
+    Updated(NodeDevice),
+    /// Fingerprint of the removed device.
+    Removed(String),
+    /// Fingerprint of a favorite that stopped announcing. It stays listed
+    /// until it's back or unpinned.
+    WentOffline(String),
+    /// A favorite that was offline announced again.
+    CameOnline(NodeDevice),
+}
+
+/// A listed device as `dump_devices_json` writes it: the device fields,
+/// then every address it was seen at, whether it's online and when it was
+/// last seen in milliseconds since the unix epoch. Field names are
+/// camelCase like the rest of the protocol.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceRecord {
+    #[serde(flatten)]
+    pub device: NodeDevice,
+    /// Most recently seen first, defaults to the device address.
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    pub online: bool,
+    pub last_seen: u64,
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+// shared so snapshots for listeners don't copy every device
+struct DeviceEntry {
+    device: Arc<NodeDevice>,
+    last_seen: Instant,
+    /// Addresses the device was seen at, most recent first, more than one
+    /// for peers reachable over both v4 and v6 or several networks.
+    addresses: Vec<String>,
+    /// Cleared when a favorite outlives the ttl instead of being evicted.
+    online: bool,
+}
+
+impl DeviceEntry {
+    /// Marks the device seen now, returning whether it was offline.
+    fn seen(&mut self) -> bool {
+        self.last_seen = Instant::now();
+        !std::mem::replace(&mut self.online, true)
+    }
+}
+
+/// Moves `address` to the front of `addresses`, dropping the oldest past
+/// `MAX_ADDRESSES`.
+fn seen_at(addresses: &mut Vec<String>, address: &str) {
+    addresses.retain(|known| known != address);
+    addresses.insert(0, address.to_string());
+    addresses.truncate(MAX_ADDRESSES);
+}
 
 struct DeviceActor {
     receiver: mpsc::Receiver<DeviceMessage>,
     current: NodeDevice,
-    device_map: HashMap<String, NodeDevice>,
-    listener: watch::Receiver<Vec<NodeDevice>>,
-    notify: watch::Sender<Vec<NodeDevice>>,
+    // serialized announce and reply of `current` by wire format, built on
+    // first use
+    announces: HashMap<(bool, WireFormat), Arc<Vec<u8>>>,
+    device_map: HashMap<String, DeviceEntry>,
+    listener: watch::Receiver<Vec<Arc<NodeDevice>>>,
+    notify: watch::Sender<Vec<Arc<NodeDevice>>>,
+    events: broadcast::Sender<DeviceEvent>,
+    // removed devices, the latest last
+    departures: VecDeque<(NodeDevice, Instant)>,
+    departures_kept: usize,
+    max_devices: Option<usize>,
+    // favorites, see `set_pinned`, never evicted to make room
+    pinned: HashSet<String>,
 }
 
 enum DeviceMessage {
     Listen {
-        respond_to: oneshot::Sender<watch::Receiver<Vec<NodeDevice>>>,
+        respond_to: oneshot::Sender<watch::Receiver<Vec<Arc<NodeDevice>>>>,
+    },
+    Subscribe {
+        respond_to: oneshot::Sender<broadcast::Receiver<DeviceEvent>>,
     },
     Add {
         device: NodeDevice,
@@ -24,17 +118,72 @@ enum DeviceMessage {
     GetAll {
         respond_to: oneshot::Sender<HashMap<String, NodeDevice>>,
     },
+    Count {
+        respond_to: oneshot::Sender<usize>,
+    },
     Get {
         fingerprint: String,
         respond_to: oneshot::Sender<Option<NodeDevice>>,
     },
+    GetByAddress {
+        address: String,
+        respond_to: oneshot::Sender<Option<NodeDevice>>,
+    },
     Clear {
         respond_to: oneshot::Sender<()>,
     },
+    Remove {
+        fingerprint: String,
+        respond_to: oneshot::Sender<Option<NodeDevice>>,
+    },
+    Touch {
+        fingerprint: String,
+        respond_to: oneshot::Sender<()>,
+    },
+    TouchAt {
+        fingerprint: String,
+        address: String,
+        respond_to: oneshot::Sender<()>,
+    },
+    GetAddresses {
+        fingerprint: String,
+        respond_to: oneshot::Sender<Vec<String>>,
+    },
+    GetDepartures {
+        respond_to: oneshot::Sender<Vec<(NodeDevice, Instant)>>,
+    },
+    GetRecords {
+        respond_to: oneshot::Sender<Vec<DeviceRecord>>,
+    },
+    Restore {
+        records: Vec<DeviceRecord>,
+        respond_to: oneshot::Sender<usize>,
+    },
+    SetMaxDevices {
+        max: Option<usize>,
+        respond_to: oneshot::Sender<()>,
+    },
+    SetDeparturesKept {
+        kept: usize,
+        respond_to: oneshot::Sender<()>,
+    },
+    SetPinned {
+        fingerprints: HashSet<String>,
+        respond_to: oneshot::Sender<()>,
+    },
+    Evict {
+        ttl: Duration,
+        keep: HashSet<String>,
+        respond_to: oneshot::Sender<Vec<NodeDevice>>,
+    },
     CheckExist {
         fingerprint: String,
         respond_to: oneshot::Sender<bool>,
     },
+    IsOnline {
+        fingerprint: String,
+        respond_to: oneshot::Sender<bool>,
+    },
     GetCurrent {
         respond_to: oneshot::Sender<NodeDevice>,
     },
@@ -42,35 +191,126 @@ enum DeviceMessage {
         device: NodeDevice,
         respond_to: oneshot::Sender<()>,
     },
+    UpdateAddress {
+        address: String,
+        respond_to: oneshot::Sender<bool>,
+    },
+    GetAnnounce {
+        reply: bool,
+        format: WireFormat,
+        respond_to: oneshot::Sender<Arc<Vec<u8>>>,
+    },
 }
 
 impl DeviceActor {
     fn new(receiver: mpsc::Receiver<DeviceMessage>, current: NodeDevice) -> Self {
-        let device_map: HashMap<String, NodeDevice> = HashMap::new();
+        let device_map: HashMap<String, DeviceEntry> = HashMap::new();
         let (tx, rx) = watch::channel(Vec::new());
+        let (events, _) = broadcast::channel(32);
         DeviceActor {
             receiver,
             current,
+            announces: HashMap::new(),
             device_map,
             listener: rx,
             notify: tx,
+            events,
+            departures: VecDeque::new(),
+            departures_kept: DEFAULT_DEPARTURES_KEPT,
+            max_devices: Some(DEFAULT_MAX_DEVICES),
+            pinned: HashSet::new(),
         }
     }
+    fn emit(&self, event: DeviceEvent) {
+        // no subscribers is fine
+        let _ = self.events.send(event);
+    }
+    fn depart(&mut self, device: NodeDevice) {
+        if self.departures_kept == 0 {
+            return;
+        }
+        if self.departures.len() == self.departures_kept {
+            self.departures.pop_front();
+        }
+        self.departures.push_back((device, Instant::now()));
+    }
+    /// Frees a slot for the new device `fingerprint` when the list is full
+    /// by evicting the one seen longest ago. Pinned devices are never
+    /// evicted and always get in, others are turned away when only pinned
+    /// ones are left. Returns whether the device may be added.
+    fn make_room(&mut self, fingerprint: &str) -> bool {
+        let max = match self.max_devices {
+            Some(max) if self.device_map.len() >= max => max,
+            _ => return true,
+        };
+        let oldest = self
+            .device_map
+            .iter()
+            .filter(|(id, _)| !self.pinned.contains(*id))
+            .min_by_key(|(_, entry)| entry.last_seen)
+            .map(|(id, _)| id.clone());
+        let entry = match oldest.and_then(|id| self.device_map.remove(&id)) {
+            Some(entry) => entry,
+            None => return self.pinned.contains(fingerprint),
+        };
+        let device = Arc::unwrap_or_clone(entry.device);
+        warn!("device list at its limit of {}, evicting {}", max, device);
+        self.emit(DeviceEvent::Removed(device.fingerprint.clone()));
+        self.depart(device);
+        true
+    }
     async fn notify_change(&self) {
         let data = self
             .device_map
-            .clone()
             .values()
-            .map(|x| x.clone())
+            .map(|entry| entry.device.clone())
             .collect::<Vec<_>>();
         let _ = self.notify.send(data);
     }
     async fn handle_message(&mut self, msg: DeviceMessage) {
         match msg {
-            DeviceMessage::Add { device, respond_to } => {
-                self.device_map.insert(device.fingerprint.clone(), device);
+            DeviceMessage::Add {
+                mut device,
+                respond_to,
+            } => {
+                if !self.device_map.contains_key(&device.fingerprint)
+                    && !self.make_room(&device.fingerprint)
+                {
+                    warn!("device list full, ignoring {}", device);
+                    let _ = respond_to.send(());
+                    return;
+                }
+                let mut addresses = Vec::new();
+                let mut came_online = false;
+                if let Some(previous) = self.device_map.get(&device.fingerprint) {
+                    came_online = !previous.online;
+                    addresses = previous.addresses.clone();
+                    seen_at(&mut addresses, &device.address);
+                    device = merged(&previous.device, device);
+                } else {
+                    addresses.push(device.address.clone());
+                }
+                let entry = DeviceEntry {
+                    device: Arc::new(device.clone()),
+                    last_seen: Instant::now(),
+                    addresses,
+                    online: true,
+                };
+                let previous = self
+                    .device_map
+                    .insert(entry.device.fingerprint.clone(), entry);
                 debug!("device added");
                 let _ = respond_to.send(());
+                if came_online {
+                    self.emit(DeviceEvent::CameOnline(device.clone()));
+                }
+                match previous {
+                    None => self.emit(DeviceEvent::Added(device)),
+                    Some(previous) if !previous.device.same_details(&device) => {
+                        self.emit(DeviceEvent::Updated(device))
+                    }
+                    Some(_) => {}
+                }
                 self.notify_change().await;
             }
             DeviceMessage::Get {
@@ -81,17 +321,45 @@ impl DeviceActor {
                     let _ = respond_to.send(Some(self.current.clone()));
                     return;
                 }
-                if self.device_map.contains_key(&fingerprint) {
-                    let _ =
-                        respond_to.send(Some(self.device_map.get(&fingerprint).unwrap().clone()));
-                    return;
-                }
-                let _ = respond_to.send(None);
+                let device = self
+                    .device_map
+                    .get(&fingerprint)
+                    .map(|entry| NodeDevice::clone(&entry.device));
+                let _ = respond_to.send(device);
+            }
+            DeviceMessage::GetByAddress {
+                address,
+                respond_to,
+            } => {
+                let wanted = parse_scoped_ip(&address);
+                let device = self
+                    .device_map
+                    .values()
+                    .filter(
+                        |entry| match (wanted, parse_scoped_ip(&entry.device.address)) {
+                            // a missing zone index matches any
+                            (Some((ip, scope)), Some((other, other_scope))) => {
+                                ip == other
+                                    && (scope == 0 || other_scope == 0 || scope == other_scope)
+                            }
+                            _ => entry.device.address == address,
+                        },
+                    )
+                    .max_by_key(|entry| entry.last_seen)
+                    .map(|entry| NodeDevice::clone(&entry.device));
+                let _ = respond_to.send(device);
             }
             DeviceMessage::GetAll { respond_to } => {
-                let id_map = self.device_map.clone();
+                let id_map = self
+                    .device_map
+                    .iter()
+                    .map(|(id, entry)| (id.clone(), NodeDevice::clone(&entry.device)))
+                    .collect();
                 let _ = respond_to.send(id_map);
             }
+            DeviceMessage::Count { respond_to } => {
+                let _ = respond_to.send(self.device_map.len());
+            }
             DeviceMessage::CheckExist {
                 fingerprint,
                 respond_to,
@@ -101,37 +369,322 @@ impl DeviceActor {
                         || self.device_map.contains_key(&fingerprint),
                 );
             }
+            DeviceMessage::IsOnline {
+                fingerprint,
+                respond_to,
+            } => {
+                let online = self
+                    .device_map
+                    .get(&fingerprint)
+                    .is_some_and(|entry| entry.online);
+                let _ = respond_to.send(online);
+            }
             DeviceMessage::GetCurrent { respond_to } => {
                 let _ = respond_to.send(self.current.clone());
             }
             DeviceMessage::SetCurrent { device, respond_to } => {
                 self.current = device;
+                self.announces.clear();
                 debug!("current device updated");
                 let _ = respond_to.send(());
             }
+            DeviceMessage::UpdateAddress {
+                address,
+                respond_to,
+            } => {
+                // announces don't carry the address, the cache stays valid
+                let changed = self.current.address != address;
+                if changed {
+                    debug!("current address {} -> {}", self.current.address, address);
+                    self.current.address = address;
+                }
+                let _ = respond_to.send(changed);
+            }
+            DeviceMessage::GetAnnounce {
+                reply,
+                format,
+                respond_to,
+            } => {
+                let current = &self.current;
+                let cached = self.announces.entry((reply, format)).or_insert_with(|| {
+                    if reply {
+                        serialize_announce(&current.to_reply(), format)
+                    } else {
+                        serialize_announce(current, format)
+                    }
+                });
+                let _ = respond_to.send(cached.clone());
+            }
             DeviceMessage::Listen { respond_to } => {
                 let _ = respond_to.send(self.listener.clone());
             }
+            DeviceMessage::Subscribe { respond_to } => {
+                let _ = respond_to.send(self.events.subscribe());
+            }
             DeviceMessage::Clear { respond_to } => {
-                self.device_map.clear();
+                // a refresh clears devices that are still around, so they
+                // aren't departures
+                for (fingerprint, _) in self.device_map.drain() {
+                    let _ = self.events.send(DeviceEvent::Removed(fingerprint));
+                }
+                self.notify_change().await;
+                let _ = respond_to.send(());
+            }
+            DeviceMessage::Remove {
+                fingerprint,
+                respond_to,
+            } => {
+                let removed = self
+                    .device_map
+                    .remove(&fingerprint)
+                    .map(|entry| Arc::unwrap_or_clone(entry.device));
+                let _ = respond_to.send(removed.clone());
+                if let Some(device) = removed {
+                    self.depart(device);
+                    self.emit(DeviceEvent::Removed(fingerprint));
+                    self.notify_change().await;
+                }
+            }
+            DeviceMessage::Touch {
+                fingerprint,
+                respond_to,
+            } => {
+                let came_online = self
+                    .device_map
+                    .get_mut(&fingerprint)
+                    .and_then(|entry| entry.seen().then(|| NodeDevice::clone(&entry.device)));
+                let _ = respond_to.send(());
+                if let Some(device) = came_online {
+                    self.emit(DeviceEvent::CameOnline(device));
+                }
+            }
+            DeviceMessage::TouchAt {
+                fingerprint,
+                address,
+                respond_to,
+            } => {
+                let mut updated = None;
+                let mut came_online = None;
+                if let Some(entry) = self.device_map.get_mut(&fingerprint) {
+                    if entry.seen() {
+                        came_online = Some(NodeDevice::clone(&entry.device));
+                    }
+                    seen_at(&mut entry.addresses, &address);
+                    if preferred_address(&entry.device.address, &address) != entry.device.address {
+                        let device = NodeDevice {
+                            address,
+                            ..NodeDevice::clone(&entry.device)
+                        };
+                        entry.device = Arc::new(device.clone());
+                        updated = Some(device);
+                    }
+                }
+                let _ = respond_to.send(());
+                if let Some(device) = came_online {
+                    self.emit(DeviceEvent::CameOnline(device));
+                }
+                if let Some(device) = updated {
+                    self.emit(DeviceEvent::Updated(device));
+                    self.notify_change().await;
+                }
+            }
+            DeviceMessage::GetAddresses {
+                fingerprint,
+                respond_to,
+            } => {
+                let addresses = self
+                    .device_map
+                    .get(&fingerprint)
+                    .map(|entry| entry.addresses.clone())
+                    .unwrap_or_default();
+                let _ = respond_to.send(addresses);
+            }
+            DeviceMessage::GetDepartures { respond_to } => {
+                let _ = respond_to.send(self.departures.iter().rev().cloned().collect());
+            }
+            DeviceMessage::GetRecords { respond_to } => {
+                let now = SystemTime::now();
+                let mut records = self
+                    .device_map
+                    .values()
+                    .map(|entry| DeviceRecord {
+                        device: NodeDevice::clone(&entry.device),
+                        addresses: entry.addresses.clone(),
+                        online: entry.online,
+                        last_seen: unix_millis(now - entry.last_seen.elapsed()),
+                    })
+                    .collect::<Vec<_>>();
+                records.sort_by(|a, b| a.device.fingerprint.cmp(&b.device.fingerprint));
+                let _ = respond_to.send(records);
+            }
+            DeviceMessage::Restore {
+                records,
+                respond_to,
+            } => {
+                let now = unix_millis(SystemTime::now());
+                let mut restored = 0;
+                for mut record in records {
+                    if record.device.fingerprint.is_empty()
+                        || record.device.fingerprint == self.current.fingerprint
+                    {
+                        continue;
+                    }
+                    if !self.device_map.contains_key(&record.device.fingerprint)
+                        && !self.make_room(&record.device.fingerprint)
+                    {
+                        warn!("device list full, not restoring {}", record.device);
+                        continue;
+                    }
+                    if record.addresses.is_empty() {
+                        record.addresses.push(record.device.address.clone());
+                    }
+                    record.addresses.truncate(MAX_ADDRESSES);
+                    let age = Duration::from_millis(now.saturating_sub(record.last_seen));
+                    // older than the clock reaches back counts as seen now
+                    let last_seen = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+                    let device = record.device;
+                    let entry = DeviceEntry {
+                        device: Arc::new(device.clone()),
+                        last_seen,
+                        addresses: record.addresses,
+                        online: record.online,
+                    };
+                    match self.device_map.insert(device.fingerprint.clone(), entry) {
+                        None => self.emit(DeviceEvent::Added(device)),
+                        Some(previous) if !previous.device.same_details(&device) => {
+                            self.emit(DeviceEvent::Updated(device))
+                        }
+                        Some(_) => {}
+                    }
+                    restored += 1;
+                }
+                debug!("restored {} devices", restored);
+                let _ = respond_to.send(restored);
                 self.notify_change().await;
+            }
+            DeviceMessage::SetMaxDevices { max, respond_to } => {
+                self.max_devices = max;
+                let _ = respond_to.send(());
+            }
+            DeviceMessage::SetDeparturesKept { kept, respond_to } => {
+                self.departures_kept = kept;
+                while self.departures.len() > kept {
+                    self.departures.pop_front();
+                }
                 let _ = respond_to.send(());
             }
+            DeviceMessage::SetPinned {
+                fingerprints,
+                respond_to,
+            } => {
+                self.pinned = fingerprints;
+                let _ = respond_to.send(());
+            }
+            DeviceMessage::Evict {
+                ttl,
+                keep,
+                respond_to,
+            } => {
+                let now = Instant::now();
+                let mut stale = Vec::new();
+                let mut offline = Vec::new();
+                for (id, entry) in self.device_map.iter_mut() {
+                    if now.duration_since(entry.last_seen) <= ttl {
+                        continue;
+                    }
+                    if !keep.contains(id) {
+                        stale.push(id.clone());
+                    } else if entry.online {
+                        entry.online = false;
+                        offline.push(id.clone());
+                    }
+                }
+                for id in offline {
+                    debug!("favorite {} went offline", id);
+                    self.emit(DeviceEvent::WentOffline(id));
+                }
+
+                let evicted = stale
+                    .iter()
+                    .filter_map(|id| self.device_map.remove(id))
+                    .map(|entry| Arc::unwrap_or_clone(entry.device))
+                    .collect::<Vec<_>>();
+                for device in &evicted {
+                    self.depart(device.clone());
+                    self.emit(DeviceEvent::Removed(device.fingerprint.clone()));
+                }
+
+                if !evicted.is_empty() {
+                    debug!("{} stale devices evicted", evicted.len());
+                    self.notify_change().await;
+                }
+                let _ = respond_to.send(evicted);
+            }
         }
     }
 }
 
+/// `device` seen again, keeping what `previous` knew better: the address
+/// `preferred_address` picks, https with its port over http, and fields
+/// the new sighting left empty. That way sightings over different paths,
+/// like multicast and a register answer, don't undo each other. A peer
+/// turning https off is taken as http once it expired.
+fn merged(previous: &NodeDevice, mut device: NodeDevice) -> NodeDevice {
+    device.address = preferred_address(&previous.address, &device.address).to_string();
+    if previous.protocol == "https" && device.protocol != "https" {
+        device.protocol = previous.protocol.clone();
+        device.port = previous.port;
+    }
+    let keep = |field: &mut String, known: &String| {
+        if field.is_empty() {
+            field.clone_from(known);
+        }
+    };
+    keep(&mut device.alias, &previous.alias);
+    keep(&mut device.version, &previous.version);
+    keep(&mut device.device_model, &previous.device_model);
+    keep(&mut device.device_type, &previous.device_type);
+    if device.port == 0 {
+        device.port = previous.port;
+    }
+    device
+}
+
+/// Address to keep for a device seen at both `current` and `seen`. v4 wins
+/// over v6, whose link local addresses need the right zone index to work,
+/// otherwise the latest sighting does.
+fn preferred_address<'a>(current: &'a str, seen: &'a str) -> &'a str {
+    let is_v4 = |address: &str| matches!(parse_scoped_ip(address), Some((IpAddr::V4(_), _)));
+    if is_v4(current) && !is_v4(seen) {
+        current
+    } else {
+        seen
+    }
+}
+
 async fn run_device_actor(mut actor: DeviceActor) {
     while let Some(msg) = actor.receiver.recv().await {
         actor.handle_message(msg).await;
     }
 }
 
+fn serialize_announce(device: &NodeDevice, format: WireFormat) -> Arc<Vec<u8>> {
+    Arc::new(device.to_announce().encode(format))
+}
+
 #[derive(Clone)]
 pub struct DeviceActorHandle {
     sender: mpsc::Sender<DeviceMessage>,
 }
 
+/// Copies of shared devices, for handing out.
+fn owned(devices: &[Arc<NodeDevice>]) -> Vec<NodeDevice> {
+    devices
+        .iter()
+        .map(|device| NodeDevice::clone(device))
+        .collect()
+}
+
 impl DeviceActorHandle {
     pub fn new(current: NodeDevice) -> Self {
         let (sender, receiver) = mpsc::channel(8);
@@ -141,7 +694,30 @@ impl DeviceActorHandle {
         Self { sender }
     }
 
+    /// Snapshots of the device list, copied for this receiver. Updates stop
+    /// once it's dropped.
     pub async fn listen(&self) -> watch::Receiver<Vec<NodeDevice>> {
+        let mut shared = self.listen_shared().await;
+        let (tx, rx) = watch::channel(owned(&shared.borrow_and_update()));
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    changed = shared.changed() => {
+                        if changed.is_err() {
+                            break;
+                        }
+                        tx.send_replace(owned(&shared.borrow_and_update()));
+                    }
+                    _ = tx.closed() => break,
+                }
+            }
+        });
+        rx
+    }
+
+    /// Snapshots of the device list as the actor keeps them, without copying
+    /// the devices. Clone the ones you keep.
+    pub(crate) async fn listen_shared(&self) -> watch::Receiver<Vec<Arc<NodeDevice>>> {
         let (send, recv) = oneshot::channel();
         let msg = DeviceMessage::Listen { respond_to: send };
 
@@ -149,6 +725,56 @@ impl DeviceActorHandle {
         recv.await.expect("Actor task has been killed")
     }
 
+    /// `listen` together with the device list at subscription time. The
+    /// snapshot is marked seen, so `changed` only fires for later updates
+    /// and none fall between the two.
+    pub async fn listen_with_snapshot(
+        &self,
+    ) -> (Vec<NodeDevice>, watch::Receiver<Vec<NodeDevice>>) {
+        let mut rx = self.listen().await;
+        let snapshot = rx.borrow_and_update().clone();
+        (snapshot, rx)
+    }
+
+    /// The device list by fingerprint as a stream, starting with the
+    /// current list and then after every change. Like with `listen`, changes
+    /// in quick succession may arrive as one. Ends when the actor stops.
+    pub async fn device_stream(&self) -> impl Stream<Item = HashMap<String, NodeDevice>> {
+        let rx = self.listen_shared().await;
+        futures::stream::unfold((rx, true), |(mut rx, first)| async move {
+            if !first {
+                rx.changed().await.ok()?;
+            }
+            let devices = rx
+                .borrow_and_update()
+                .iter()
+                .map(|device| (device.fingerprint.clone(), NodeDevice::clone(device)))
+                .collect();
+            Some((devices, (rx, false)))
+        })
+    }
+
+    pub async fn subscribe(&self) -> broadcast::Receiver<DeviceEvent> {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::Subscribe { respond_to: send };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Device announced from `address`. Several devices can share one behind
+    /// NAT or in containers, the most recently seen wins.
+    pub async fn get_device_by_address(&self, address: &str) -> Option<NodeDevice> {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::GetByAddress {
+            address: address.to_string(),
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
     pub async fn clear_devices(&self) {
         let (send, recv) = oneshot::channel();
         let msg = DeviceMessage::Clear { respond_to: send };
@@ -157,6 +783,158 @@ impl DeviceActorHandle {
         recv.await.expect("Actor task has been killed")
     }
 
+    /// Forgets the device with `fingerprint`, returning it if it was known.
+    pub async fn remove_device(&self, fingerprint: String) -> Option<NodeDevice> {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::Remove {
+            fingerprint,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Refreshes the last seen time of a known device.
+    pub async fn touch_device(&self, fingerprint: String) {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::Touch {
+            fingerprint,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Like `touch_device`, also recording that the device was seen at
+    /// `address`. A v4 address replaces a v6 one the device was known by.
+    pub async fn touch_device_at(&self, fingerprint: String, address: String) {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::TouchAt {
+            fingerprint,
+            address,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// The addresses the device with `fingerprint` was seen at, its current
+    /// one included, most recently seen first.
+    pub async fn get_device_addresses(&self, fingerprint: String) -> Vec<String> {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::GetAddresses {
+            fingerprint,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Devices recently evicted or removed with when they left, the latest
+    /// first. Only the last `DEFAULT_DEPARTURES_KEPT` are kept unless
+    /// changed with `set_departures_kept`.
+    pub async fn recent_departures(&self) -> Vec<(NodeDevice, Instant)> {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::GetDepartures { respond_to: send };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// The listed devices with their addresses and when they were last
+    /// seen, sorted by fingerprint.
+    pub async fn device_records(&self) -> Vec<DeviceRecord> {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::GetRecords { respond_to: send };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// The device list as a JSON array of [`DeviceRecord`], for monitoring
+    /// scripts and other tools.
+    pub async fn dump_devices_json(&self) -> String {
+        serde_json::to_string(&self.device_records().await).unwrap_or_else(|_| "[]".to_string())
+    }
+
+    /// Adds the devices of a `dump_devices_json` array, replacing listed
+    /// ones with the same fingerprint, to seed tests or restore a saved
+    /// list. Our own device is skipped. Returns how many were added.
+    pub async fn load_devices_json(&self, json: &str) -> Result<usize, String> {
+        let records =
+            serde_json::from_str::<Vec<DeviceRecord>>(json).map_err(|err| err.to_string())?;
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::Restore {
+            records,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        Ok(recv.await.expect("Actor task has been killed"))
+    }
+
+    /// Caps the device list, `None` lifts the cap. A new device over it
+    /// evicts the one seen longest ago, except those pinned with
+    /// `set_pinned`. Lowering it doesn't evict anyone until the next device
+    /// is added.
+    pub async fn set_max_devices(&self, max: Option<usize>) {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::SetMaxDevices {
+            max,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// How many departures `recent_departures` keeps, zero keeps none.
+    pub async fn set_departures_kept(&self, kept: usize) {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::SetDeparturesKept {
+            kept,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Devices that always get a place in the list, evicting others when
+    /// it's full, the favorites. The core keeps them in sync.
+    pub async fn set_pinned(&self, fingerprints: HashSet<String>) {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::SetPinned {
+            fingerprints,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Removes devices not seen within `ttl`, returning the evicted ones.
+    /// Those in `keep`, the favorites, are only marked offline.
+    pub async fn evict_stale_devices(
+        &self,
+        ttl: Duration,
+        keep: HashSet<String>,
+    ) -> Vec<NodeDevice> {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::Evict {
+            ttl,
+            keep,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
     pub async fn add_node_device(&self, device: NodeDevice) {
         let (send, recv) = oneshot::channel();
         let msg = DeviceMessage::Add {
@@ -176,6 +954,19 @@ impl DeviceActorHandle {
         recv.await.expect("Actor task has been killed")
     }
 
+    /// Number of known devices, not counting ourselves.
+    pub async fn device_count(&self) -> usize {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::Count { respond_to: send };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    pub async fn has_devices(&self) -> bool {
+        self.device_count().await > 0
+    }
+
     pub async fn get_device(&self, fingerprint: String) -> Option<NodeDevice> {
         let (send, recv) = oneshot::channel();
         let msg = DeviceMessage::Get {
@@ -187,10 +978,48 @@ impl DeviceActorHandle {
         recv.await.expect("Actor task has been killed")
     }
 
+    /// Resolves once a device with `fingerprint` is known, or with `None` when
+    /// it doesn't show up within `timeout`.
+    pub async fn wait_for_device(
+        &self,
+        fingerprint: &str,
+        timeout: Duration,
+    ) -> Option<NodeDevice> {
+        let mut rx = self.listen_shared().await;
+        let wait = async {
+            loop {
+                let found = rx
+                    .borrow_and_update()
+                    .iter()
+                    .find(|device| device.fingerprint == fingerprint)
+                    .map(|device| NodeDevice::clone(device));
+                if found.is_some() {
+                    return found;
+                }
+                if rx.changed().await.is_err() {
+                    return None;
+                }
+            }
+        };
+        tokio::time::timeout(timeout, wait).await.ok().flatten()
+    }
+
     pub async fn check_device_exist(&self, fingerprint: String) -> bool {
         let (send, recv) = oneshot::channel();
         let msg = DeviceMessage::CheckExist {
-            fingerprint: fingerprint,
+            fingerprint,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Whether the device is known and hasn't been marked offline.
+    pub async fn is_device_online(&self, fingerprint: String) -> bool {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::IsOnline {
+            fingerprint,
             respond_to: send,
         };
 
@@ -209,6 +1038,43 @@ impl DeviceActorHandle {
         recv.await.expect("Actor task has been killed")
     }
 
+    /// Replaces only the address of the current device, returning whether it
+    /// changed.
+    pub async fn update_address(&self, address: IpAddr) -> bool {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::UpdateAddress {
+            address: address.to_string(),
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Our announce as sent over multicast, serialized once per change of
+    /// the current device.
+    pub async fn get_current_announce(&self, format: WireFormat) -> Arc<Vec<u8>> {
+        self.get_announce(false, format).await
+    }
+
+    /// Like `get_current_announce`, with the flags cleared as in
+    /// `NodeDevice::to_reply`.
+    pub async fn get_current_reply(&self, format: WireFormat) -> Arc<Vec<u8>> {
+        self.get_announce(true, format).await
+    }
+
+    async fn get_announce(&self, reply: bool, format: WireFormat) -> Arc<Vec<u8>> {
+        let (send, recv) = oneshot::channel();
+        let msg = DeviceMessage::GetAnnounce {
+            reply,
+            format,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
     pub async fn get_current_device(&self) -> NodeDevice {
         let (send, recv) = oneshot::channel();
         let msg = DeviceMessage::GetCurrent { respond_to: send };
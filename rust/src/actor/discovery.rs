@@ -1,18 +1,158 @@
-use std::net::IpAddr;
-use std::net::Ipv4Addr;
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use log::{debug, info};
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use rand::Rng;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
 use tokio::sync::mpsc;
 use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::{debug, debug_span, error, info, warn, Instrument, Span};
 
 use tokio::net::UdpSocket;
+use tokio::time;
 
-use crate::actor::model::NodeDevice;
+use crate::actor::model::{
+    api_path, api_url, normalize_protocol, NodeAnnounce, NodeDevice, API_PATH,
+};
+use crate::tls;
+use crate::util::{
+    default_interface_v4, hex_string, interface_addrs, interface_ipv4_by_name, parse_scoped_ip,
+    scoped_ip_string,
+};
 
 use super::core::CoreActorHandle;
-use super::core::CoreConfig;
+use super::core::{CoreConfig, DiscoveryConfig};
+
+/// The group LocalSend devices announce on.
+pub const DEFAULT_MULTICAST: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 167), 53317);
+
+/// The link local IPv6 group, used next to the v4 one in dual stack mode.
+pub const DEFAULT_MULTICAST_V6: SocketAddrV6 =
+    SocketAddrV6::new(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x167), 53317, 0, 0);
+
+/// Approves discovered peers, e.g. against an allowlist of fingerprints.
+/// Rejected peers are neither added to the device list nor registered with.
+#[derive(Clone)]
+pub struct PeerFilter(Arc<dyn Fn(&NodeDevice) -> bool + Send + Sync>);
+
+impl PeerFilter {
+    pub fn new<F>(allow: F) -> Self
+    where
+        F: Fn(&NodeDevice) -> bool + Send + Sync + 'static,
+    {
+        PeerFilter(Arc::new(allow))
+    }
+
+    pub fn allows(&self, device: &NodeDevice) -> bool {
+        (self.0)(device)
+    }
+}
+
+impl fmt::Debug for PeerFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PeerFilter")
+    }
+}
+
+/// Told about announces that couldn't be sent, like while the Wi-Fi is
+/// down. Announcing carries on regardless.
+#[derive(Clone)]
+pub struct AnnounceErrorHandler(Arc<dyn Fn(&io::Error) + Send + Sync>);
+
+impl AnnounceErrorHandler {
+    pub fn new<F>(handle: F) -> Self
+    where
+        F: Fn(&io::Error) + Send + Sync + 'static,
+    {
+        AnnounceErrorHandler(Arc::new(handle))
+    }
+
+    pub fn handle(&self, err: &io::Error) {
+        (self.0)(err)
+    }
+}
+
+impl fmt::Debug for AnnounceErrorHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AnnounceErrorHandler")
+    }
+}
+
+/// Running totals of discovery activity, shared by everything that sends
+/// or receives for discovery.
+#[derive(Debug, Default)]
+pub struct DiscoveryCounters {
+    packets_received: AtomicU64,
+    parse_failures: AtomicU64,
+    announces_sent: AtomicU64,
+    registers_attempted: AtomicU64,
+    registers_succeeded: AtomicU64,
+    registers_failed: AtomicU64,
+}
+
+/// Snapshot of the counters, for telling a network that drops our multicast
+/// from peers we can't understand.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiscoveryMetrics {
+    pub packets_received: u64,
+    pub parse_failures: u64,
+    pub announces_sent: u64,
+    pub registers_attempted: u64,
+    pub registers_succeeded: u64,
+    pub registers_failed: u64,
+    pub nodes_known: usize,
+}
+
+impl DiscoveryCounters {
+    fn add(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, nodes_known: usize) -> DiscoveryMetrics {
+        DiscoveryMetrics {
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            parse_failures: self.parse_failures.load(Ordering::Relaxed),
+            announces_sent: self.announces_sent.load(Ordering::Relaxed),
+            registers_attempted: self.registers_attempted.load(Ordering::Relaxed),
+            registers_succeeded: self.registers_succeeded.load(Ordering::Relaxed),
+            registers_failed: self.registers_failed.load(Ordering::Relaxed),
+            nodes_known,
+        }
+    }
+}
+
+/// Spaces out multicast re-announces. Our announce is the same for every
+/// peer, so one send covers all those asking within a window.
+#[derive(Debug, Default)]
+struct ReannounceLimiter {
+    /// When the latest send was scheduled for, possibly in the future.
+    last: Mutex<Option<Instant>>,
+}
+
+impl ReannounceLimiter {
+    /// How long to wait before sending, or `None` when a send already
+    /// scheduled will cover this one. The first send in a quiet period goes
+    /// out at once, later ones within `window` are pushed to its end.
+    fn schedule(&self, window: Duration) -> Option<Duration> {
+        let now = Instant::now();
+        let mut last = self.last.lock();
+        let at = match *last {
+            Some(last) if last > now => return None,
+            Some(last) if now.duration_since(last) < window => last + window,
+            _ => now,
+        };
+        *last = Some(at);
+        Some(at - now)
+    }
+}
 
 enum DiscoverMessage {
     Shutdown,
@@ -21,164 +161,1453 @@ enum DiscoverMessage {
 struct DiscoverActor {
     receiver: mpsc::Receiver<DiscoverMessage>,
     core: CoreActorHandle,
+    /// When each fingerprint was last fully handled, for the debounce.
+    recent: HashMap<String, Instant>,
+    /// Replies and re-announces in flight, aborted on shutdown.
+    tasks: JoinSet<()>,
+    reannounce: Arc<ReannounceLimiter>,
 }
 
-async fn register(current: NodeDevice, target: NodeDevice) -> bool {
-    let api = format!(
-        "{}://{}:{}/api/localsend/v2/register",
-        target.protocol,
-        target.address,
-        target.port.to_string()
-    );
-    let announce = current.to_announce();
+#[derive(Debug)]
+pub enum DiscoveryError {
+    InvalidAddress(String),
+    InvalidMulticastAddress(String),
+    BindFailed(io::Error),
+    JoinMulticastFailed(io::Error),
+    LeaveMulticastFailed(io::Error),
+    /// Receiving failed in a way retrying won't fix, discovery stopped.
+    RecvFailed(io::Error),
+    NodeNotInitialized,
+}
 
-    let message = serde_json::to_string(&announce).unwrap();
-    let resp = ureq::post(&api)
-        .set("X-My-Header", "Secret")
-        .send_string(&message);
-    match resp {
-        Ok(_) => {
-            debug!("register success");
-            true
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiscoveryError::InvalidAddress(addr) => write!(f, "invalid address {}", addr),
+            DiscoveryError::InvalidMulticastAddress(addr) => {
+                write!(f, "{} is not a multicast group", addr)
+            }
+            DiscoveryError::BindFailed(err) => write!(f, "couldn't bind to address: {}", err),
+            DiscoveryError::JoinMulticastFailed(err) => {
+                write!(f, "failed to join multicast: {}", err)
+            }
+            DiscoveryError::LeaveMulticastFailed(err) => {
+                write!(f, "failed to leave multicast: {}", err)
+            }
+            DiscoveryError::RecvFailed(err) => write!(f, "receiving announces failed: {}", err),
+            DiscoveryError::NodeNotInitialized => write!(f, "current node not initialized"),
         }
-        Err(_) => {
-            debug!("register failed");
-            false
+    }
+}
+
+impl std::error::Error for DiscoveryError {}
+
+/// Multicast group and the local interface used to join it, resolved from
+/// `CoreConfig`. Either both are IPv4 or the group is IPv6 and the interface
+/// is identified by its zone index.
+#[derive(Debug, Clone, Copy)]
+struct MulticastGroup {
+    addr: IpAddr,
+    port: u16,
+    interface: IpAddr,
+    scope_id: u32,
+}
+
+impl MulticastGroup {
+    fn from_config(config: &CoreConfig) -> Result<Self, DiscoveryError> {
+        let addr = IpAddr::from_str(&config.multicast_addr)
+            .map_err(|_| DiscoveryError::InvalidAddress(config.multicast_addr.clone()))?;
+        if !addr.is_multicast() || config.multicast_port == 0 {
+            return Err(DiscoveryError::InvalidMulticastAddress(format!(
+                "{}:{}",
+                config.multicast_addr, config.multicast_port
+            )));
+        }
+        let (interface, scope_id) = parse_scoped_ip(&config.interface_addr)
+            .ok_or_else(|| DiscoveryError::InvalidAddress(config.interface_addr.clone()))?;
+
+        // the default interface is `0.0.0.0`, which means "any" for v6 groups too
+        let interface = match (addr, interface) {
+            (IpAddr::V6(_), IpAddr::V4(ip)) if ip.is_unspecified() => {
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            }
+            _ => interface,
+        };
+
+        Ok(MulticastGroup {
+            addr,
+            port: config.multicast_port,
+            interface,
+            scope_id,
+        })
+    }
+
+    fn target(&self) -> SocketAddr {
+        match self.addr {
+            IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, self.port)),
+            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, self.port, 0, self.scope_id)),
+        }
+    }
+
+    fn local(&self, port: u16) -> SocketAddr {
+        match self.interface {
+            IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
+            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, self.scope_id)),
+        }
+    }
+
+    fn join(&self, socket: &UdpSocket) -> io::Result<()> {
+        match (self.addr, self.interface) {
+            (IpAddr::V4(group), IpAddr::V4(interface)) => {
+                socket.join_multicast_v4(group, interface)
+            }
+            (IpAddr::V6(group), IpAddr::V6(_)) => socket.join_multicast_v6(&group, self.scope_id),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "interface and multicast address families differ",
+            )),
+        }
+    }
+
+    fn leave(&self, socket: &UdpSocket) -> io::Result<()> {
+        match (self.addr, self.interface) {
+            (IpAddr::V4(group), IpAddr::V4(interface)) => {
+                socket.leave_multicast_v4(group, interface)
+            }
+            (IpAddr::V6(group), IpAddr::V6(_)) => socket.leave_multicast_v6(&group, self.scope_id),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "interface and multicast address families differ",
+            )),
+        }
+    }
+
+    fn set_ttl(&self, socket: &UdpSocket, ttl: u32) -> io::Result<()> {
+        match self.addr {
+            IpAddr::V4(_) => socket.set_multicast_ttl_v4(ttl),
+            IpAddr::V6(_) => SockRef::from(socket).set_multicast_hops_v6(ttl),
+        }
+    }
+
+    /// Sets multicast loopback. Linux and macOS apply it to what the socket
+    /// sends, windows to what it receives, so it's set on both ends.
+    fn set_loop(&self, socket: &UdpSocket, enabled: bool) -> io::Result<()> {
+        match self.addr {
+            IpAddr::V4(_) => socket.set_multicast_loop_v4(enabled),
+            IpAddr::V6(_) => socket.set_multicast_loop_v6(enabled),
+        }
+    }
+
+    /// The same group on every up, non loopback interface of its family,
+    /// one entry per interface. v4 link local interfaces only count with
+    /// `link_local`.
+    fn on_all_interfaces(&self, link_local: bool) -> Vec<MulticastGroup> {
+        let mut groups: Vec<MulticastGroup> = Vec::new();
+        for iface in interface_addrs().into_iter().filter(|i| !i.loopback) {
+            let group = match (self.addr, iface.addr) {
+                (IpAddr::V4(_), IpAddr::V4(ip)) if link_local || !ip.is_link_local() => {
+                    MulticastGroup {
+                        interface: iface.addr,
+                        scope_id: 0,
+                        ..*self
+                    }
+                }
+                // v6 joins are keyed by interface index, one address is enough
+                (IpAddr::V6(_), IpAddr::V6(_))
+                    if !groups.iter().any(|g| g.scope_id == iface.index) =>
+                {
+                    MulticastGroup {
+                        interface: iface.addr,
+                        scope_id: iface.index,
+                        ..*self
+                    }
+                }
+                _ => continue,
+            };
+            groups.push(group);
+        }
+        groups
+    }
+
+    /// The LocalSend group of the other address family on the same port,
+    /// with the interface left for the OS to pick.
+    fn counterpart(&self) -> MulticastGroup {
+        let addr = match self.addr {
+            IpAddr::V4(_) => IpAddr::V6(*DEFAULT_MULTICAST_V6.ip()),
+            IpAddr::V6(_) => IpAddr::V4(*DEFAULT_MULTICAST.ip()),
+        };
+        MulticastGroup { addr, ..*self }.any_interface()
+    }
+
+    /// The group with the interface left for the OS to pick.
+    fn any_interface(&self) -> MulticastGroup {
+        let interface = match self.addr {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        MulticastGroup {
+            interface,
+            scope_id: 0,
+            ..*self
         }
     }
+
+    /// Binds a socket on the interface and joins the group. Address (and on
+    /// unix, port) reuse is enabled so several LocalSend aware processes can
+    /// share the multicast port and quick restarts don't hit "address in use".
+    fn bind(&self, port: u16) -> Result<UdpSocket, DiscoveryError> {
+        let socket = self.open(port)?;
+        self.join(&socket)
+            .map_err(DiscoveryError::JoinMulticastFailed)?;
+        Ok(socket)
+    }
+
+    /// Binds a socket for sending announces, preferring `port` but taking
+    /// any free one when it's busy, since nothing needs to reach it there.
+    fn bind_send(&self, port: u16) -> Result<UdpSocket, DiscoveryError> {
+        let socket = match self.bind(port) {
+            Ok(socket) => socket,
+            Err(err) => {
+                warn!("couldn't bind send socket to {}: {}", port, err);
+                self.bind(0)?
+            }
+        };
+        if let Ok(local) = socket.local_addr() {
+            debug!("send socket bound to port {}", local.port());
+        }
+        Ok(socket)
+    }
+
+    /// Binds a socket on the interface without joining the group. Outgoing
+    /// multicast leaves through that interface.
+    fn open(&self, port: u16) -> Result<UdpSocket, DiscoveryError> {
+        let local = self.local(port);
+        let socket = Socket::new(Domain::for_address(local), Type::DGRAM, Some(Protocol::UDP))
+            .map_err(DiscoveryError::BindFailed)?;
+
+        socket
+            .set_reuse_address(true)
+            .map_err(DiscoveryError::BindFailed)?;
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket
+            .set_reuse_port(true)
+            .map_err(DiscoveryError::BindFailed)?;
+        socket
+            .set_nonblocking(true)
+            .map_err(DiscoveryError::BindFailed)?;
+        socket
+            .bind(&local.into())
+            .map_err(DiscoveryError::BindFailed)?;
+        match self.interface {
+            IpAddr::V4(ip) if !ip.is_unspecified() => socket.set_multicast_if_v4(&ip),
+            IpAddr::V6(_) if self.scope_id != 0 => socket.set_multicast_if_v6(self.scope_id),
+            _ => Ok(()),
+        }
+        .map_err(DiscoveryError::BindFailed)?;
+
+        UdpSocket::from_std(socket.into()).map_err(DiscoveryError::BindFailed)
+    }
 }
 
-async fn announce(config: CoreConfig, current: String) {
-    let interface_addr = Ipv4Addr::from_str(&config.interface_addr).unwrap();
-    let multicast_addr = Ipv4Addr::from_str(&config.multicast_addr).unwrap();
-    let multicast_port = config.multicast_port;
+const REGISTER_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const REGISTER_READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Agents unused for this long are dropped with their connections, so we
+/// don't hold sockets to peers that left.
+const REGISTER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
-    let send_socket: UdpSocket = UdpSocket::bind((interface_addr, multicast_port + 2))
-        .await
-        .expect("couldn't bind to address");
-
-    send_socket
-        .join_multicast_v4(multicast_addr, interface_addr)
-        .expect("failed to join multicast");
-
-    let buf = current.as_bytes();
-    for _ in 1..3 {
-        let _ = send_socket
-            .send_to(
-                buf,
-                SocketAddr::new(IpAddr::from(multicast_addr), multicast_port),
-            )
-            .await;
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct AgentKey {
+    addr: SocketAddr,
+    /// Pinned fingerprint of https peers.
+    fingerprint: Option<String>,
+    insecure_tls: bool,
+}
+
+/// Register clients kept per peer, so repeated registers reuse the kept
+/// alive connection instead of connecting again, which for https peers
+/// saves the TLS handshake.
+#[derive(Default)]
+pub(crate) struct RegisterClients {
+    agents: Mutex<HashMap<AgentKey, (ureq::Agent, Instant)>>,
+}
+
+impl RegisterClients {
+    fn agent(
+        &self,
+        addr: SocketAddr,
+        protocol: &str,
+        fingerprint: &str,
+        insecure_tls: bool,
+    ) -> ureq::Agent {
+        let key = AgentKey {
+            addr,
+            fingerprint: (normalize_protocol(protocol) == "https").then(|| fingerprint.to_string()),
+            insecure_tls,
+        };
+        let now = Instant::now();
+        let mut agents = self.agents.lock();
+        agents.retain(|_, (_, used)| now.duration_since(*used) < REGISTER_IDLE_TIMEOUT);
+        let (agent, used) = agents.entry(key).or_insert_with_key(|key| {
+            // urls can't carry an IPv6 zone index, so hand the resolved address to ureq
+            let mut builder = ureq::AgentBuilder::new()
+                .resolver(move |_: &str| Ok(vec![addr]))
+                .timeout_connect(REGISTER_CONNECT_TIMEOUT)
+                .timeout_read(REGISTER_READ_TIMEOUT);
+            // https peers must present the certificate their fingerprint is the hash of
+            if let Some(fingerprint) = &key.fingerprint {
+                builder = builder.tls_config(tls::client_config(
+                    Some(fingerprint.clone()),
+                    key.insecure_tls,
+                ));
+            }
+            (builder.build(), now)
+        });
+        *used = now;
+        agent.clone()
     }
 }
 
-async fn run_udp_actor(mut actor: DiscoverActor, shutdown_callback: watch::Sender<bool>) {
-    let config = actor.core.get_config().await;
-    let interface_addr = Ipv4Addr::from_str(&config.interface_addr).unwrap();
-    let multicast_addr = Ipv4Addr::from_str(&config.multicast_addr).unwrap();
-    let multicast_port = config.multicast_port;
+/// Registers `current` with `target`, retrying with exponential backoff as
+/// configured. Peers are often still starting their server or busy with a
+/// transfer when they announce. Returns the peer as it described itself in
+/// the answer, `None` when it never answered.
+async fn register(
+    current: NodeDevice,
+    target: NodeDevice,
+    config: &DiscoveryConfig,
+    counters: &DiscoveryCounters,
+    clients: &RegisterClients,
+) -> Option<NodeDevice> {
+    let addr = match target.socket_addr() {
+        Some(addr) => addr,
+        None => {
+            debug!("register with {} failed: invalid address", target);
+            return None;
+        }
+    };
 
-    info!("udp service {} started", multicast_port);
+    let retry = config.register;
 
-    let rec_socket = UdpSocket::bind((interface_addr, multicast_port))
+    let mut backoff = retry.backoff;
+    let headers = config.register_headers();
+    for attempt in 1..=retry.attempts.max(1) {
+        let api = api_path(&target.version);
+        let agent = clients.agent(
+            addr,
+            &target.protocol,
+            &target.fingerprint,
+            config.insecure_tls,
+        );
+        match try_register(
+            &current,
+            &target.protocol,
+            addr,
+            api,
+            &headers,
+            agent,
+            counters,
+        )
         .await
-        .expect("couldn't bind to address");
+        {
+            Ok(body) => {
+                debug!("registered with {}", target);
+                return Some(registered_peer(target, &body));
+            }
+            Err(err) => {
+                debug!(
+                    "register attempt {} with {} failed: {}",
+                    attempt, target, err
+                );
+            }
+        }
+        if attempt < retry.attempts {
+            time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+    None
+}
+
+/// `target` updated with the announce it answered a register with, which
+/// is fresher than what multicast told us. Fields the answer lacks, like
+/// the port the official app leaves out, keep their known value. An empty
+/// or unusable answer leaves `target` as it was.
+fn registered_peer(target: NodeDevice, body: &str) -> NodeDevice {
+    if body.trim().is_empty() {
+        return target;
+    }
+    let fields = match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(fields)) => fields,
+        _ => {
+            debug!("register answer of {} isn't an announce", target);
+            return target;
+        }
+    };
+    let mut known = match serde_json::to_value(NodeAnnounce::from(&target)) {
+        Ok(serde_json::Value::Object(known)) => known,
+        _ => return target,
+    };
+    known.extend(fields);
+    match serde_json::from_value::<NodeAnnounce>(serde_json::Value::Object(known)) {
+        Ok(announce) if announce.fingerprint != target.fingerprint => {
+            warn!("{} answered as {}", target, announce.fingerprint);
+            target
+        }
+        Ok(announce) if !announce.is_compatible() => target,
+        Ok(announce) => NodeDevice::from_announce(&announce, &target.address),
+        Err(err) => {
+            debug!("bad register answer of {}: {}", target, err);
+            target
+        }
+    }
+}
+
+/// Posts our announce to the register endpoint of the api under `api_path`
+/// at `addr` and returns the response body. ureq is blocking, so the
+/// request runs on the blocking pool instead of a runtime worker.
+async fn try_register(
+    current: &NodeDevice,
+    protocol: &str,
+    addr: SocketAddr,
+    api_path: &str,
+    headers: &[(String, String)],
+    agent: ureq::Agent,
+    counters: &DiscoveryCounters,
+) -> Result<String, String> {
+    DiscoveryCounters::add(&counters.registers_attempted);
+    let result = post_register(current, protocol, addr, api_path, headers, agent).await;
+    match &result {
+        Ok(_) => DiscoveryCounters::add(&counters.registers_succeeded),
+        Err(_) => DiscoveryCounters::add(&counters.registers_failed),
+    }
+    result
+}
 
-    let send_socket: UdpSocket = UdpSocket::bind((interface_addr, multicast_port + 1))
+async fn post_register(
+    current: &NodeDevice,
+    protocol: &str,
+    addr: SocketAddr,
+    api_path: &str,
+    headers: &[(String, String)],
+    agent: ureq::Agent,
+) -> Result<String, String> {
+    let api = api_url(protocol, addr, api_path, "/register");
+    let announce = current.to_announce();
+    let message = serde_json::to_string(&announce).map_err(|err| err.to_string())?;
+    let headers = headers.to_vec();
+
+    tokio::task::spawn_blocking(move || {
+        let mut request = agent.post(&api).set("Content-Type", "application/json");
+        for (name, value) in &headers {
+            request = request.set(name, value);
+        }
+        request
+            .send_string(&message)
+            .map_err(|err| err.to_string())?
+            .into_string()
+            .map_err(|err| err.to_string())
+    })
+    .await
+    .map_err(|err| err.to_string())?
+}
+
+/// Fetches the info endpoint of the api under `api_path` at `addr`, which
+/// unlike a register doesn't tell the peer about us.
+async fn get_info(
+    protocol: &str,
+    addr: SocketAddr,
+    api_path: &str,
+    headers: &[(String, String)],
+    agent: ureq::Agent,
+) -> Result<String, String> {
+    let api = api_url(protocol, addr, api_path, "/info");
+    let headers = headers.to_vec();
+
+    tokio::task::spawn_blocking(move || {
+        let mut request = agent.get(&api);
+        for (name, value) in &headers {
+            request = request.set(name, value);
+        }
+        request
+            .call()
+            .map_err(|err| err.to_string())?
+            .into_string()
+            .map_err(|err| err.to_string())
+    })
+    .await
+    .map_err(|err| err.to_string())?
+}
+
+/// Registers with each of `targets` over http, for networks where multicast
+/// is blocked. Every peer that answers with its announce is added to the
+/// device list, and the discovered devices are returned. Nothing happens in
+/// observe only mode.
+pub async fn discover_http(core: CoreActorHandle, targets: Vec<SocketAddr>) -> Vec<NodeDevice> {
+    let current = core.device.get_current_device().await;
+    let discovery_config = core.get_discovery_config().await;
+    if discovery_config.observe_only {
+        debug!("observe only, not registering");
+        return Vec::new();
+    }
+    let headers = discovery_config.register_headers();
+    let counters = &core.discovery_counters;
+    let clients = &core.register_clients;
+
+    let probes = targets.into_iter().map(|addr| {
+        let current = &current;
+        let headers = &headers;
+        async move {
+            let agent = clients.agent(addr, "http", "", false);
+            let body = match try_register(current, "http", addr, API_PATH, headers, agent, counters)
+                .await
+            {
+                Ok(body) => body,
+                Err(err) => {
+                    debug!("http discovery {} failed: {}", addr, err);
+                    return None;
+                }
+            };
+            match NodeAnnounce::parse(&body) {
+                Ok(announce) if !announce.is_compatible() => {
+                    warn!(
+                        "http discovery {} speaks protocol {}",
+                        addr, announce.version
+                    );
+                    None
+                }
+                Ok(announce) => Some(NodeDevice::from_announce(
+                    &announce,
+                    &scoped_ip_string(&addr),
+                )),
+                Err(err) => {
+                    debug!("http discovery {} bad response: {}", addr, err);
+                    None
+                }
+            }
+        }
+    });
+
+    let mut found = Vec::new();
+    for device in futures::future::join_all(probes)
         .await
-        .expect("couldn't bind to address");
+        .into_iter()
+        .flatten()
+    {
+        if device.fingerprint == current.fingerprint {
+            continue;
+        }
+        if !discovery_config.allows_peer(&device) {
+            debug!("ignore {}, rejected by the peer filter", device);
+            continue;
+        }
+        core.device.add_node_device(device.clone()).await;
+        core.favorites.refresh(device.clone()).await;
+        found.push(device);
+    }
+    found
+}
 
-    rec_socket
-        .join_multicast_v4(multicast_addr, interface_addr)
-        .expect("failed to join multicast");
+/// Registers with every known device again, for peers that restarted and
+/// forgot us while we still list them. Devices seen at several addresses
+/// are tried at the others too, most recent first. Devices that don't
+/// answer at any after the configured retries are dropped and returned.
+/// Nothing happens in observe only mode.
+pub async fn refresh(core: CoreActorHandle) -> Vec<NodeDevice> {
+    let current = core.device.get_current_device().await.to_reply();
+    let discovery_config = core.get_discovery_config().await;
+    if discovery_config.observe_only {
+        debug!("observe only, not registering");
+        return Vec::new();
+    }
+    let counters = &core.discovery_counters;
+    let clients = &core.register_clients;
 
-    send_socket
-        .join_multicast_v4(multicast_addr, interface_addr)
-        .expect("failed to join multicast");
+    let devices = core.device.get_device_map().await.into_values();
+    let registers = devices.map(|device| {
+        let current = current.clone();
+        let discovery_config = &discovery_config;
+        let device_handle = &core.device;
+        async move {
+            let mut addresses = device_handle
+                .get_device_addresses(device.fingerprint.clone())
+                .await;
+            addresses.retain(|address| *address != device.address);
+            addresses.insert(0, device.address.clone());
+            for address in addresses {
+                let target = NodeDevice {
+                    address,
+                    ..device.clone()
+                };
+                let answer =
+                    register(current.clone(), target, discovery_config, counters, clients).await;
+                if answer.is_some() {
+                    return (device, answer);
+                }
+            }
+            (device, None)
+        }
+    });
 
-    let mut buf: [u8; 1024] = [0; 1024];
+    let mut pruned = Vec::new();
+    for (device, answer) in futures::future::join_all(registers).await {
+        if let Some(answer) = answer {
+            core.device.add_node_device(answer).await;
+            continue;
+        }
+        debug!("{} didn't answer, dropping it", device);
+        if let Some(device) = core.device.remove_device(device.fingerprint).await {
+            pruned.push(device);
+        }
+    }
+    pruned
+}
 
-    let device_handle = actor.core.device.clone();
+/// Whether the http api of `target` answers within `timeout`, to check a
+/// listed device is still there before a transfer. It's a single register
+/// without the configured retries, so the peer learns about us too, or in
+/// observe only mode a fetch of its info. An answer from another device at
+/// its address doesn't count.
+pub async fn probe(core: &CoreActorHandle, target: &NodeDevice, timeout: Duration) -> bool {
+    let addr = match target.socket_addr() {
+        Some(addr) => addr,
+        None => return false,
+    };
+    let current = core.device.get_current_device().await.to_reply();
+    let discovery_config = core.get_discovery_config().await;
+    let headers = discovery_config.register_headers();
+    let agent = core.register_clients.agent(
+        addr,
+        &target.protocol,
+        &target.fingerprint,
+        discovery_config.insecure_tls,
+    );
+    let api = api_path(&target.version);
+    let request: BoxFuture<Result<String, String>> = if discovery_config.observe_only {
+        Box::pin(get_info(&target.protocol, addr, api, &headers, agent))
+    } else {
+        Box::pin(try_register(
+            &current,
+            &target.protocol,
+            addr,
+            api,
+            &headers,
+            agent,
+            &core.discovery_counters,
+        ))
+    };
+    match time::timeout(timeout, request).await {
+        // the answer is the peer's info, possibly partial, or empty from older peers
+        Ok(Ok(body)) => match serde_json::from_str::<serde_json::Value>(&body) {
+            Ok(info) => info
+                .get("fingerprint")
+                .and_then(serde_json::Value::as_str)
+                .is_none_or(|fingerprint| fingerprint == target.fingerprint),
+            Err(_) => body.trim().is_empty(),
+        },
+        Ok(Err(err)) => {
+            debug!("probe of {} failed: {}", target, err);
+            false
+        }
+        Err(_) => {
+            debug!("probe of {} timed out", target);
+            false
+        }
+    }
+}
 
-    loop {
-        let current = device_handle.get_current_device().await;
-        let s_message = serde_json::to_string(&current).unwrap();
-        let core_config = config.clone();
+/// Discovers nearby devices without `serve`, for one-shot tools: opens the
+/// discovery sockets, announces, collects the announces arriving within
+/// `window` and closes the sockets again, not announcing in observe only
+/// mode. The device list is left alone and nobody is registered with.
+/// Peers answer by registering with our http server, and when it isn't
+/// running with an announce to our multicast port or the group, which is
+/// what this hears.
+pub async fn scan(
+    core: &CoreActorHandle,
+    window: Duration,
+) -> Result<HashMap<String, NodeDevice>, DiscoveryError> {
+    let config = core.get_config().await;
+    let discovery_config = core.get_discovery_config().await;
+    let current = core.device.get_current_device().await;
+    let message = core
+        .device
+        .get_current_announce(discovery_config.wire_format)
+        .await;
+    let max_size = discovery_config.recv_buffer_size;
+    let announce_config = discovery_config.announce;
+    let deadline = time::sleep(window);
+    tokio::pin!(deadline);
+
+    let repeat = if discovery_config.observe_only {
+        0
+    } else {
+        announce_config.repeat
+    };
+
+    let mut sockets = DiscoverySockets::open_all(&config, &discovery_config)?;
+    for i in 0..repeat {
+        if i > 0 {
+            time::sleep(jittered(announce_config.interval, announce_config.jitter)).await;
+        }
+        for set in &sockets {
+            let target = match set.target {
+                Some(target) => target,
+                None => continue,
+            };
+            match set.send.send_to(&message, target).await {
+                Ok(_) => DiscoveryCounters::add(&core.discovery_counters.announces_sent),
+                Err(err) => warn!("scan announce to {} failed: {}", target, err),
+            }
+        }
+    }
 
+    let mut found = HashMap::new();
+    let mut failure = None;
+    loop {
         tokio::select! {
-            Ok((size, addr)) = rec_socket.recv_from(&mut buf) => {
-                if current.address == addr.to_string() {
-                    debug!("self loop");
+            received = recv_any(&mut sockets) => {
+                let (size, addr, index) = match received {
+                    Ok(received) => received,
+                    Err(err) if is_transient(&err) => continue,
+                    Err(err) => {
+                        failure = Some(DiscoveryError::RecvFailed(err));
+                        break;
+                    }
+                };
+                if size > max_size || !source_allowed(&discovery_config, addr.ip()) {
                     continue
                 }
-                debug!("recv msg");
-                let message = String::from_utf8_lossy(&buf[..size]);
-                match serde_json::from_str(&message) {
-                    Ok(node_announce) => {
+                let announce = match NodeAnnounce::decode(&sockets[index].buf[..size]) {
+                    Ok(announce) => announce,
+                    Err(err) => {
+                        debug!("skip malformed packet from {}: {}", addr, err);
+                        log_malformed(&discovery_config, addr, &sockets[index].buf[..size]);
+                        continue
+                    }
+                };
+                if announce.fingerprint == current.fingerprint || !announce.is_compatible() {
+                    continue
+                }
+                let device = NodeDevice::from_announce(&announce, &scoped_ip_string(&addr));
+                if discovery_config.allows_peer(&device) {
+                    found.insert(device.fingerprint.clone(), device);
+                }
+            }
+            _ = &mut deadline => break,
+        }
+    }
 
+    let closed = DiscoverySockets::close_all(sockets);
+    match failure {
+        Some(err) => Err(err),
+        None => closed.map(|()| found),
+    }
+}
 
-                        let device = NodeDevice::from_announce(&node_announce, &addr.ip().to_string());
-                        let exist = device_handle.check_device_exist(device.fingerprint.clone()).await;
+/// The groups discovery runs on, one per interface when `all_interfaces` is
+/// set and the configured one otherwise. Dual stack adds the same for the
+/// group of the other family.
+fn discovery_groups(
+    config: &CoreConfig,
+    discovery_config: &DiscoveryConfig,
+) -> Result<Vec<MulticastGroup>, DiscoveryError> {
+    Ok(family_groups(config, discovery_config)?
+        .into_iter()
+        .flat_map(|group| interface_groups(group, discovery_config))
+        .collect())
+}
 
+/// The configured group, followed by its counterpart in dual stack mode.
+fn family_groups(
+    config: &CoreConfig,
+    discovery_config: &DiscoveryConfig,
+) -> Result<Vec<MulticastGroup>, DiscoveryError> {
+    let group = MulticastGroup::from_config(config)?;
+    let mut groups = vec![group];
+    if discovery_config.dual_stack {
+        groups.push(group.counterpart());
+    }
+    Ok(groups)
+}
 
-                        if current.fingerprint == device.fingerprint {
-                            debug!("self loop");
-                        } else if exist {
-                            tokio::spawn(
-                                async {
-                                    register(current, device).await;
-                                }
-                            );
-                        } else {
-                            debug!("node {:?}", device);
+fn interface_groups(
+    group: MulticastGroup,
+    discovery_config: &DiscoveryConfig,
+) -> Vec<MulticastGroup> {
+    if discovery_config.all_interfaces {
+        let groups = group.on_all_interfaces(discovery_config.link_local);
+        if !groups.is_empty() {
+            return groups;
+        }
+        warn!(
+            "no interface found for {}, using the configured one",
+            group.addr
+        );
+    }
+    vec![group]
+}
 
-                            device_handle.add_node_device(device.clone()).await;
+pub async fn announce(
+    config: CoreConfig,
+    discovery_config: DiscoveryConfig,
+    message: &[u8],
+    counters: &DiscoveryCounters,
+) -> Result<(), DiscoveryError> {
+    let announce_config = discovery_config.announce;
+    let mut sockets = Vec::new();
+    for group in discovery_groups(&config, &discovery_config)? {
+        let send_socket = group.bind_send(group.port.wrapping_add(2))?;
+        if let Err(err) = group.set_ttl(&send_socket, announce_config.multicast_ttl) {
+            warn!("couldn't set multicast ttl: {}", err);
+        }
+        if let Err(err) = group.set_loop(&send_socket, announce_config.multicast_loop) {
+            warn!("couldn't set multicast loopback: {}", err);
+        }
+        sockets.push((group, send_socket));
+    }
 
-                            let current_s = s_message.clone();
-                            let config = core_config.clone();
+    for i in 0..announce_config.repeat {
+        if i > 0 {
+            let delay = jittered(announce_config.interval, announce_config.jitter);
+            if !delay.is_zero() {
+                time::sleep(delay).await;
+            }
+        }
+        for (group, send_socket) in &sockets {
+            match send_socket.send_to(message, group.target()).await {
+                Ok(_) => DiscoveryCounters::add(&counters.announces_sent),
+                Err(err) => {
+                    warn!("announce to {} failed: {}", group.addr, err);
+                    if let Some(handler) = &discovery_config.on_announce_error {
+                        handler.handle(&err);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Carries announces in and out of the discovery loop. Implemented over
+/// [`UdpSocket`], tests can swap in an in-memory one to drive the loop
+/// without a network.
+pub trait AnnounceTransport: Send + Sync {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr)
+        -> BoxFuture<'a, io::Result<usize>>;
+
+    /// Must be cancel safe, the loop drops it when something else is ready.
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8])
+        -> BoxFuture<'a, io::Result<(usize, SocketAddr)>>;
+}
+
+impl AnnounceTransport for UdpSocket {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> BoxFuture<'a, io::Result<usize>> {
+        Box::pin(UdpSocket::send_to(self, buf, target))
+    }
+
+    fn recv_from<'a>(
+        &'a self,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+        Box::pin(UdpSocket::recv_from(self, buf))
+    }
+}
+
+/// `interval` moved by a random amount up to `jitter` either way, never
+/// below zero.
+fn jittered(interval: Duration, jitter: Duration) -> Duration {
+    if jitter.is_zero() {
+        return interval;
+    }
+    let offset = rand::thread_rng().gen_range(Duration::ZERO..=jitter * 2);
+    (interval + offset).saturating_sub(jitter)
+}
+
+/// Sends `message` straight to `target` from `socket` instead of to the
+/// whole group, for answering a single peer.
+pub async fn announce_unicast(
+    socket: &dyn AnnounceTransport,
+    message: &[u8],
+    target: SocketAddr,
+    counters: &DiscoveryCounters,
+) -> io::Result<()> {
+    socket.send_to(message, target).await?;
+    DiscoveryCounters::add(&counters.announces_sent);
+    Ok(())
+}
+
+/// Receive socket on the multicast port, plus the socket replies leave from
+/// on the port above it, with the groups each joined. There's one set per
+/// address family discovery runs on.
+struct DiscoverySockets {
+    recv: Arc<dyn AnnounceTransport>,
+    /// Source of unicast replies, shared with the tasks sending them.
+    send: Arc<dyn AnnounceTransport>,
+    /// Groups to leave on close, with the socket that joined each.
+    memberships: Vec<(MulticastGroup, Arc<UdpSocket>)>,
+    /// Where announces to the group go.
+    target: Option<SocketAddr>,
+    // one spare byte tells a datagram that fills the buffer from a truncated one
+    buf: Vec<u8>,
+}
+
+impl DiscoverySockets {
+    /// Opens the set for every family, the configured group's first. Only
+    /// failing for that one is fatal, a host without v6 still discovers
+    /// over v4 in dual stack mode.
+    fn open_all(
+        config: &CoreConfig,
+        discovery_config: &DiscoveryConfig,
+    ) -> Result<Vec<Self>, DiscoveryError> {
+        let mut groups = family_groups(config, discovery_config)?.into_iter();
+        let mut all = Vec::new();
+        if let Some(group) = groups.next() {
+            all.push(Self::open(group, discovery_config)?);
+        }
+        for group in groups {
+            match Self::open(group, discovery_config) {
+                Ok(sockets) => all.push(sockets),
+                Err(err) => warn!("couldn't open udp sockets for {}: {}", group.addr, err),
+            }
+        }
+        Ok(all)
+    }
+
+    fn open(
+        group: MulticastGroup,
+        discovery_config: &DiscoveryConfig,
+    ) -> Result<Self, DiscoveryError> {
+        let multicast_port = group.port;
+        let (recv, recv_groups) = if discovery_config.all_interfaces {
+            let socket = group.any_interface().open(multicast_port)?;
+            let mut joined = Vec::new();
+            for group in interface_groups(group, discovery_config) {
+                match group.join(&socket) {
+                    Ok(()) => joined.push(group),
+                    Err(err) => warn!(
+                        "couldn't join {} on {}: {}",
+                        group.addr, group.interface, err
+                    ),
+                }
+            }
+            (socket, joined)
+        } else if discovery_config.recv_bind_any {
+            let socket = group.any_interface().open(multicast_port)?;
+            group
+                .join(&socket)
+                .map_err(DiscoveryError::JoinMulticastFailed)?;
+            (socket, vec![group])
+        } else {
+            (group.bind(multicast_port)?, vec![group])
+        };
+        let send = group.bind_send(multicast_port.wrapping_add(1))?;
+        if let Err(err) = group.set_ttl(&send, discovery_config.announce.multicast_ttl) {
+            warn!("couldn't set multicast ttl: {}", err);
+        }
+        let multicast_loop = discovery_config.announce.multicast_loop;
+        for socket in [&recv, &send] {
+            if let Err(err) = group.set_loop(socket, multicast_loop) {
+                warn!("couldn't set multicast loopback: {}", err);
+            }
+        }
+
+        let recv = Arc::new(recv);
+        let send = Arc::new(send);
+        let mut memberships = recv_groups
+            .into_iter()
+            .map(|group| (group, recv.clone()))
+            .collect::<Vec<_>>();
+        memberships.push((group, send.clone()));
+        let mut sockets = Self::over(recv, send, discovery_config);
+        sockets.memberships = memberships;
+        sockets.target = Some(group.target());
+        Ok(sockets)
+    }
+
+    /// A set on other transports than multicast sockets, which has no groups
+    /// to leave.
+    fn over(
+        recv: Arc<dyn AnnounceTransport>,
+        send: Arc<dyn AnnounceTransport>,
+        discovery_config: &DiscoveryConfig,
+    ) -> Self {
+        DiscoverySockets {
+            recv,
+            send,
+            memberships: Vec::new(),
+            target: None,
+            buf: vec![0; discovery_config.recv_buffer_size + 1],
+        }
+    }
+
+    /// Leaves every joined group before the sockets are dropped, reporting
+    /// the first failure.
+    fn close(self) -> Result<(), DiscoveryError> {
+        let mut result = Ok(());
+        for (group, socket) in &self.memberships {
+            if let Err(err) = group.leave(socket) {
+                debug!("couldn't leave {}: {}", group.addr, err);
+                if result.is_ok() {
+                    result = Err(DiscoveryError::LeaveMulticastFailed(err));
+                }
+            }
+        }
+        result
+    }
+
+    /// Closes every set, reporting the first failure.
+    fn close_all(all: Vec<Self>) -> Result<(), DiscoveryError> {
+        let mut result = Ok(());
+        for sockets in all {
+            let closed = sockets.close();
+            if result.is_ok() {
+                result = closed;
+            }
+        }
+        result
+    }
+}
+
+/// Receives into the buffer of whichever set in `sockets` has a datagram
+/// first, also returning the index of that set. Must be given at least one
+/// set.
+async fn recv_any(sockets: &mut [DiscoverySockets]) -> io::Result<(usize, SocketAddr, usize)> {
+    let receives = sockets
+        .iter_mut()
+        .map(|set| set.recv.recv_from(&mut set.buf));
+    let (result, index, _) = futures::future::select_all(receives).await;
+    result.map(|(size, addr)| (size, addr, index))
+}
 
-                            tokio::spawn(
-                                async {
-                                   announce(config, current_s).await;
-                                }
-                            );
-                        }
+/// Logs `packet` from `addr` when `log_malformed` is set, as quoted text or
+/// hex when it isn't UTF-8.
+fn log_malformed(discovery_config: &DiscoveryConfig, addr: SocketAddr, packet: &[u8]) {
+    if !discovery_config.log_malformed {
+        return;
+    }
+    match std::str::from_utf8(packet) {
+        Ok(text) => info!("malformed announce from {}: {:?}", addr, text),
+        Err(_) => info!("malformed announce from {}: {}", addr, hex_string(packet)),
+    }
+}
 
-                    },
-                    Err(_) => todo!(),
+/// Whether announces and registers from `ip` pass `allowed_subnets`, which
+/// link local sources always do in link local mode.
+pub(crate) fn source_allowed(discovery_config: &DiscoveryConfig, ip: IpAddr) -> bool {
+    let allowed = &discovery_config.allowed_subnets;
+    let link_local = discovery_config.link_local
+        && matches!(ip.to_canonical(), IpAddr::V4(ip) if ip.is_link_local());
+    allowed.is_empty() || link_local || allowed.iter().any(|net| net.contains(&ip))
+}
+
+/// Receive errors that leave the socket usable: interrupted calls, spurious
+/// wakeups and ICMP errors for earlier sends, which some platforms report
+/// on the next receive, port unreachable as a reset on windows.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+    )
+}
+
+/// Addresses of the non loopback interfaces, in a stable order for
+/// comparing.
+fn interface_snapshot() -> Vec<(u32, IpAddr)> {
+    let mut addrs = interface_addrs()
+        .into_iter()
+        .filter(|iface| !iface.loopback)
+        .map(|iface| (iface.index, iface.addr))
+        .collect::<Vec<_>>();
+    addrs.sort();
+    addrs
+}
+
+/// New interface to use when the configured one lost its address, or the
+/// current address of `DiscoveryConfig::interface_name` when that changed.
+/// Otherwise any address and v6 interfaces are left alone.
+fn replacement_interface(
+    config: &CoreConfig,
+    discovery_config: &DiscoveryConfig,
+    addrs: &[(u32, IpAddr)],
+) -> Option<Ipv4Addr> {
+    if let Some(name) = &discovery_config.interface_name {
+        // while the interface has no address we stay where we are
+        let ip = interface_ipv4_by_name(name)?;
+        return (config.interface_addr != ip.to_string()).then_some(ip);
+    }
+    let link_local = discovery_config.link_local;
+    match parse_scoped_ip(&config.interface_addr)? {
+        (IpAddr::V4(ip), _) if !ip.is_unspecified() => {
+            if addrs.iter().any(|(_, addr)| *addr == IpAddr::V4(ip)) {
+                None
+            } else {
+                default_interface_v4(link_local)
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Spots our own announces, which multicast loops back constantly unless
+/// `AnnounceConfig::multicast_loop` is off, without parsing them. We
+/// serialize them ourselves, so they contain the fingerprint field exactly
+/// as built here. `handle_announce` still checks the parsed fingerprint for
+/// anything that slips through.
+#[derive(Default)]
+struct OwnAnnounce {
+    fingerprint: String,
+    needle: Vec<u8>,
+}
+
+impl OwnAnnounce {
+    fn update(&mut self, fingerprint: &str) {
+        if self.fingerprint != fingerprint {
+            self.fingerprint = fingerprint.to_string();
+            self.needle =
+                format!("\"fingerprint\":{}", serde_json::Value::from(fingerprint)).into_bytes();
+        }
+    }
+
+    fn matches(&self, packet: &[u8]) -> bool {
+        !self.fingerprint.is_empty()
+            && packet
+                .windows(self.needle.len())
+                .any(|window| window == self.needle.as_slice())
+    }
+}
+
+async fn run_udp_actor(
+    actor: DiscoverActor,
+    shutdown_callback: watch::Sender<Option<Result<(), String>>>,
+) {
+    let result = serve(actor).await.map_err(|err| err.to_string());
+    if let Err(err) = &result {
+        error!("udp service failed: {}", err);
+    }
+
+    let _ = shutdown_callback.send(Some(result));
+}
+
+async fn serve(actor: DiscoverActor) -> Result<(), DiscoveryError> {
+    let config = actor.core.get_config().await;
+    let discovery_config = actor.core.get_discovery_config().await;
+    let group = MulticastGroup::from_config(&config)?;
+
+    if actor
+        .core
+        .device
+        .get_current_device()
+        .await
+        .fingerprint
+        .is_empty()
+    {
+        return Err(DiscoveryError::NodeNotInitialized);
+    }
+
+    let sockets = DiscoverySockets::open_all(&config, &discovery_config)?;
+    info!("udp service {} started", group.port);
+    serve_on(actor, sockets).await
+}
+
+/// The discovery loop over `sockets`, which are closed when it ends. They're
+/// rebuilt as multicast sockets when the network interfaces change.
+async fn serve_on(
+    mut actor: DiscoverActor,
+    mut sockets: Vec<DiscoverySockets>,
+) -> Result<(), DiscoveryError> {
+    let mut config = actor.core.get_config().await;
+    let discovery_config = actor.core.get_discovery_config().await;
+    let device_handle = actor.core.device.clone();
+    let max_size = discovery_config.recv_buffer_size;
+    device_handle
+        .set_max_devices(discovery_config.max_devices)
+        .await;
+
+    let node_ttl = discovery_config.node_ttl;
+    let mut sweeper = time::interval((node_ttl / 3).max(Duration::from_secs(1)));
+
+    // interfaces are only listed on unix, elsewhere no change would show
+    let interface_poll = discovery_config.interface_poll.filter(|_| cfg!(unix));
+    if interface_poll.is_none() && discovery_config.interface_poll.is_some() {
+        warn!("interface changes can't be watched on this platform");
+    }
+    let mut interface_timer = time::interval(
+        interface_poll
+            .unwrap_or(Duration::from_secs(3600))
+            .max(Duration::from_secs(1)),
+    );
+    let mut interfaces = interface_snapshot();
+    let mut own = OwnAnnounce::default();
+    let counters = actor.core.discovery_counters.clone();
+    let mut failure = None;
+
+    loop {
+        let current = device_handle.get_current_device().await;
+        own.update(&current.fingerprint);
+
+        tokio::select! {
+            // checked first so a flood of announces can't hold off a shutdown
+            biased;
+            msg = actor.receiver.recv() => match msg {
+                Some(msg) => {
+                    if actor.handle_message(msg) {
+                        debug!("shutdown by signal");
+                        break;
+                    }
+                }
+                None => {
+                    debug!("every discovery handle is gone, shutting down");
+                    break;
+                }
+            },
+            received = recv_any(&mut sockets) => {
+                let (size, addr, index) = match received {
+                    Ok(received) => received,
+                    Err(err) if is_transient(&err) => {
+                        debug!("transient receive error: {}", err);
+                        continue
+                    }
+                    Err(err) => {
+                        error!("receiving announces failed, stopping discovery: {}", err);
+                        failure = Some(DiscoveryError::RecvFailed(err));
+                        break;
+                    }
+                };
+                let buf = &sockets[index].buf;
+                if own.matches(&buf[..size]) {
+                    continue
+                }
+                if !source_allowed(&discovery_config, addr.ip()) {
+                    debug!("drop announce from {} outside the allowed subnets", addr);
+                    continue
+                }
+                DiscoveryCounters::add(&counters.packets_received);
+                if size > max_size {
+                    warn!("drop announce from {}, larger than {} bytes", addr, max_size);
+                    DiscoveryCounters::add(&counters.parse_failures);
+                    continue
+                }
+                debug!("recv msg");
+                match NodeAnnounce::decode(&buf[..size]) {
+                    Ok(node_announce) => {
+                        let span = debug_span!(
+                            "announce",
+                            fingerprint = %node_announce.fingerprint,
+                            addr = %addr,
+                        );
+                        actor
+                            .handle_announce(
+                                &config,
+                                &discovery_config,
+                                &sockets[index].send,
+                                node_announce,
+                                addr,
+                            )
+                            .instrument(span)
+                            .await;
+                    }
+                    Err(err) => {
+                        debug!("skip malformed packet from {}: {}", addr, err);
+                        log_malformed(&discovery_config, addr, &buf[..size]);
+                        DiscoveryCounters::add(&counters.parse_failures);
+                    }
+                }
+            }
+            _ = sweeper.tick() => {
+                let favorites = actor
+                    .core
+                    .favorites
+                    .get_favorites()
+                    .await
+                    .into_iter()
+                    .map(|favorite| favorite.fingerprint)
+                    .collect();
+                for device in device_handle.evict_stale_devices(node_ttl, favorites).await {
+                    debug!("node {} expired", device);
                 }
+                while actor.tasks.try_join_next().is_some() {}
+                let debounce = discovery_config.announce_debounce;
+                actor.recent.retain(|_, seen| seen.elapsed() < debounce);
             }
-            Some(_) = actor.receiver.recv() => {
-            //    let flag = actor.handle_message(msg);
-            //    if flag {
-            //     break;
-            //    }
-                debug!("shutdown by signal");
-                break;
+            _ = interface_timer.tick(), if interface_poll.is_some() => {
+                let snapshot = interface_snapshot();
+                if snapshot == interfaces {
+                    continue
+                }
+                interfaces = snapshot;
+                info!("network interfaces changed");
+
+                if let Some(ip) = replacement_interface(&config, &discovery_config, &interfaces) {
+                    info!("interface {} is gone, moving to {}", config.interface_addr, ip);
+                    config.interface_addr = ip.to_string();
+                    actor.core.change_config(config.clone()).await;
+                    device_handle.update_address(IpAddr::V4(ip)).await;
+                }
+                match DiscoverySockets::open_all(&config, &discovery_config) {
+                    Ok(rebuilt) => {
+                        // the old interface may be gone, so failing to leave is expected
+                        let _ = DiscoverySockets::close_all(std::mem::replace(&mut sockets, rebuilt));
+                    }
+                    Err(err) => {
+                        warn!("couldn't rebuild udp sockets: {}", err);
+                        continue
+                    }
+                }
+                let core = actor.core.clone();
+                actor.tasks.spawn(async move { core.announce().await });
             }
         }
     }
 
-    drop(rec_socket);
+    actor.tasks.shutdown().await;
+    actor.recent.clear();
+    let result = DiscoverySockets::close_all(sockets);
 
-    info!("udp service {} shutdown", multicast_port);
+    info!("udp service {} shutdown", config.multicast_port);
 
-    let _ = shutdown_callback.send(true);
+    match failure {
+        Some(err) => Err(err),
+        None => result,
+    }
 }
 
 impl DiscoverActor {
     pub fn new(receiver: mpsc::Receiver<DiscoverMessage>, core: CoreActorHandle) -> Self {
-        DiscoverActor { receiver, core }
+        DiscoverActor {
+            receiver,
+            core,
+            recent: HashMap::new(),
+            tasks: JoinSet::new(),
+            reannounce: Arc::default(),
+        }
     }
 
-    pub fn handle_message(&mut self, msg: DiscoverMessage) -> bool {
-        match msg {
-            DiscoverMessage::Shutdown => {
-                return true;
+    /// Records the announcing device and, when it is announcing itself rather
+    /// than answering someone, replies with our own announce. Replies go over
+    /// http register first and fall back to a unicast announce from `send`,
+    /// then to multicast; either way they carry a cleared announce flag so
+    /// the peer doesn't answer back.
+    /// Announces repeating within `announce_debounce` only refresh the device.
+    /// Runs inside the announce span, which the reply task inherits so its
+    /// register logs can be traced back to the announce that caused them.
+    async fn handle_announce(
+        &mut self,
+        config: &CoreConfig,
+        discovery_config: &DiscoveryConfig,
+        send: &Arc<dyn AnnounceTransport>,
+        node_announce: NodeAnnounce,
+        addr: SocketAddr,
+    ) {
+        let device_handle = &self.core.device;
+        let current = device_handle.get_current_device().await;
+        let device = NodeDevice::from_announce(&node_announce, &scoped_ip_string(&addr));
+
+        if current.fingerprint == device.fingerprint {
+            debug!("self loop");
+            return;
+        }
+
+        let now = Instant::now();
+        if let Some(seen) = self.recent.get(&device.fingerprint) {
+            if now.duration_since(*seen) < discovery_config.announce_debounce {
+                device_handle
+                    .touch_device_at(device.fingerprint, device.address)
+                    .await;
+                return;
             }
-            _ => {
-                return false;
+        }
+        self.recent.insert(device.fingerprint.clone(), now);
+
+        if !node_announce.is_compatible() {
+            warn!(
+                "ignore {} speaking protocol {}",
+                device, node_announce.version
+            );
+            return;
+        }
+
+        if !discovery_config.allows_peer(&device) {
+            debug!("ignore {}, rejected by the peer filter", device);
+            return;
+        }
+
+        if device_handle
+            .check_device_exist(device.fingerprint.clone())
+            .await
+        {
+            device_handle
+                .touch_device_at(device.fingerprint.clone(), device.address.clone())
+                .await;
+        } else {
+            debug!("node {} ({})", device, device.short_fingerprint());
+            device_handle.add_node_device(device.clone()).await;
+        }
+        self.core.favorites.refresh(device.clone()).await;
+
+        if !node_announce.is_announcement() {
+            debug!("announce reply from {}", device);
+            return;
+        }
+        if discovery_config.observe_only {
+            return;
+        }
+
+        let reply = current.to_reply();
+        let config = config.clone();
+        let discovery_config = discovery_config.clone();
+        let counters = self.core.discovery_counters.clone();
+        let clients = self.core.register_clients.clone();
+        let device_handle = device_handle.clone();
+        let send = send.clone();
+        let reannounce = self.reannounce.clone();
+        // peers listen for announces on the multicast port, not the one
+        // this announce was sent from
+        let mut target = addr;
+        target.set_port(config.multicast_port);
+        self.tasks.spawn(
+            async move {
+                if let Some(answer) =
+                    register(reply, device, &discovery_config, &counters, &clients).await
+                {
+                    device_handle.add_node_device(answer).await;
+                    return;
+                }
+                let message = device_handle
+                    .get_current_reply(discovery_config.wire_format)
+                    .await;
+                match announce_unicast(send.as_ref(), &message, target, &counters).await {
+                    Ok(()) => return,
+                    Err(err) => debug!("unicast announce to {} failed: {}", target, err),
+                }
+                match reannounce.schedule(discovery_config.reannounce_interval) {
+                    Some(delay) if !delay.is_zero() => time::sleep(delay).await,
+                    Some(_) => {}
+                    None => {
+                        debug!("re-announce coalesced with a pending one");
+                        return;
+                    }
+                }
+                if let Err(err) = announce(config, discovery_config, &message, &counters).await {
+                    warn!("announce failed: {}", err);
+                }
             }
+            .instrument(Span::current()),
+        );
+    }
+
+    pub fn handle_message(&mut self, msg: DiscoverMessage) -> bool {
+        match msg {
+            DiscoverMessage::Shutdown => true,
         }
     }
 }
@@ -186,13 +1615,13 @@ impl DiscoverActor {
 #[derive(Clone)]
 pub struct DiscoverHandle {
     sender: mpsc::Sender<DiscoverMessage>,
-    shutdown_receiver: watch::Receiver<bool>,
+    shutdown_receiver: watch::Receiver<Option<Result<(), String>>>,
 }
 
 impl DiscoverHandle {
     pub fn new(core: CoreActorHandle) -> Self {
         let (sender, receiver) = mpsc::channel(8);
-        let (s_sender, s_receiver) = watch::channel(true);
+        let (s_sender, s_receiver) = watch::channel(None);
 
         let actor = DiscoverActor::new(receiver, core);
 
@@ -203,16 +1632,299 @@ impl DiscoverHandle {
         }
     }
 
-    pub async fn shutdown(mut self) {
+    /// Resolves when the service stops, whether through `shutdown` or
+    /// because it failed.
+    pub async fn stopped(&self) -> Result<(), String> {
+        let mut receiver = self.shutdown_receiver.clone();
+        let result = receiver
+            .wait_for(Option::is_some)
+            .await
+            .expect("Actor task has been killed");
+        result.clone().unwrap()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.shutdown_receiver.borrow().is_none()
+    }
+
+    /// Stops the service once it has left the multicast groups and closed
+    /// its sockets. An error means teardown, or the service itself,
+    /// failed.
+    pub async fn shutdown(self) -> Result<(), String> {
         let msg = DiscoverMessage::Shutdown;
 
-        // Ignore send errors. If this send fails, so does the
-        // recv.await below. There's no reason to check the
-        // failure twice.
+        // Ignore send errors. If this send fails, the service already
+        // stopped and left its result behind.
         let _ = self.sender.send(msg).await;
-        self.shutdown_receiver
-            .changed()
+        self.stopped().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::post, Router};
+    use tokio::{sync::mpsc::UnboundedReceiver, task::JoinHandle};
+
+    use super::*;
+    use crate::actor::core::RegisterConfig;
+    use crate::actor::model::{NodeBuilder, WireFormat};
+
+    type Datagram = (Vec<u8>, SocketAddr);
+
+    /// Receives what's pushed into `inbox` and hands what's sent to
+    /// `outbox`, standing in for both sockets of a set.
+    struct MemoryTransport {
+        inbox: tokio::sync::Mutex<UnboundedReceiver<Datagram>>,
+        outbox: mpsc::UnboundedSender<Datagram>,
+    }
+
+    impl AnnounceTransport for MemoryTransport {
+        fn send_to<'a>(
+            &'a self,
+            buf: &'a [u8],
+            target: SocketAddr,
+        ) -> BoxFuture<'a, io::Result<usize>> {
+            let sent = self.outbox.send((buf.to_vec(), target));
+            Box::pin(async move {
+                sent.map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?;
+                Ok(buf.len())
+            })
+        }
+
+        fn recv_from<'a>(
+            &'a self,
+            buf: &'a mut [u8],
+        ) -> BoxFuture<'a, io::Result<(usize, SocketAddr)>> {
+            Box::pin(async move {
+                let (packet, from) = self
+                    .inbox
+                    .lock()
+                    .await
+                    .recv()
+                    .await
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+                // like udp, what doesn't fit the buffer is cut off
+                let size = packet.len().min(buf.len());
+                buf[..size].copy_from_slice(&packet[..size]);
+                Ok((size, from))
+            })
+        }
+    }
+
+    /// The discovery loop running over a `MemoryTransport`.
+    struct Harness {
+        core: CoreActorHandle,
+        current: NodeDevice,
+        inbox: mpsc::UnboundedSender<Datagram>,
+        outbox: UnboundedReceiver<Datagram>,
+        shutdown: mpsc::Sender<DiscoverMessage>,
+        serving: JoinHandle<Result<(), DiscoveryError>>,
+    }
+
+    impl Harness {
+        async fn start(discovery_config: DiscoveryConfig) -> Harness {
+            let current = NodeBuilder::new()
+                .alias("me")
+                .address("127.0.0.1")
+                .port(closed_port())
+                .build()
+                .unwrap();
+            let core = CoreActorHandle::new(current.clone(), CoreConfig::default());
+            // a register that fails falls back to unicast right away
+            core.change_discovery_config(DiscoveryConfig {
+                interface_poll: None,
+                register: RegisterConfig {
+                    attempts: 1,
+                    ..RegisterConfig::default()
+                },
+                ..discovery_config
+            })
+            .await;
+
+            let (inbox, inbox_recv) = mpsc::unbounded_channel();
+            let (outbox_send, outbox) = mpsc::unbounded_channel();
+            let transport: Arc<dyn AnnounceTransport> = Arc::new(MemoryTransport {
+                inbox: tokio::sync::Mutex::new(inbox_recv),
+                outbox: outbox_send,
+            });
+            let sockets = DiscoverySockets::over(
+                transport.clone(),
+                transport,
+                &core.get_discovery_config().await,
+            );
+            let (shutdown, receiver) = mpsc::channel(8);
+            let actor = DiscoverActor::new(receiver, core.clone());
+            let serving = tokio::spawn(serve_on(actor, vec![sockets]));
+            Harness {
+                core,
+                current,
+                inbox,
+                outbox,
+                shutdown,
+                serving,
+            }
+        }
+
+        fn deliver(&self, announce: &NodeAnnounce, format: WireFormat, from: SocketAddr) {
+            self.inbox.send((announce.encode(format), from)).unwrap();
+        }
+
+        async fn next_sent(&mut self) -> Datagram {
+            time::timeout(Duration::from_secs(10), self.outbox.recv())
+                .await
+                .expect("nothing was sent")
+                .unwrap()
+        }
+
+        /// Waits for the device list to satisfy `done`, returning it.
+        async fn devices_until<F>(&self, done: F) -> HashMap<String, NodeDevice>
+        where
+            F: Fn(&HashMap<String, NodeDevice>) -> bool,
+        {
+            let waiting = async {
+                loop {
+                    let devices = self.core.device.get_device_map().await;
+                    if done(&devices) {
+                        return devices;
+                    }
+                    time::sleep(Duration::from_millis(20)).await;
+                }
+            };
+            time::timeout(Duration::from_secs(10), waiting)
+                .await
+                .expect("device list never got there")
+        }
+
+        async fn stop(self) {
+            self.shutdown.send(DiscoverMessage::Shutdown).await.unwrap();
+            self.serving.await.unwrap().unwrap();
+        }
+    }
+
+    fn peer(alias: &str, port: u16, announce: bool) -> NodeAnnounce {
+        NodeAnnounce {
+            announce,
+            ..NodeBuilder::new()
+                .alias(alias)
+                .port(port)
+                .protocol("http")
+                .build()
+                .unwrap()
+                .to_announce()
+        }
+    }
+
+    /// A port nothing listens on, it was just freed.
+    fn closed_port() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    fn source(ip: [u8; 4]) -> SocketAddr {
+        SocketAddr::from((ip, 53317))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ignores_own_announces() {
+        let mut harness = Harness::start(DiscoveryConfig::default()).await;
+        let own = NodeAnnounce {
+            announce: true,
+            ..harness.current.to_announce()
+        };
+        // JSON is caught before parsing, CBOR only once parsed
+        harness.deliver(&own, WireFormat::Json, source([127, 0, 0, 1]));
+        harness.deliver(&own, WireFormat::Cbor, source([127, 0, 0, 1]));
+        let other = peer("other", closed_port(), true);
+        harness.deliver(&other, WireFormat::Json, source([127, 0, 0, 2]));
+
+        // answering ourselves would send a reply to 127.0.0.1 around this one
+        let (_, target) = harness.next_sent().await;
+        assert_eq!(target, source([127, 0, 0, 2]));
+        time::sleep(Duration::from_millis(500)).await;
+        assert!(harness.outbox.try_recv().is_err());
+        let devices = harness.core.device.get_device_map().await;
+        assert_eq!(devices.len(), 1);
+        assert!(devices.contains_key(&other.fingerprint));
+        harness.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn registers_with_announcing_peers() {
+        let (registered, mut registers) = mpsc::unbounded_channel();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let answer = NodeAnnounce {
+            alias: "answered".to_string(),
+            ..peer("announced", port, false)
+        };
+        let body = serde_json::to_string(&answer).unwrap();
+        let app = Router::new().route(
+            &format!("{}/register", API_PATH),
+            post(move |request: String| async move {
+                let _ = registered.send(request);
+                body
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let harness = Harness::start(DiscoveryConfig::default()).await;
+        let announce = NodeAnnounce {
+            fingerprint: answer.fingerprint.clone(),
+            ..peer("announced", port, true)
+        };
+        harness.deliver(&announce, WireFormat::Json, source([127, 0, 0, 1]));
+
+        let request = time::timeout(Duration::from_secs(10), registers.recv())
             .await
-            .expect("Actor task has been killed")
+            .unwrap()
+            .unwrap();
+        let request: NodeAnnounce = serde_json::from_str(&request).unwrap();
+        assert_eq!(request.fingerprint, harness.current.fingerprint);
+        assert!(!request.is_announcement());
+
+        let devices = harness
+            .devices_until(|devices| {
+                devices
+                    .get(&answer.fingerprint)
+                    .is_some_and(|device| device.alias == "answered")
+            })
+            .await;
+        assert_eq!(devices[&answer.fingerprint].address, "127.0.0.1");
+        harness.stop().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn answers_unregistrable_peers_by_unicast() {
+        let mut harness = Harness::start(DiscoveryConfig::default()).await;
+        harness.deliver(
+            &peer("unreachable", closed_port(), true),
+            WireFormat::Json,
+            SocketAddr::from(([127, 0, 0, 1], 40000)),
+        );
+
+        let (packet, target) = harness.next_sent().await;
+        let reply = NodeAnnounce::decode(&packet).unwrap();
+        assert_eq!(reply.fingerprint, harness.current.fingerprint);
+        assert!(!reply.is_announcement());
+        // sent to the multicast port, not the one the announce came from
+        assert_eq!(target, source([127, 0, 0, 1]));
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn evicts_devices_past_their_ttl() {
+        let harness = Harness::start(DiscoveryConfig {
+            node_ttl: Duration::from_millis(500),
+            ..DiscoveryConfig::default()
+        })
+        .await;
+        let stale = peer("stale", 1, false);
+        harness.deliver(&stale, WireFormat::Json, source([10, 0, 0, 2]));
+        harness
+            .devices_until(|devices| devices.contains_key(&stale.fingerprint))
+            .await;
+
+        harness.devices_until(HashMap::is_empty).await;
+        harness.stop().await;
     }
 }
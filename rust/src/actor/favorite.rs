@@ -0,0 +1,280 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot, watch};
+
+use super::model::NodeDevice;
+
+/// A device the user pinned, with where it was last seen. Missing fields
+/// take their defaults and unknown ones are ignored, so files written by
+/// other versions still load.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Favorite {
+    pub fingerprint: String,
+    pub alias: String,
+    pub address: String,
+    pub port: u16,
+    pub protocol: String,
+}
+
+impl Favorite {
+    pub fn from_device(device: &NodeDevice) -> Self {
+        Favorite {
+            fingerprint: device.fingerprint.clone(),
+            alias: device.alias.clone(),
+            address: device.address.clone(),
+            port: device.port,
+            protocol: device.protocol.clone(),
+        }
+    }
+
+    /// The device as far as we know it without having heard from it.
+    pub fn to_device(&self) -> NodeDevice {
+        NodeDevice {
+            alias: self.alias.clone(),
+            fingerprint: self.fingerprint.clone(),
+            address: self.address.clone(),
+            port: self.port,
+            protocol: self.protocol.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.to_device().socket_addr()
+    }
+}
+
+struct FavoriteActor {
+    receiver: mpsc::Receiver<FavoriteMessage>,
+    path: Option<PathBuf>,
+    favorites: HashMap<String, Favorite>,
+    fingerprints: watch::Sender<HashSet<String>>,
+}
+
+enum FavoriteMessage {
+    Open {
+        path: PathBuf,
+        respond_to: oneshot::Sender<Result<usize, String>>,
+    },
+    Add {
+        favorite: Favorite,
+        respond_to: oneshot::Sender<Result<(), String>>,
+    },
+    Remove {
+        fingerprint: String,
+        respond_to: oneshot::Sender<Result<bool, String>>,
+    },
+    Refresh {
+        device: NodeDevice,
+    },
+    GetAll {
+        respond_to: oneshot::Sender<Vec<Favorite>>,
+    },
+}
+
+async fn load(path: &Path) -> Result<Vec<Favorite>, String> {
+    match tokio::fs::read(path).await {
+        Ok(data) => serde_json::from_slice(&data).map_err(|err| err.to_string()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+impl FavoriteActor {
+    fn new(
+        receiver: mpsc::Receiver<FavoriteMessage>,
+        fingerprints: watch::Sender<HashSet<String>>,
+    ) -> Self {
+        FavoriteActor {
+            receiver,
+            path: None,
+            favorites: HashMap::new(),
+            fingerprints,
+        }
+    }
+
+    fn publish(&self) {
+        let fingerprints = self.favorites.keys().cloned().collect();
+        self.fingerprints.send_replace(fingerprints);
+    }
+
+    /// Writes every favorite to the file, through a temporary file so a
+    /// crash can't leave it half written.
+    async fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut favorites = self.favorites.values().collect::<Vec<_>>();
+        favorites.sort_by(|a, b| a.fingerprint.cmp(&b.fingerprint));
+        let data = serde_json::to_vec_pretty(&favorites).map_err(|err| err.to_string())?;
+
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, data)
+            .await
+            .map_err(|err| err.to_string())?;
+        tokio::fs::rename(&tmp, path)
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    async fn handle_message(&mut self, msg: FavoriteMessage) {
+        match msg {
+            FavoriteMessage::Open { path, respond_to } => {
+                let result = load(&path).await.map(|favorites| {
+                    self.favorites = favorites
+                        .into_iter()
+                        .filter(|favorite| !favorite.fingerprint.is_empty())
+                        .map(|favorite| (favorite.fingerprint.clone(), favorite))
+                        .collect();
+                    self.path = Some(path);
+                    self.favorites.len()
+                });
+                self.publish();
+                let _ = respond_to.send(result);
+            }
+            FavoriteMessage::Add {
+                favorite,
+                respond_to,
+            } => {
+                self.favorites
+                    .insert(favorite.fingerprint.clone(), favorite);
+                self.publish();
+                let _ = respond_to.send(self.save().await);
+            }
+            FavoriteMessage::Remove {
+                fingerprint,
+                respond_to,
+            } => {
+                let result = match self.favorites.remove(&fingerprint) {
+                    Some(_) => {
+                        self.publish();
+                        self.save().await.map(|_| true)
+                    }
+                    None => Ok(false),
+                };
+                let _ = respond_to.send(result);
+            }
+            FavoriteMessage::Refresh { device } => {
+                let Some(favorite) = self.favorites.get_mut(&device.fingerprint) else {
+                    return;
+                };
+                let seen = Favorite::from_device(&device);
+                if *favorite == seen {
+                    return;
+                }
+                debug!("favorite moved to {}", device);
+                *favorite = seen;
+                if let Err(err) = self.save().await {
+                    warn!("failed to save favorites: {}", err);
+                }
+            }
+            FavoriteMessage::GetAll { respond_to } => {
+                let _ = respond_to.send(self.favorites.values().cloned().collect());
+            }
+        }
+    }
+}
+
+async fn run_favorite_actor(mut actor: FavoriteActor) {
+    while let Some(msg) = actor.receiver.recv().await {
+        actor.handle_message(msg).await;
+    }
+}
+
+/// Favorite devices, kept apart from the discovered ones and persisted to a
+/// json file once `open` is called.
+#[derive(Clone)]
+pub struct FavoriteHandle {
+    sender: mpsc::Sender<FavoriteMessage>,
+    fingerprints: watch::Receiver<HashSet<String>>,
+}
+
+impl Default for FavoriteHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FavoriteHandle {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel(8);
+        let (fingerprints_tx, fingerprints) = watch::channel(HashSet::new());
+        let actor = FavoriteActor::new(receiver, fingerprints_tx);
+        tokio::spawn(run_favorite_actor(actor));
+
+        Self {
+            sender,
+            fingerprints,
+        }
+    }
+
+    /// The fingerprints of the favorites, updated whenever they change.
+    pub fn watch_fingerprints(&self) -> watch::Receiver<HashSet<String>> {
+        self.fingerprints.clone()
+    }
+
+    /// Loads the favorites stored at `path`, replacing the current ones, and
+    /// saves further changes there. A missing file is an empty list.
+    pub async fn open(&self, path: impl Into<PathBuf>) -> Result<usize, String> {
+        let (send, recv) = oneshot::channel();
+        let msg = FavoriteMessage::Open {
+            path: path.into(),
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    pub async fn add(&self, device: &NodeDevice) -> Result<(), String> {
+        let (send, recv) = oneshot::channel();
+        let msg = FavoriteMessage::Add {
+            favorite: Favorite::from_device(device),
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Returns whether `fingerprint` was a favorite.
+    pub async fn remove(&self, fingerprint: String) -> Result<bool, String> {
+        let (send, recv) = oneshot::channel();
+        let msg = FavoriteMessage::Remove {
+            fingerprint,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Updates the last known address of a favorite that showed up again.
+    /// Other devices are ignored.
+    pub async fn refresh(&self, device: NodeDevice) {
+        let msg = FavoriteMessage::Refresh { device };
+        let _ = self.sender.send(msg).await;
+    }
+
+    pub async fn get_favorites(&self) -> Vec<Favorite> {
+        let (send, recv) = oneshot::channel();
+        let msg = FavoriteMessage::GetAll { respond_to: send };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    pub async fn is_favorite(&self, fingerprint: &str) -> bool {
+        self.get_favorites()
+            .await
+            .iter()
+            .any(|favorite| favorite.fingerprint == fingerprint)
+    }
+}
@@ -1,11 +1,18 @@
-use std::net::SocketAddr;
-
-use axum::Router;
-use log::info;
-
-use tokio::sync::{mpsc, watch};
-
-use crate::api::v2;
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::{extract::ConnectInfo, http::Request, Router};
+use hyper::{body::Incoming, server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use log::{debug, error, info, warn};
+use rustls::ServerConfig;
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, watch},
+    time,
+};
+use tower::ServiceExt;
+
+use crate::{api::v2, tls::stream::accept};
 
 use super::{core::CoreActorHandle, discovery::DiscoverHandle};
 
@@ -20,18 +27,16 @@ struct HttpServerActor {
 
 async fn shutdown_signal(mut actor: HttpServerActor) {
     while let Some(msg) = actor.receiver.recv().await {
-        if matches!(msg, ServerMessage::Shutdown) {
+        if actor.handle_message(msg) {
             break;
-        };
-        actor.handle_message(msg).await;
+        }
     }
 }
 
 async fn run_http_actor(actor: HttpServerActor, shutdown_callback: watch::Sender<bool>) {
     let config = actor.core.get_config().await;
-    let n_port = config.port;
-
-    let discover_handle = DiscoverHandle::new(actor.core.clone());
+    let receive = actor.core.get_receive_config().await;
+    let n_port = receive.bind_port.unwrap_or(config.port);
 
     let addr = SocketAddr::from(([0, 0, 0, 0], n_port));
     let listener = tokio::net::TcpListener::bind(addr)
@@ -40,28 +45,114 @@ async fn run_http_actor(actor: HttpServerActor, shutdown_callback: watch::Sender
 
     let app = Router::new().nest("/api/localsend/", v2::app(actor.core.clone()));
 
-    info!("http service {} started", n_port);
+    // settled before discovery starts, which announces our protocol
+    let mut current = actor.core.device.get_current_device().await;
+    let tls = if current.protocol == "https" {
+        match actor
+            .core
+            .tls_identity()
+            .await
+            .and_then(|identity| identity.server_config())
+        {
+            Ok(config) => Some(config),
+            Err(err) => {
+                error!("https unavailable, serving and announcing http: {}", err);
+                current.protocol = "http".to_string();
+                actor.core.device.set_current_device(current).await;
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let discover_handle = DiscoverHandle::new(actor.core.clone());
 
-    axum::serve(listener, app)
+    info!(
+        "{} service {} started",
+        if tls.is_some() { "https" } else { "http" },
+        n_port
+    );
+
+    match tls {
+        Some(tls) => serve_tls(listener, app, tls, shutdown_signal(actor)).await,
+        None => axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
         .with_graceful_shutdown(shutdown_signal(actor))
         .await
-        .unwrap();
+        .unwrap(),
+    }
 
     info!("http service {} shutdown", n_port);
 
-    discover_handle.shutdown().await;
+    if let Err(err) = discover_handle.shutdown().await {
+        warn!("udp service didn't stop cleanly: {}", err);
+    }
 
     let _ = shutdown_callback.send(true);
 }
 
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts connections until `signal` resolves, terminating TLS and handing
+/// each connection to `app` with its peer address attached.
+async fn serve_tls(
+    listener: TcpListener,
+    app: Router,
+    config: Arc<ServerConfig>,
+    signal: impl Future<Output = ()>,
+) {
+    futures::pin_mut!(signal);
+    loop {
+        let (stream, remote) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!("accept failed: {}", err);
+                    continue;
+                }
+            },
+            _ = &mut signal => break,
+        };
+
+        let app = app.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            let stream = match time::timeout(TLS_HANDSHAKE_TIMEOUT, accept(stream, config)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(err)) => {
+                    debug!("tls handshake with {} failed: {}", remote, err);
+                    return;
+                }
+                Err(_) => {
+                    debug!("tls handshake with {} timed out", remote);
+                    return;
+                }
+            };
+
+            let service = service_fn(move |mut request: Request<Incoming>| {
+                request.extensions_mut().insert(ConnectInfo(remote));
+                app.clone().oneshot(request)
+            });
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("connection with {} closed: {}", remote, err);
+            }
+        });
+    }
+}
+
 impl HttpServerActor {
     pub fn new(receiver: mpsc::Receiver<ServerMessage>, core: CoreActorHandle) -> Self {
         HttpServerActor { receiver, core }
     }
-    async fn handle_message(&mut self, msg: ServerMessage) {
+    fn handle_message(&mut self, msg: ServerMessage) -> bool {
         match msg {
-            ServerMessage::Shutdown => todo!(),
-            _ => {}
+            ServerMessage::Shutdown => true,
         }
     }
 }
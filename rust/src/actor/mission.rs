@@ -1,6 +1,19 @@
-use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
-use crate::api::model::FileInfo;
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use tokio::sync::watch;
+
+use crate::api::model::{FileInfo, FileRequest};
 
 use super::model::{MissionState, NodeDevice};
 
@@ -8,10 +21,6 @@ pub mod notify;
 pub mod pending;
 pub mod transfer;
 
-lazy_static! {
-    pub static ref MISSION_NOTIFY: notify::Handle = notify::Handle::new();
-}
-
 #[derive(Clone)]
 pub struct MissionInfo {
     pub id: String,
@@ -35,17 +44,186 @@ pub enum FileState {
     Fail { msg: String },
 }
 
+/// What to do with an incoming transfer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransferDecision {
+    AcceptAll,
+    /// Accept only the files with these ids.
+    AcceptSome(Vec<String>),
+    /// Accept into `dir` instead of the store path, every file or only those
+    /// with the ids in `files`. The directory is created when missing.
+    AcceptInto {
+        dir: PathBuf,
+        files: Option<Vec<String>>,
+    },
+    Reject,
+}
+
+type Decide =
+    dyn Fn(&FileRequest, &NodeDevice) -> BoxFuture<'static, TransferDecision> + Send + Sync;
+
+/// Decides on incoming transfers instead of waiting for the user to accept
+/// them, given the request and the registered device sending it.
+#[derive(Clone)]
+pub struct TransferPolicy(Arc<Decide>);
+
+impl TransferPolicy {
+    pub fn new<F, Fut>(decide: F) -> Self
+    where
+        F: Fn(&FileRequest, &NodeDevice) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = TransferDecision> + Send + 'static,
+    {
+        TransferPolicy(Arc::new(
+            move |request: &FileRequest, device: &NodeDevice| {
+                Box::pin(decide(request, device)) as BoxFuture<'static, TransferDecision>
+            },
+        ))
+    }
+
+    /// Accepts everything, for headless and server use.
+    pub fn auto_accept() -> Self {
+        TransferPolicy::new(|_, _| async { TransferDecision::AcceptAll })
+    }
+
+    pub async fn decide(&self, request: &FileRequest, device: &NodeDevice) -> TransferDecision {
+        (self.0)(request, device).await
+    }
+}
+
+impl fmt::Debug for TransferPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TransferPolicy")
+    }
+}
+
+/// Counts the sessions between prepare-upload and their end, so the number
+/// running at once can be capped.
+#[derive(Clone, Default)]
+pub struct SessionLimit {
+    active: Arc<AtomicUsize>,
+}
+
+/// A slot taken from a [`SessionLimit`], given back when dropped. It travels
+/// with its mission through the pending and transfer actors.
+#[derive(Debug)]
+pub struct SessionPermit {
+    active: Arc<AtomicUsize>,
+}
+
+impl SessionLimit {
+    /// Takes a slot unless `max` sessions are active already. `None` means
+    /// no limit.
+    pub fn try_acquire(&self, max: Option<usize>) -> Option<SessionPermit> {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| match max {
+                Some(max) if active >= max => None,
+                _ => Some(active + 1),
+            })
+            .ok()?;
+        Some(SessionPermit {
+            active: self.active.clone(),
+        })
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Sessions we're sending to a peer, by the session id the peer gave them,
+/// so they can be canceled like incoming ones.
+#[derive(Clone, Default)]
+pub struct OutgoingSessions {
+    sessions: Arc<Mutex<HashMap<String, watch::Sender<bool>>>>,
+}
+
+/// An outgoing session registered with [`OutgoingSessions`], unregistered
+/// when dropped. `canceled` flips to true when it's canceled.
+pub struct OutgoingSession {
+    id: String,
+    sessions: Arc<Mutex<HashMap<String, watch::Sender<bool>>>>,
+    pub canceled: watch::Receiver<bool>,
+}
+
+impl OutgoingSessions {
+    pub fn register(&self, id: String) -> OutgoingSession {
+        let (tx, canceled) = watch::channel(false);
+        self.sessions.lock().insert(id.clone(), tx);
+        OutgoingSession {
+            id,
+            sessions: self.sessions.clone(),
+            canceled,
+        }
+    }
+
+    /// Returns whether `id` was being sent.
+    pub fn cancel(&self, id: &str) -> bool {
+        match self.sessions.lock().get(id) {
+            Some(canceled) => {
+                canceled.send_replace(true);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for OutgoingSession {
+    fn drop(&mut self) {
+        self.sessions.lock().remove(&self.id);
+    }
+}
+
 #[derive(Clone)]
 pub struct MissionHandle {
+    pub notify: notify::Handle,
     pub pending: pending::Handle,
     pub transfer: transfer::Handle,
+    pub sessions: SessionLimit,
+    pub outgoing: OutgoingSessions,
+}
+
+impl Default for MissionHandle {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MissionHandle {
     pub fn new() -> Self {
-        let transfer = transfer::Handle::new();
-        let pending = pending::Handle::new(transfer.clone());
+        let notify = notify::Handle::new();
+        let transfer = transfer::Handle::new(notify.clone());
+        let pending = pending::Handle::new(transfer.clone(), notify.clone());
+
+        Self {
+            notify,
+            pending,
+            transfer,
+            sessions: SessionLimit::default(),
+            outgoing: OutgoingSessions::default(),
+        }
+    }
+
+    /// Sessions currently waiting for acceptance or being transferred.
+    pub fn active_sessions(&self) -> usize {
+        self.sessions.active()
+    }
 
-        Self { pending, transfer }
+    /// Cancels the session whether it is still waiting for acceptance or
+    /// being transferred. Running uploads stop and remove their partial
+    /// files. Sessions we're sending stop uploading and the peer is told
+    /// to cancel too. Unknown ids are ignored.
+    pub async fn cancel_session(&self, session_id: String) {
+        if self.outgoing.cancel(&session_id) {
+            return;
+        }
+        self.pending.cancel(session_id.clone()).await;
+        self.transfer.cancel(session_id).await;
     }
 }
@@ -1,24 +1,18 @@
-use log::{debug, trace};
+use log::trace;
 use tokio::sync::{mpsc, oneshot, watch};
 
-use crate::{
-    actor::{
-        mission::MISSION_NOTIFY,
-        model::{Mission, MissionState, NodeDevice},
-    },
-    api::model::FileInfo,
-};
-
 use super::MissionInfo;
 
 enum Message {
     Notify {
-        mission: Option<MissionInfo>,
+        mission: Box<Option<MissionInfo>>,
     },
     Listen {
         respond_to: oneshot::Sender<watch::Receiver<Option<MissionInfo>>>,
     },
-    Clear,
+    Clear {
+        respond_to: oneshot::Sender<()>,
+    },
 }
 
 struct Actor {
@@ -41,14 +35,15 @@ impl Actor {
         match msg {
             Message::Notify { mission } => {
                 trace!("mission changed");
-                let _ = self.notify.send(mission);
+                let _ = self.notify.send(*mission);
             }
             Message::Listen { respond_to } => {
                 let rx = self.listener.clone();
                 let _ = respond_to.send(rx);
             }
-            Message::Clear => {
+            Message::Clear { respond_to } => {
                 let _ = self.notify.send(None);
+                let _ = respond_to.send(());
             }
         }
     }
@@ -65,6 +60,12 @@ pub struct Handle {
     sender: mpsc::Sender<Message>,
 }
 
+impl Default for Handle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Handle {
     pub fn new() -> Self {
         let (sender, receiver) = mpsc::channel(8);
@@ -83,12 +84,14 @@ impl Handle {
     }
 
     pub async fn notify(&self, mission: Option<MissionInfo>) {
-        let msg = Message::Notify { mission };
+        let msg = Message::Notify {
+            mission: Box::new(mission),
+        };
         let _ = self.sender.send(msg).await;
     }
     pub async fn clear(&self) {
         let (send, recv) = oneshot::channel();
-        let msg = Message::Clear;
+        let msg = Message::Clear { respond_to: send };
 
         let _ = self.sender.send(msg).await;
         recv.await.expect("Actor task has been killed")
@@ -1,13 +1,16 @@
+use std::collections::HashMap;
+
 use log::debug;
 use tokio::sync::{mpsc, oneshot, watch};
 
 use crate::actor::model::{Mission, MissionState};
 
-use super::{transfer, FileState, MissionFileInfo, MissionInfo, MISSION_NOTIFY};
+use super::{notify, transfer, FileState, MissionFileInfo, MissionInfo, SessionPermit};
 
 enum Message {
     Add {
-        mission: Mission,
+        mission: Box<Mission>,
+        permit: SessionPermit,
         respond_to: oneshot::Sender<watch::Receiver<MissionState>>,
     },
     Cancel {
@@ -23,6 +26,7 @@ enum Message {
 pub struct PendingMission {
     pub mission: Mission,
     pub notify: watch::Sender<MissionState>,
+    pub permit: SessionPermit,
 }
 
 impl MissionInfo {
@@ -43,38 +47,48 @@ impl MissionInfo {
     }
 }
 
+/// Missions waiting for a decision, by id. How many may wait at once is
+/// up to the `SessionPermit` each holds.
 struct MissionStore {
-    mission: Option<PendingMission>,
+    missions: HashMap<String, PendingMission>,
 }
 
 struct Actor {
     transfer: transfer::Handle,
+    notify: notify::Handle,
     receiver: mpsc::Receiver<Message>,
     store: MissionStore,
 }
 
 impl Actor {
-    fn new(receiver: mpsc::Receiver<Message>, transfer: transfer::Handle) -> Self {
+    fn new(
+        receiver: mpsc::Receiver<Message>,
+        transfer: transfer::Handle,
+        notify: notify::Handle,
+    ) -> Self {
         let store: MissionStore = MissionStore {
-            mission: Option::None,
+            missions: HashMap::new(),
         };
         Actor {
             receiver,
             store,
             transfer,
+            notify,
         }
     }
     async fn handle_message(&mut self, msg: Message) {
         match msg {
             Message::Add {
                 mission,
+                permit,
                 respond_to,
             } => {
+                let mission = *mission;
                 debug!("mission added pending: {:?}", mission);
 
                 let (tx, rx) = watch::channel(MissionState::Pending);
 
-                if self.store.mission.is_some() {
+                if self.store.missions.contains_key(&mission.id) {
                     let _ = respond_to.send(rx);
                     let _ = tx.send(MissionState::Busy);
                     return;
@@ -83,40 +97,31 @@ impl Actor {
                 let pending_mission = PendingMission {
                     mission: mission.clone(),
                     notify: tx,
+                    permit,
                 };
 
-                self.store.mission.replace(pending_mission);
-                MISSION_NOTIFY
+                self.store
+                    .missions
+                    .insert(mission.id.clone(), pending_mission);
+                self.notify
                     .notify(Some(MissionInfo::from_mission(mission)))
                     .await;
                 let _ = respond_to.send(rx);
             }
             Message::Cancel { id, respond_to } => {
-                match &self.store.mission {
-                    Some(mission) => {
-                        if mission.mission.id == id {
-                            let mission = self.store.mission.take().unwrap();
-                            let _ = mission.notify.send(MissionState::Canceled);
-                            MISSION_NOTIFY
-                                .notify(Some(MissionInfo::from_mission(mission.mission)))
-                                .await;
-                        }
-                    }
-                    None => {}
+                if let Some(mission) = self.store.missions.remove(&id) {
+                    let _ = mission.notify.send(MissionState::Canceled);
+                    self.notify
+                        .notify(Some(MissionInfo::from_mission(mission.mission)))
+                        .await;
                 }
 
                 let _ = respond_to.send(());
             }
             Message::Accept { id, respond_to } => {
-                match &self.store.mission {
-                    Some(mission) => {
-                        if mission.mission.id == id {
-                            let mission = self.store.mission.take().unwrap();
-                            let _ = mission.notify.send(MissionState::Transfering);
-                            let _ = self.transfer.add(mission.mission).await;
-                        }
-                    }
-                    None => {}
+                if let Some(mission) = self.store.missions.remove(&id) {
+                    let _ = mission.notify.send(MissionState::Transfering);
+                    let _ = self.transfer.add(mission.mission, mission.permit).await;
                 }
 
                 let _ = respond_to.send(());
@@ -137,17 +142,22 @@ pub struct Handle {
 }
 
 impl Handle {
-    pub fn new(transfer: transfer::Handle) -> Self {
+    pub fn new(transfer: transfer::Handle, notify: notify::Handle) -> Self {
         let (sender, receiver) = mpsc::channel(8);
-        let actor = Actor::new(receiver, transfer);
+        let actor = Actor::new(receiver, transfer, notify);
         tokio::spawn(run_mission_actor(actor));
 
         Self { sender }
     }
-    pub async fn add(&self, mission: Mission) -> watch::Receiver<MissionState> {
+    pub async fn add(
+        &self,
+        mission: Mission,
+        permit: SessionPermit,
+    ) -> watch::Receiver<MissionState> {
         let (send, recv) = oneshot::channel();
         let msg = Message::Add {
-            mission,
+            mission: Box::new(mission),
+            permit,
             respond_to: send,
         };
 
@@ -1,58 +1,197 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 use log::debug;
-use tokio::sync::{mpsc, oneshot, watch};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 
 use crate::{
-    actor::model::{Mission, MissionState, NodeDevice, TaskState},
-    api::model::FileInfo,
+    actor::model::{Mission, MissionState, NodeDevice},
+    api::model::{Compression, FileInfo},
+    util::unique_path,
 };
 
-use super::{FileState, MissionFileInfo, MissionInfo, MISSION_NOTIFY};
+use super::{notify, FileState, MissionFileInfo, MissionInfo, SessionPermit};
 
 enum Message {
     Add {
-        mission: Mission,
+        mission: Box<Mission>,
+        permit: SessionPermit,
         respond_to: oneshot::Sender<Result<(), MissionState>>,
     },
     ListenTask {
         respond_to: oneshot::Sender<Result<watch::Receiver<usize>, String>>,
     },
     StartTask {
+        session_id: String,
+        file_id: String,
         token: String,
-        respond_to: oneshot::Sender<Result<(watch::Sender<usize>, FileInfo), String>>,
+        respond_to: oneshot::Sender<Result<StartedTask, TaskError>>,
     },
     StateTask {
         token: String,
         state: FileState,
         respond_to: oneshot::Sender<()>,
     },
+    Partial {
+        session_id: String,
+        file_id: String,
+        token: String,
+        respond_to: oneshot::Sender<Result<Option<PathBuf>, TaskError>>,
+    },
+    Interrupt {
+        token: String,
+        attempt: u32,
+        path: PathBuf,
+        respond_to: oneshot::Sender<()>,
+    },
+    Expire {
+        token: String,
+        attempt: u32,
+        respond_to: oneshot::Sender<()>,
+    },
+    ReceiveText {
+        token: String,
+        text: String,
+        respond_to: oneshot::Sender<()>,
+    },
+    Complete {
+        token: String,
+        part: PathBuf,
+        target: PathBuf,
+        respond_to: oneshot::Sender<()>,
+    },
+    ExpireIdle {
+        session_id: String,
+        idle: Duration,
+        respond_to: oneshot::Sender<bool>,
+    },
     Cancel {
         id: String,
         respond_to: oneshot::Sender<()>,
     },
+    Subscribe {
+        respond_to: oneshot::Sender<broadcast::Receiver<TransferEvent>>,
+    },
+    TrackUpload {
+        session_id: String,
+        file_id: String,
+        total_bytes: u64,
+        respond_to: oneshot::Sender<watch::Sender<usize>>,
+    },
+    Emit {
+        event: TransferEvent,
+        respond_to: oneshot::Sender<()>,
+    },
 }
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferProgress {
+    pub session_id: String,
+    pub file_id: String,
+    pub bytes_sent: u64,
+    pub total_bytes: u64,
+}
+
+/// Per file transfer updates, of files we receive and of files we send.
+/// Progress is sampled by the writer, or the reader when sending, at most
+/// every 100ms.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransferEvent {
+    Progress(TransferProgress),
+    Completed {
+        session_id: String,
+        file_id: String,
+    },
+    Failed {
+        session_id: String,
+        file_id: String,
+        msg: String,
+    },
+    /// A text message arrived. Its item completes right after.
+    TextReceived {
+        session_id: String,
+        file_id: String,
+        from: NodeDevice,
+        text: String,
+    },
+    /// Every file of the session arrived and was moved to its final name.
+    SessionCompleted {
+        session_id: String,
+    },
+}
+/// An upload allowed to run. `canceled` flips to true when its mission is
+/// canceled. `partial` is where an interrupted earlier attempt left its
+/// bytes, to resume from.
+pub struct StartedTask {
+    pub progress: watch::Sender<usize>,
+    pub info: FileInfo,
+    pub canceled: watch::Receiver<bool>,
+    pub attempt: u32,
+    pub partial: Option<PathBuf>,
+    /// Overrides the configured store path, see `Mission::store_path`.
+    pub store_path: Option<PathBuf>,
+    /// Encoding the upload may come in, see `Mission::compression`.
+    pub compression: Option<Compression>,
+}
+
+/// Why an upload can't start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaskError {
+    /// No mission of that id is being transferred.
+    NoSession,
+    /// The file id and token don't belong to the mission.
+    InvalidToken,
+}
+
 #[derive(Debug, Clone)]
 struct TransferMission {
     id: String,
     sender: NodeDevice,
     files: HashMap<String, MissionFileInfo>,
     state: MissionState,
+    /// Upload attempts per token, so a stale interruption can't touch a
+    /// newer attempt.
+    attempts: HashMap<String, u32>,
+    /// Partial files of interrupted uploads waiting to be resumed.
+    partials: HashMap<String, PathBuf>,
+    store_path: Option<PathBuf>,
+    compression: Option<Compression>,
+    /// Part files of completed uploads with the names they get when the
+    /// session ends.
+    received: Vec<(PathBuf, PathBuf)>,
+    last_activity: Instant,
+}
+
+/// A mission being transferred, with what its uploads share.
+struct TransferSession {
+    mission: TransferMission,
+    /// Held until the session ends, its slot is given back then.
+    _permit: SessionPermit,
+    /// Flips to true when the session is canceled, stopping its uploads.
+    cancel: watch::Sender<bool>,
 }
+
+/// Sessions being transferred, by id. How many run at once is up to the
+/// `SessionPermit` each holds. Tokens are unique across sessions, so
+/// messages carrying only a token find their session by it.
 struct MissionStore {
-    mission: Option<TransferMission>,
-    task: Option<TransferTask>,
+    sessions: HashMap<String, TransferSession>,
+    /// The upload started last, of whichever session.
+    task: Option<(String, TransferTask)>,
 }
 
 struct Actor {
     receiver: mpsc::Receiver<Message>,
     store: MissionStore,
+    notify: notify::Handle,
+    events: broadcast::Sender<TransferEvent>,
 }
 
 #[derive(Debug, Clone)]
 struct TransferTask {
-    token: String,
-    state: TaskState,
     progress: watch::Receiver<usize>,
 }
 
@@ -61,26 +200,47 @@ impl MissionInfo {
         Self {
             id: mission.id,
             sender: mission.sender,
-            files: mission
-                .files
-                .values()
-                .map(|x| x.clone())
-                .collect::<Vec<_>>(),
+            files: mission.files.values().cloned().collect::<Vec<_>>(),
             state: mission.state,
         }
     }
 }
 
 impl Actor {
-    fn new(receiver: mpsc::Receiver<Message>) -> Self {
+    fn new(receiver: mpsc::Receiver<Message>, notify: notify::Handle) -> Self {
         let store: MissionStore = MissionStore {
-            mission: Option::None,
+            sessions: HashMap::new(),
             task: Option::None,
         };
-        Actor { receiver, store }
+        let (events, _) = broadcast::channel(32);
+        Actor {
+            receiver,
+            store,
+            notify,
+            events,
+        }
+    }
+    fn emit(&self, event: TransferEvent) {
+        // no subscribers is fine
+        let _ = self.events.send(event);
+    }
+    /// The mission the file of `token` belongs to.
+    fn mission_of(&self, token: &str) -> Option<&TransferMission> {
+        self.store
+            .sessions
+            .values()
+            .map(|session| &session.mission)
+            .find(|mission| mission.files.contains_key(token))
+    }
+    fn mission_of_mut(&mut self, token: &str) -> Option<&mut TransferMission> {
+        self.store
+            .sessions
+            .values_mut()
+            .map(|session| &mut session.mission)
+            .find(|mission| mission.files.contains_key(token))
     }
-    fn check_finish(&self) -> bool {
-        for (_, file) in self.store.mission.clone().unwrap().files {
+    fn check_finish(mission: &TransferMission) -> bool {
+        for file in mission.files.values() {
             match file.state {
                 FileState::Finish => {}
                 FileState::Skip => {}
@@ -89,41 +249,105 @@ impl Actor {
                 }
             }
         }
-        return true;
+        true
     }
-    fn change_file_state(&mut self, token: String, state: FileState) {
-        let mut file = self
+    fn find_file(
+        &self,
+        session_id: &str,
+        file_id: &str,
+        token: &str,
+    ) -> Result<&MissionFileInfo, TaskError> {
+        let session = self
             .store
-            .mission
-            .clone()
-            .unwrap()
-            .files
-            .get(&token)
-            .unwrap()
-            .clone();
-        file.state = state;
-        self.store
-            .mission
-            .as_mut()
-            .unwrap()
-            .files
-            .insert(token, file.clone());
+            .sessions
+            .get(session_id)
+            .ok_or(TaskError::NoSession)?;
+        match session.mission.files.get(token) {
+            Some(file) if file.info.id == file_id => Ok(file),
+            _ => Err(TaskError::InvalidToken),
+        }
     }
-    async fn finish_mission(&mut self, state: MissionState) {
-        let mut mission = self.store.mission.take().unwrap();
+    /// Ends session `id` in `state`, giving back its permit.
+    async fn finish_mission(&mut self, id: &str, state: MissionState) {
+        let mut mission = match self.store.sessions.remove(id) {
+            Some(session) => session.mission,
+            None => return,
+        };
+        discard_partials(&mut mission);
+        finalize_received(&mut mission).await;
+        if matches!(state, MissionState::Finished) {
+            self.emit(TransferEvent::SessionCompleted {
+                session_id: mission.id.clone(),
+            });
+        }
         mission.state = state;
-        MISSION_NOTIFY
+        self.notify
             .notify(Some(MissionInfo::from_transfer_mission(mission)))
             .await;
     }
+    /// Ends session `id` because it was canceled or given up on, stopping
+    /// its running uploads.
+    async fn abort_mission(&mut self, id: &str, state: MissionState) {
+        if let Some(session) = self.store.sessions.get(id) {
+            let _ = session.cancel.send(true);
+        }
+        if matches!(&self.store.task, Some((session_id, _)) if session_id == id) {
+            self.store.task.take();
+        }
+        self.finish_mission(id, state).await;
+    }
+    async fn state_task(&mut self, token: String, state: FileState) {
+        let mission = match self.mission_of_mut(&token) {
+            Some(mission) => mission,
+            None => return,
+        };
+        mission.last_activity = Instant::now();
+        let session_id = mission.id.clone();
+        let file = mission.files.get_mut(&token).unwrap();
+        file.state = state.clone();
+        let file_id = file.info.id.clone();
+        match &state {
+            FileState::Finish => self.emit(TransferEvent::Completed {
+                session_id: session_id.clone(),
+                file_id,
+            }),
+            FileState::Fail { msg } => self.emit(TransferEvent::Failed {
+                session_id: session_id.clone(),
+                file_id,
+                msg: msg.clone(),
+            }),
+            _ => {}
+        }
+        match state {
+            FileState::Skip | FileState::Finish => {
+                let finish = Self::check_finish(&self.store.sessions[&session_id].mission);
+                if finish {
+                    self.finish_mission(&session_id, MissionState::Finished)
+                        .await;
+                }
+            }
+            FileState::Fail { msg: _ } => {
+                self.finish_mission(&session_id, MissionState::Failed).await;
+            }
+            _ => {
+                self.notify
+                    .notify(Some(MissionInfo::from_transfer_mission(
+                        self.store.sessions[&session_id].mission.clone(),
+                    )))
+                    .await;
+            }
+        }
+    }
     async fn handle_message(&mut self, msg: Message) {
         match msg {
             Message::Add {
                 mission,
+                permit,
                 respond_to,
             } => {
+                let mission = *mission;
                 debug!("mission added transfer: {:?}", mission);
-                if self.store.mission.is_some() {
+                if self.store.sessions.contains_key(&mission.id) {
                     let _ = respond_to.send(Err(MissionState::Busy));
                     return;
                 }
@@ -143,107 +367,277 @@ impl Actor {
                     id: mission.id,
                     sender: mission.sender,
                     state: MissionState::Transfering,
-                    files: files,
+                    files,
+                    attempts: HashMap::new(),
+                    partials: HashMap::new(),
+                    store_path: mission.store_path,
+                    compression: mission.compression,
+                    received: Vec::new(),
+                    last_activity: Instant::now(),
                 };
 
-                self.store.mission.replace(transfer_mission.clone());
-                MISSION_NOTIFY
+                self.store.sessions.insert(
+                    transfer_mission.id.clone(),
+                    TransferSession {
+                        mission: transfer_mission.clone(),
+                        _permit: permit,
+                        cancel: watch::channel(false).0,
+                    },
+                );
+                self.notify
                     .notify(Some(MissionInfo::from_transfer_mission(transfer_mission)))
                     .await;
                 let _ = respond_to.send(Ok(()));
             }
-            Message::StartTask { token, respond_to } => {
-                let mission = self.store.mission.as_mut().unwrap();
-                if !mission.files.contains_key(&token) {
-                    let _ = respond_to.send(Err("task token not exist".to_string()));
-                    return;
-                }
-
-                let mut file = mission.files.get(&token).unwrap().clone();
+            Message::StartTask {
+                session_id,
+                file_id,
+                token,
+                respond_to,
+            } => {
+                let mut file = match self.find_file(&session_id, &file_id, &token) {
+                    Ok(file) => file.clone(),
+                    Err(err) => {
+                        let _ = respond_to.send(Err(err));
+                        return;
+                    }
+                };
+                let session = self.store.sessions.get_mut(&session_id).unwrap();
+                let canceled = session.cancel.subscribe();
+                let mission = &mut session.mission;
                 file.state = FileState::Transfer;
                 mission.files.insert(token.clone(), file.clone());
+                let attempt = mission.attempts.entry(token.clone()).or_default();
+                *attempt += 1;
+                let attempt = *attempt;
+                let partial = mission.partials.remove(&token);
+                let store_path = mission.store_path.clone();
+                let compression = mission.compression;
+                mission.last_activity = Instant::now();
+                let info = MissionInfo::from_transfer_mission(mission.clone());
 
                 let (tx, rx) = watch::channel(0);
 
                 let task = TransferTask {
-                    token: token,
-                    state: TaskState::Transfering,
-                    progress: rx,
+                    progress: rx.clone(),
                 };
-
-                self.store.task.replace(task);
-                MISSION_NOTIFY
-                    .notify(Some(MissionInfo::from_transfer_mission(
-                        self.store.mission.clone().unwrap(),
-                    )))
-                    .await;
-                let _ = respond_to.send(Ok((tx, file.info)));
+                tokio::spawn(forward_progress(
+                    rx,
+                    self.events.clone(),
+                    session_id.clone(),
+                    file.info.id.clone(),
+                    file.info.size.max(0) as u64,
+                ));
+
+                self.store.task.replace((session_id, task));
+                self.notify.notify(Some(info)).await;
+                let _ = respond_to.send(Ok(StartedTask {
+                    progress: tx,
+                    info: file.info,
+                    canceled,
+                    attempt,
+                    partial,
+                    store_path,
+                    compression,
+                }));
             }
             Message::StateTask {
                 token,
                 state,
                 respond_to,
             } => {
-                if self.store.mission.is_some() {
-                    self.change_file_state(token, state.clone());
-                    match state {
-                        FileState::Skip | FileState::Finish => {
-                            let finish = self.check_finish();
-                            if finish {
-                                self.finish_mission(MissionState::Finished).await;
-                            }
-                        }
-                        FileState::Fail { msg: _ } => {
-                            self.finish_mission(MissionState::Failed).await;
-                        }
-                        _ => {
-                            MISSION_NOTIFY
-                                .notify(Some(MissionInfo::from_transfer_mission(
-                                    self.store.mission.clone().unwrap(),
-                                )))
-                                .await;
-                        }
+                self.state_task(token, state).await;
+                let _ = respond_to.send(());
+            }
+            Message::Partial {
+                session_id,
+                file_id,
+                token,
+                respond_to,
+            } => {
+                let partial = self.find_file(&session_id, &file_id, &token).map(|_| {
+                    let mission = &self.store.sessions[&session_id].mission;
+                    mission.partials.get(&token).cloned()
+                });
+                let _ = respond_to.send(partial);
+            }
+            Message::Interrupt {
+                token,
+                attempt,
+                path,
+                respond_to,
+            } => {
+                match self.mission_of_mut(&token) {
+                    Some(mission) if mission.attempts.get(&token) == Some(&attempt) => {
+                        debug!("upload {} interrupted, keeping {:?}", token, path);
+                        mission.partials.insert(token.clone(), path);
+                        self.state_task(token, FileState::Pending).await;
+                    }
+                    // a newer attempt started over elsewhere, or the mission is gone
+                    _ => {
+                        let _ = tokio::fs::remove_file(path).await;
                     }
                 }
-
                 let _ = respond_to.send(());
             }
-            Message::Cancel { id, respond_to } => {
-                match &self.store.mission {
-                    Some(mission) => {
-                        if mission.id == id {
-                            let mut mission = self.store.mission.take().unwrap();
-                            mission.state = MissionState::Canceled;
-                            MISSION_NOTIFY
-                                .notify(Some(MissionInfo::from_transfer_mission(mission)))
-                                .await;
-                        }
+            Message::Expire {
+                token,
+                attempt,
+                respond_to,
+            } => {
+                let expired = self.mission_of(&token).is_some_and(|mission| {
+                    mission.attempts.get(&token) == Some(&attempt)
+                        && mission.partials.contains_key(&token)
+                });
+                if expired {
+                    let msg = "upload interrupted".to_string();
+                    self.state_task(token, FileState::Fail { msg }).await;
+                }
+                let _ = respond_to.send(());
+            }
+            Message::ReceiveText {
+                token,
+                text,
+                respond_to,
+            } => {
+                if let Some(mission) = self.mission_of(&token) {
+                    let file = &mission.files[&token];
+                    self.emit(TransferEvent::TextReceived {
+                        session_id: mission.id.clone(),
+                        file_id: file.info.id.clone(),
+                        from: mission.sender.clone(),
+                        text,
+                    });
+                }
+                self.state_task(token, FileState::Finish).await;
+                let _ = respond_to.send(());
+            }
+            Message::Complete {
+                token,
+                part,
+                target,
+                respond_to,
+            } => {
+                match self.mission_of_mut(&token) {
+                    Some(mission) => mission.received.push((part, target)),
+                    // the session ended meanwhile, nothing will rename it
+                    None => {
+                        let _ = tokio::fs::rename(&part, unique_path(&target)).await;
                     }
-                    None => {}
                 }
-
+                self.state_task(token, FileState::Finish).await;
                 let _ = respond_to.send(());
             }
-            Message::ListenTask { respond_to } => match &self.store.mission {
-                Some(_) => {
-                    let task = self.store.task.clone();
-                    match task {
-                        Some(task) => {
-                            let _ = respond_to.send(Ok(task.progress));
-                        }
-                        None => {
-                            let _ = respond_to.send(Err("task not found".to_string()));
-                        }
+            Message::ExpireIdle {
+                session_id,
+                idle,
+                respond_to,
+            } => {
+                let running = match self.store.sessions.get(&session_id) {
+                    Some(session) => &session.mission,
+                    None => {
+                        let _ = respond_to.send(false);
+                        return;
                     }
+                };
+                let uploading = running
+                    .files
+                    .values()
+                    .any(|file| matches!(file.state, FileState::Transfer));
+                if uploading || running.last_activity.elapsed() < idle {
+                    let _ = respond_to.send(true);
+                    return;
                 }
-                None => {
+                debug!("session {} idle for {:?}, giving up", session_id, idle);
+                self.abort_mission(&session_id, MissionState::Failed).await;
+                let _ = respond_to.send(false);
+            }
+            Message::Cancel { id, respond_to } => {
+                self.abort_mission(&id, MissionState::Canceled).await;
+                let _ = respond_to.send(());
+            }
+            Message::Subscribe { respond_to } => {
+                let _ = respond_to.send(self.events.subscribe());
+            }
+            Message::TrackUpload {
+                session_id,
+                file_id,
+                total_bytes,
+                respond_to,
+            } => {
+                let (tx, rx) = watch::channel(0);
+                tokio::spawn(forward_progress(
+                    rx,
+                    self.events.clone(),
+                    session_id,
+                    file_id,
+                    total_bytes,
+                ));
+                let _ = respond_to.send(tx);
+            }
+            Message::Emit { event, respond_to } => {
+                self.emit(event);
+                let _ = respond_to.send(());
+            }
+            Message::ListenTask { respond_to } => match &self.store.task {
+                Some((session_id, task)) if self.store.sessions.contains_key(session_id) => {
+                    let _ = respond_to.send(Ok(task.progress.clone()));
+                }
+                Some(_) => {
                     let _ = respond_to.send(Err("mission not found".to_string()));
                 }
+                None if self.store.sessions.is_empty() => {
+                    let _ = respond_to.send(Err("mission not found".to_string()));
+                }
+                None => {
+                    let _ = respond_to.send(Err("task not found".to_string()));
+                }
             },
         }
     }
 }
 
+/// Removes what interrupted uploads of a mission that won't resume left
+/// behind.
+fn discard_partials(mission: &mut TransferMission) {
+    for (_, path) in mission.partials.drain() {
+        tokio::spawn(async move {
+            let _ = tokio::fs::remove_file(path).await;
+        });
+    }
+}
+
+/// Moves the completed files of a mission to their final names, whether it
+/// finished or not. What arrived in full is kept.
+async fn finalize_received(mission: &mut TransferMission) {
+    for (part, target) in mission.received.drain(..) {
+        let target = unique_path(&target);
+        if let Err(err) = tokio::fs::rename(&part, &target).await {
+            debug!("can't move {:?} to {:?}: {}", part, target, err);
+        }
+    }
+}
+
+/// Turns the byte counter of a running upload into progress events until the
+/// writer goes away.
+async fn forward_progress(
+    mut progress: watch::Receiver<usize>,
+    events: broadcast::Sender<TransferEvent>,
+    session_id: String,
+    file_id: String,
+    total_bytes: u64,
+) {
+    while progress.changed().await.is_ok() {
+        let bytes_sent = *progress.borrow_and_update() as u64;
+        let _ = events.send(TransferEvent::Progress(TransferProgress {
+            session_id: session_id.clone(),
+            file_id: file_id.clone(),
+            bytes_sent,
+            total_bytes,
+        }));
+    }
+}
+
 async fn run_mission_actor(mut actor: Actor) {
     while let Some(msg) = actor.receiver.recv().await {
         actor.handle_message(msg).await;
@@ -256,20 +650,64 @@ pub struct Handle {
 }
 
 impl Handle {
-    pub fn new() -> Self {
+    pub fn new(notify: notify::Handle) -> Self {
         let (sender, receiver) = mpsc::channel(8);
-        let actor = Actor::new(receiver);
+        let actor = Actor::new(receiver, notify);
         tokio::spawn(run_mission_actor(actor));
 
         Self { sender }
     }
 
+    pub async fn subscribe(&self) -> broadcast::Receiver<TransferEvent> {
+        let (send, recv) = oneshot::channel();
+        let msg = Message::Subscribe { respond_to: send };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Byte counter of an upload we send, reported as progress events
+    /// until it's dropped.
+    pub async fn track_upload(
+        &self,
+        session_id: String,
+        file_id: String,
+        total_bytes: u64,
+    ) -> watch::Sender<usize> {
+        let (send, recv) = oneshot::channel();
+        let msg = Message::TrackUpload {
+            session_id,
+            file_id,
+            total_bytes,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Passes `event` of a session we send to the subscribers.
+    pub async fn emit(&self, event: TransferEvent) {
+        let (send, recv) = oneshot::channel();
+        let msg = Message::Emit {
+            event,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
     pub async fn start_task(
         &self,
+        session_id: String,
+        file_id: String,
         token: String,
-    ) -> Result<(watch::Sender<usize>, FileInfo), String> {
+    ) -> Result<StartedTask, TaskError> {
         let (send, recv) = oneshot::channel();
         let msg = Message::StartTask {
+            session_id,
+            file_id,
             token,
             respond_to: send,
         };
@@ -287,6 +725,53 @@ impl Handle {
         recv.await.expect("Actor task has been killed")
     }
 
+    /// Where an interrupted upload of the file left its bytes, if anywhere.
+    pub async fn partial(
+        &self,
+        session_id: String,
+        file_id: String,
+        token: String,
+    ) -> Result<Option<PathBuf>, TaskError> {
+        let (send, recv) = oneshot::channel();
+        let msg = Message::Partial {
+            session_id,
+            file_id,
+            token,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Puts the file back to pending after `attempt` lost its connection,
+    /// keeping what arrived at `path` for a resume.
+    pub async fn interrupt(&self, token: String, attempt: u32, path: PathBuf) {
+        let (send, recv) = oneshot::channel();
+        let msg = Message::Interrupt {
+            token,
+            attempt,
+            path,
+            respond_to: send,
+        };
+        let _ = self.sender.send(msg).await;
+
+        recv.await.expect("Actor task has been killed");
+    }
+
+    /// Fails the file unless it was resumed since `attempt` was interrupted.
+    pub async fn expire(&self, token: String, attempt: u32) {
+        let (send, recv) = oneshot::channel();
+        let msg = Message::Expire {
+            token,
+            attempt,
+            respond_to: send,
+        };
+        let _ = self.sender.send(msg).await;
+
+        recv.await.expect("Actor task has been killed");
+    }
+
     pub async fn state_task(&self, token: String, state: FileState) {
         let (send, recv) = oneshot::channel();
         let msg = Message::StateTask {
@@ -299,10 +784,54 @@ impl Handle {
         recv.await.expect("Actor task has been killed");
     }
 
-    pub async fn add(&self, mission: Mission) -> Result<(), MissionState> {
+    /// Completes the file of `token`, received in full at `part`. It's moved
+    /// to `target` when the session ends.
+    pub async fn complete(&self, token: String, part: PathBuf, target: PathBuf) {
+        let (send, recv) = oneshot::channel();
+        let msg = Message::Complete {
+            token,
+            part,
+            target,
+            respond_to: send,
+        };
+        let _ = self.sender.send(msg).await;
+
+        recv.await.expect("Actor task has been killed");
+    }
+
+    /// Fails session `session_id` if nothing happened in it for `idle` and no
+    /// upload is running. Returns whether the session is still on, for
+    /// checking again later.
+    pub async fn expire_idle(&self, session_id: String, idle: Duration) -> bool {
+        let (send, recv) = oneshot::channel();
+        let msg = Message::ExpireIdle {
+            session_id,
+            idle,
+            respond_to: send,
+        };
+        let _ = self.sender.send(msg).await;
+
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Hands over the text of a text item, which completes it.
+    pub async fn receive_text(&self, token: String, text: String) {
+        let (send, recv) = oneshot::channel();
+        let msg = Message::ReceiveText {
+            token,
+            text,
+            respond_to: send,
+        };
+        let _ = self.sender.send(msg).await;
+
+        recv.await.expect("Actor task has been killed");
+    }
+
+    pub async fn add(&self, mission: Mission, permit: SessionPermit) -> Result<(), MissionState> {
         let (send, recv) = oneshot::channel();
         let msg = Message::Add {
-            mission,
+            mission: Box::new(mission),
+            permit,
             respond_to: send,
         };
 
@@ -1,6 +1,9 @@
 pub mod core;
 pub mod device;
 pub mod discovery;
+pub mod favorite;
 pub mod http;
 pub mod mission;
 pub mod model;
+pub mod send;
+pub mod share;
@@ -1,10 +1,111 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr, SocketAddrV6},
+    path::PathBuf,
+};
 
+use log::debug;
+use serde::Deserializer;
 use serde_derive::{Deserialize, Serialize};
 
-use crate::api::model::FileInfo;
+use crate::{
+    api::model::{Compression, FileInfo, SenderInfo},
+    util::{hex_string, parse_scoped_ip},
+};
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// Version of the LocalSend protocol we speak. Peers with a major version
+/// other than this one or the legacy v1 are ignored.
+pub const PROTOCOL_VERSION: &str = "2.0";
+
+/// Version v1 announces are assumed to speak, they don't carry one.
+pub const PROTOCOL_VERSION_V1: &str = "1.0";
+
+/// Where peers serve the api of that version.
+pub const API_PATH: &str = "/api/localsend/v2";
+
+/// Where legacy v1 peers serve theirs.
+pub const API_PATH_V1: &str = "/api/localsend/v1";
+
+/// Port v1 peers listen on, their announces don't carry one.
+const DEFAULT_PORT_V1: u16 = 53317;
+
+/// Starts CBOR announces. It's the self-described CBOR tag, which no JSON
+/// text starts with.
+pub const CBOR_PREFIX: &[u8] = &[0xd9, 0xd9, 0xf7];
+
+/// Encoding of the announces we send. Either is understood on receipt.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum WireFormat {
+    /// What LocalSend apps speak.
+    #[default]
+    Json,
+    /// Smaller datagrams, only for groups of devices running this library.
+    Cbor,
+}
+
+/// Api path of a peer speaking `version`.
+pub fn api_path(version: &str) -> &'static str {
+    match major_version(version) {
+        Some(1) => API_PATH_V1,
+        _ => API_PATH,
+    }
+}
+
+/// The scheme to reach a peer announcing `protocol` with, `http` or
+/// `https` in any case. Anything else is taken as `http`, so peers can't
+/// have us build urls with another scheme.
+pub fn normalize_protocol(protocol: &str) -> &'static str {
+    if protocol.eq_ignore_ascii_case("https") {
+        return "https";
+    }
+    if !protocol.eq_ignore_ascii_case("http") {
+        debug!("unknown protocol {:?}, using http", protocol);
+    }
+    "http"
+}
+
+fn deserialize_protocol<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let protocol = <String as serde::Deserialize>::deserialize(deserializer)?;
+    Ok(normalize_protocol(&protocol).to_string())
+}
+
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + serde::Deserialize<'de>,
+{
+    let value = <Option<T> as serde::Deserialize>::deserialize(deserializer)?;
+    Ok(value.unwrap_or_default())
+}
+
+/// Url of `endpoint` on the api served under `api_path` at `addr`. The
+/// address goes in without a zone index, which urls can't carry.
+pub fn api_url(protocol: &str, addr: SocketAddr, api_path: &str, endpoint: &str) -> String {
+    let host = match addr.ip() {
+        IpAddr::V6(ip) => format!("[{}]", ip),
+        IpAddr::V4(ip) => ip.to_string(),
+    };
+    format!(
+        "{}://{}:{}{}{}",
+        normalize_protocol(protocol),
+        host,
+        addr.port(),
+        api_path,
+        endpoint
+    )
+}
+
+/// Major part of a `major.minor` protocol version.
+pub fn major_version(version: &str) -> Option<u32> {
+    version.split('.').next()?.trim().parse().ok()
+}
+
+/// A device is identified by its fingerprint alone, equality and hashing
+/// ignore everything else, which may change between sightings. Use
+/// `same_details` to compare every field.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct NodeDevice {
     pub alias: String,
@@ -20,30 +121,168 @@ pub struct NodeDevice {
     pub announce: bool,
 }
 
+/// Device types defined by the protocol. Unrecognized values parse as
+/// `Unknown`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", from = "String")]
+pub enum DeviceType {
+    Mobile,
+    Desktop,
+    Web,
+    Headless,
+    Server,
+    #[default]
+    Unknown,
+}
+
+impl DeviceType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceType::Mobile => "mobile",
+            DeviceType::Desktop => "desktop",
+            DeviceType::Web => "web",
+            DeviceType::Headless => "headless",
+            DeviceType::Server => "server",
+            DeviceType::Unknown => "unknown",
+        }
+    }
+}
+
+impl From<&str> for DeviceType {
+    fn from(value: &str) -> Self {
+        match value.to_ascii_lowercase().as_str() {
+            "mobile" => DeviceType::Mobile,
+            "desktop" => DeviceType::Desktop,
+            "web" => DeviceType::Web,
+            "headless" => DeviceType::Headless,
+            "server" => DeviceType::Server,
+            _ => DeviceType::Unknown,
+        }
+    }
+}
+
+impl From<String> for DeviceType {
+    fn from(value: String) -> Self {
+        DeviceType::from(value.as_str())
+    }
+}
+
+/// Besides the protocol's camelCase names, snake_case ones some clients
+/// send are accepted. The flags default to off when left out, as apps
+/// have sent only one of `announce` and `announcement`, and the device
+/// model and type, which the official app leaves null when unknown, to
+/// empty and `Unknown`.
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct NodeAnnounce {
     pub alias: String,
     pub version: String,
+    #[serde(alias = "device_model", default, deserialize_with = "null_as_default")]
     pub device_model: String,
-    pub device_type: String,
+    #[serde(alias = "device_type", default, deserialize_with = "null_as_default")]
+    pub device_type: DeviceType,
     pub fingerprint: String,
     pub port: u16,
+    /// Always `http` or `https`, see `normalize_protocol`.
+    #[serde(deserialize_with = "deserialize_protocol")]
     pub protocol: String,
+    #[serde(default)]
     pub download: bool,
+    #[serde(default)]
     pub announcement: bool,
+    #[serde(default)]
     pub announce: bool,
 }
 
-impl NodeDevice {
-    pub fn from_announce(announce: &NodeAnnounce, address: &str) -> NodeDevice {
+/// Announce of legacy v1 peers, which lacks the version and connection
+/// details v2 added.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeAnnounceV1 {
+    pub alias: String,
+    #[serde(alias = "device_model")]
+    pub device_model: Option<String>,
+    #[serde(alias = "device_type", default, deserialize_with = "null_as_default")]
+    pub device_type: DeviceType,
+    pub fingerprint: String,
+    #[serde(default)]
+    pub announcement: bool,
+}
+
+impl From<NodeAnnounceV1> for NodeAnnounce {
+    fn from(v1: NodeAnnounceV1) -> Self {
+        NodeAnnounce {
+            alias: v1.alias,
+            version: PROTOCOL_VERSION_V1.to_string(),
+            device_model: v1.device_model.unwrap_or_default(),
+            device_type: v1.device_type,
+            fingerprint: v1.fingerprint,
+            port: DEFAULT_PORT_V1,
+            protocol: "http".to_string(),
+            download: false,
+            announcement: v1.announcement,
+            announce: false,
+        }
+    }
+}
+
+impl NodeAnnounce {
+    /// Parses a v2 announce, falling back to the v1 format for messages
+    /// without a version. A broken v2 announce isn't mistaken for v1.
+    pub fn parse(message: &str) -> serde_json::Result<NodeAnnounce> {
+        serde_json::from_str::<NodeAnnounce>(message).or_else(|err| {
+            let value: serde_json::Value = serde_json::from_str(message)?;
+            if value.get("version").is_some() {
+                return Err(err);
+            }
+            serde_json::from_value::<NodeAnnounceV1>(value).map(NodeAnnounce::from)
+        })
+    }
+
+    /// Decodes a datagram in either wire format, told apart by
+    /// `CBOR_PREFIX`.
+    pub fn decode(packet: &[u8]) -> Result<NodeAnnounce, String> {
+        match packet.strip_prefix(CBOR_PREFIX) {
+            Some(cbor) => ciborium::from_reader(cbor).map_err(|err| err.to_string()),
+            None => Self::parse(&String::from_utf8_lossy(packet)).map_err(|err| err.to_string()),
+        }
+    }
+
+    pub fn encode(&self, format: WireFormat) -> Vec<u8> {
+        match format {
+            WireFormat::Json => serde_json::to_vec(self).unwrap(),
+            WireFormat::Cbor => {
+                let mut packet = CBOR_PREFIX.to_vec();
+                ciborium::into_writer(self, &mut packet).unwrap();
+                packet
+            }
+        }
+    }
+
+    /// Whether the sender is announcing itself, as opposed to replying to an
+    /// announce. v1 peers use `announcement`, v2 peers `announce`.
+    pub fn is_announcement(&self) -> bool {
+        self.announce || self.announcement
+    }
+
+    /// Whether the sender speaks our major protocol version or v1.
+    pub fn is_compatible(&self) -> bool {
+        let major = major_version(&self.version);
+        major == major_version(PROTOCOL_VERSION) || major == major_version(PROTOCOL_VERSION_V1)
+    }
+}
+
+/// Every field the protocol defines carries over, the address isn't part of
+/// an announce and is left empty.
+impl From<&NodeAnnounce> for NodeDevice {
+    fn from(announce: &NodeAnnounce) -> Self {
         NodeDevice {
             alias: announce.alias.clone(),
             version: announce.version.clone(),
             device_model: announce.device_model.clone(),
-            device_type: announce.device_type.clone(),
+            device_type: announce.device_type.as_str().to_string(),
             fingerprint: announce.fingerprint.clone(),
-            address: address.to_string(),
+            address: String::new(),
             port: announce.port,
             protocol: announce.protocol.clone(),
             download: announce.download,
@@ -51,23 +290,247 @@ impl NodeDevice {
             announce: announce.announce,
         }
     }
+}
+
+/// Every field the protocol defines carries over, the address is dropped.
+impl From<&NodeDevice> for NodeAnnounce {
+    fn from(device: &NodeDevice) -> Self {
+        NodeAnnounce {
+            alias: device.alias.clone(),
+            version: device.version.clone(),
+            device_model: device.device_model.clone(),
+            device_type: device.kind(),
+            fingerprint: device.fingerprint.clone(),
+            port: device.port,
+            protocol: device.protocol.clone(),
+            download: device.download,
+            announcement: device.announcement,
+            announce: device.announce,
+        }
+    }
+}
+
+impl NodeDevice {
+    pub fn from_announce(announce: &NodeAnnounce, address: &str) -> NodeDevice {
+        NodeDevice {
+            address: address.to_string(),
+            ..NodeDevice::from(announce)
+        }
+    }
+
+    /// Whether every field matches, unlike `==` which only compares the
+    /// fingerprint.
+    pub fn same_details(&self, other: &NodeDevice) -> bool {
+        self.alias == other.alias
+            && self.version == other.version
+            && self.device_model == other.device_model
+            && self.device_type == other.device_type
+            && self.fingerprint == other.fingerprint
+            && self.address == other.address
+            && self.port == other.port
+            && self.protocol == other.protocol
+            && self.download == other.download
+            && self.announcement == other.announcement
+            && self.announce == other.announce
+    }
+
+    /// First 8 characters of the fingerprint, enough to tell devices apart
+    /// in logs.
+    pub fn short_fingerprint(&self) -> &str {
+        match self.fingerprint.char_indices().nth(8) {
+            Some((end, _)) => &self.fingerprint[..end],
+            None => &self.fingerprint,
+        }
+    }
 
+    /// A fresh 256 bit identity, hex encoded.
+    pub fn random_fingerprint() -> String {
+        let bytes: [u8; 32] = rand::random();
+        hex_string(&bytes)
+    }
+
+    /// Typed form of `device_type`, which stays a string across the bridge.
+    pub fn kind(&self) -> DeviceType {
+        DeviceType::from(self.device_type.as_str())
+    }
+
+    /// Socket address of the device's http api, keeping the zone index of
+    /// link-local IPv6 addresses.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        let (ip, scope_id) = parse_scoped_ip(&self.address)?;
+        match SocketAddr::new(ip, self.port) {
+            SocketAddr::V6(v6) => Some(SocketAddr::V6(SocketAddrV6::new(
+                *v6.ip(),
+                v6.port(),
+                0,
+                scope_id,
+            ))),
+            addr => Some(addr),
+        }
+    }
+
+    /// Copy of this device used to answer someone else's announce. The
+    /// cleared flags tell the peer not to answer back.
+    pub fn to_reply(&self) -> NodeDevice {
+        NodeDevice {
+            announcement: false,
+            announce: false,
+            ..self.clone()
+        }
+    }
+
+    /// Our announce, which always claims the protocol version we speak.
+    /// Converting with `NodeAnnounce::from` keeps the device's version.
     pub fn to_announce(&self) -> NodeAnnounce {
         NodeAnnounce {
+            version: PROTOCOL_VERSION.to_string(),
+            ..NodeAnnounce::from(self)
+        }
+    }
+
+    /// How this device introduces itself in a prepare-upload request.
+    pub fn to_sender_info(&self) -> SenderInfo {
+        SenderInfo {
             alias: self.alias.clone(),
-            version: self.version.clone(),
+            version: PROTOCOL_VERSION.to_string(),
             device_model: self.device_model.clone(),
             device_type: self.device_type.clone(),
             fingerprint: self.fingerprint.clone(),
-            port: self.port,
+            port: self.port as i64,
             protocol: self.protocol.clone(),
             download: self.download,
-            announcement: self.announcement,
-            announce: self.announce,
         }
     }
 }
 
+impl PartialEq for NodeDevice {
+    fn eq(&self, other: &Self) -> bool {
+        self.fingerprint == other.fingerprint
+    }
+}
+
+impl Eq for NodeDevice {}
+
+impl Hash for NodeDevice {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.fingerprint.hash(state);
+    }
+}
+
+/// `alias (device_model) @ address:port [protocol]`, for logs and UIs.
+/// `Debug` keeps every field.
+impl fmt::Display for NodeDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.alias)?;
+        if !self.device_model.is_empty() {
+            write!(f, " ({})", self.device_model)?;
+        }
+        write!(f, " @ {}:{} [{}]", self.address, self.port, self.protocol)
+    }
+}
+
+/// Cleans up an alias for showing on peers: control characters, like line
+/// breaks, are dropped and surrounding whitespace is trimmed. Fails when
+/// nothing is left.
+pub fn sanitize_alias(alias: &str) -> Result<String, String> {
+    let alias = alias
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>();
+    let alias = alias.trim();
+    if alias.is_empty() {
+        return Err("alias must not be empty".to_string());
+    }
+    Ok(alias.to_string())
+}
+
+/// Builds the device describing this node. Everything but the alias has a
+/// default: a random fingerprint, `http` on port 53317 and a headless type.
+#[derive(Debug, Clone)]
+pub struct NodeBuilder {
+    device: NodeDevice,
+}
+
+impl Default for NodeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NodeBuilder {
+    pub fn new() -> Self {
+        NodeBuilder {
+            device: NodeDevice {
+                version: PROTOCOL_VERSION.to_string(),
+                device_type: DeviceType::Headless.as_str().to_string(),
+                fingerprint: NodeDevice::random_fingerprint(),
+                port: 53317,
+                protocol: "http".to_string(),
+                announcement: true,
+                announce: true,
+                ..NodeDevice::default()
+            },
+        }
+    }
+
+    pub fn alias(mut self, alias: impl Into<String>) -> Self {
+        self.device.alias = alias.into();
+        self
+    }
+
+    pub fn device_model(mut self, model: impl Into<String>) -> Self {
+        self.device.device_model = model.into();
+        self
+    }
+
+    pub fn device_type(mut self, device_type: DeviceType) -> Self {
+        self.device.device_type = device_type.as_str().to_string();
+        self
+    }
+
+    pub fn fingerprint(mut self, fingerprint: impl Into<String>) -> Self {
+        self.device.fingerprint = fingerprint.into();
+        self
+    }
+
+    pub fn address(mut self, address: impl Into<String>) -> Self {
+        self.device.address = address.into();
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.device.port = port;
+        self
+    }
+
+    pub fn protocol(mut self, protocol: impl Into<String>) -> Self {
+        self.device.protocol = protocol.into();
+        self
+    }
+
+    pub fn download(mut self, download: bool) -> Self {
+        self.device.download = download;
+        self
+    }
+
+    pub fn build(self) -> Result<NodeDevice, String> {
+        let device = self.device;
+        if device.alias.is_empty() {
+            return Err("alias is required".to_string());
+        }
+        if device.fingerprint.is_empty() {
+            return Err("fingerprint must not be empty".to_string());
+        }
+        if device.port == 0 {
+            return Err("port must not be 0".to_string());
+        }
+        if device.protocol != "http" && device.protocol != "https" {
+            return Err(format!("unsupported protocol {}", device.protocol));
+        }
+        Ok(device)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Mission {
     pub id: String,
@@ -75,6 +538,12 @@ pub struct Mission {
     pub id_token_map: HashMap<String, String>,
     pub token_id_map: HashMap<String, String>,
     pub info_map: HashMap<String, FileInfo>,
+    /// Where this mission's files go instead of the configured store path.
+    #[serde(default)]
+    pub store_path: Option<PathBuf>,
+    /// Encoding agreed on for the uploads, see [`Compression`].
+    #[serde(default)]
+    pub compression: Option<Compression>,
 }
 
 impl Mission {
@@ -94,6 +563,8 @@ impl Mission {
             id_token_map,
             token_id_map,
             info_map: info_map.clone(),
+            store_path: None,
+            compression: None,
         }
     }
 }
@@ -114,3 +585,305 @@ pub enum TaskState {
     Transfering,
     Finished,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE_TYPES: [DeviceType; 6] = [
+        DeviceType::Mobile,
+        DeviceType::Desktop,
+        DeviceType::Web,
+        DeviceType::Headless,
+        DeviceType::Server,
+        DeviceType::Unknown,
+    ];
+
+    fn announces() -> Vec<NodeAnnounce> {
+        let mut announces = Vec::new();
+        for (i, device_type) in DEVICE_TYPES.iter().enumerate() {
+            for flags in 0..8u8 {
+                announces.push(NodeAnnounce {
+                    alias: format!("alias {}", i),
+                    version: if i % 2 == 0 { "2.1" } else { "1.0" }.to_string(),
+                    device_model: format!("model {}", flags),
+                    device_type: *device_type,
+                    fingerprint: NodeDevice::random_fingerprint(),
+                    port: 53317 + flags as u16,
+                    protocol: if flags % 2 == 0 { "http" } else { "https" }.to_string(),
+                    download: flags & 1 != 0,
+                    announcement: flags & 2 != 0,
+                    announce: flags & 4 != 0,
+                });
+            }
+        }
+        announces
+    }
+
+    #[test]
+    fn announce_survives_node_round_trip() {
+        for announce in announces() {
+            let device = NodeDevice::from(&announce);
+            assert_eq!(device.kind(), announce.device_type);
+            assert_eq!(device.address, "");
+            assert_eq!(NodeAnnounce::from(&device), announce);
+
+            let device = NodeDevice::from_announce(&announce, "192.168.1.2");
+            assert_eq!(device.address, "192.168.1.2");
+            assert_eq!(NodeAnnounce::from(&device), announce);
+        }
+    }
+
+    #[test]
+    fn node_survives_announce_round_trip() {
+        for announce in announces() {
+            let device = NodeDevice::from_announce(&announce, "fe80::1%2");
+            let back = NodeDevice::from_announce(&NodeAnnounce::from(&device), &device.address);
+            assert!(back.same_details(&device), "{:?} != {:?}", back, device);
+        }
+    }
+
+    #[test]
+    fn announce_survives_both_wire_formats() {
+        for announce in announces() {
+            for format in [WireFormat::Json, WireFormat::Cbor].iter() {
+                let decoded = NodeAnnounce::decode(&announce.encode(*format)).unwrap();
+                assert_eq!(decoded, announce);
+            }
+        }
+    }
+
+    #[test]
+    fn device_type_names_parse_back() {
+        for device_type in DEVICE_TYPES.iter() {
+            assert_eq!(DeviceType::from(device_type.as_str()), *device_type);
+            assert_eq!(
+                DeviceType::from(device_type.as_str().to_uppercase()),
+                *device_type
+            );
+        }
+        assert_eq!(DeviceType::from("tv"), DeviceType::Unknown);
+    }
+
+    /// Announces in the shape LocalSend releases send them.
+    mod fixtures {
+        /// App 1.x before protocol v2: no version, port or protocol, and
+        /// only the `announcement` flag.
+        pub const V1_DESKTOP: &str = r#"{
+            "alias": "Secret Banana",
+            "deviceModel": "Windows",
+            "deviceType": "desktop",
+            "fingerprint": "a4d5e0c2-6c1b-4d3f-9f0e-2b7c8e1f5a90",
+            "announcement": true
+        }"#;
+
+        /// Protocol v2.0 phone with encryption turned off, sending both
+        /// flags for v1 peers.
+        pub const V2_0_MOBILE_HTTP: &str = r#"{
+            "alias": "Smart Mango",
+            "version": "2.0",
+            "deviceModel": "Samsung",
+            "deviceType": "mobile",
+            "fingerprint": "6f0c9d6e-3a48-4b0c-8d6a-1e5b2f7c9a13",
+            "port": 53317,
+            "protocol": "http",
+            "download": false,
+            "announcement": true,
+            "announce": true
+        }"#;
+
+        /// Protocol v2.1 desktop on https, fingerprinted by its certificate,
+        /// with the download api on.
+        pub const V2_1_DESKTOP_HTTPS: &str = r#"{
+            "alias": "Fresh Pineapple",
+            "version": "2.1",
+            "deviceModel": "macOS",
+            "deviceType": "desktop",
+            "fingerprint": "3c8b2a0f9d71e6c45b0a8f2e7d19c3b6a5e4f0d28c7b1a9e6f3d2c0b8a7e5f41",
+            "port": 53317,
+            "protocol": "https",
+            "download": true,
+            "announcement": true,
+            "announce": true
+        }"#;
+
+        /// Protocol v2.1 answer to an announce, from a device that doesn't
+        /// know its model or type.
+        pub const V2_1_REPLY_UNKNOWN_DEVICE: &str = r#"{
+            "alias": "Quiet Cherry",
+            "version": "2.1",
+            "deviceModel": null,
+            "deviceType": null,
+            "fingerprint": "0e9d8c7b6a5f4e3d2c1b0a9f8e7d6c5b4a3f2e1d0c9b8a7f6e5d4c3b2a1f0e9d",
+            "port": 53318,
+            "protocol": "https",
+            "download": false,
+            "announcement": false,
+            "announce": false
+        }"#;
+    }
+
+    fn parse(json: &str) -> NodeDevice {
+        NodeDevice::from_announce(&NodeAnnounce::parse(json).unwrap(), "192.168.1.7")
+    }
+
+    fn assert_device(actual: NodeDevice, expected: NodeDevice) {
+        assert!(
+            actual.same_details(&expected),
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn parses_v1_desktop() {
+        let device = parse(fixtures::V1_DESKTOP);
+        assert_device(
+            device.clone(),
+            NodeDevice {
+                alias: "Secret Banana".to_string(),
+                version: PROTOCOL_VERSION_V1.to_string(),
+                device_model: "Windows".to_string(),
+                device_type: "desktop".to_string(),
+                fingerprint: "a4d5e0c2-6c1b-4d3f-9f0e-2b7c8e1f5a90".to_string(),
+                address: "192.168.1.7".to_string(),
+                port: 53317,
+                protocol: "http".to_string(),
+                download: false,
+                announcement: true,
+                announce: false,
+            },
+        );
+        assert!(NodeAnnounce::from(&device).is_announcement());
+    }
+
+    #[test]
+    fn parses_v2_0_mobile() {
+        assert_device(
+            parse(fixtures::V2_0_MOBILE_HTTP),
+            NodeDevice {
+                alias: "Smart Mango".to_string(),
+                version: "2.0".to_string(),
+                device_model: "Samsung".to_string(),
+                device_type: "mobile".to_string(),
+                fingerprint: "6f0c9d6e-3a48-4b0c-8d6a-1e5b2f7c9a13".to_string(),
+                address: "192.168.1.7".to_string(),
+                port: 53317,
+                protocol: "http".to_string(),
+                download: false,
+                announcement: true,
+                announce: true,
+            },
+        );
+    }
+
+    fn v2_1_desktop() -> NodeDevice {
+        NodeDevice {
+            alias: "Fresh Pineapple".to_string(),
+            version: "2.1".to_string(),
+            device_model: "macOS".to_string(),
+            device_type: "desktop".to_string(),
+            fingerprint: "3c8b2a0f9d71e6c45b0a8f2e7d19c3b6a5e4f0d28c7b1a9e6f3d2c0b8a7e5f41"
+                .to_string(),
+            address: "192.168.1.7".to_string(),
+            port: 53317,
+            protocol: "https".to_string(),
+            download: true,
+            announcement: true,
+            announce: true,
+        }
+    }
+
+    #[test]
+    fn parses_v2_1_desktop_over_https() {
+        assert_device(parse(fixtures::V2_1_DESKTOP_HTTPS), v2_1_desktop());
+    }
+
+    #[test]
+    fn parses_reply_with_null_model_and_type() {
+        let device = parse(fixtures::V2_1_REPLY_UNKNOWN_DEVICE);
+        assert_device(
+            device.clone(),
+            NodeDevice {
+                alias: "Quiet Cherry".to_string(),
+                version: "2.1".to_string(),
+                device_model: String::new(),
+                device_type: "unknown".to_string(),
+                fingerprint: "0e9d8c7b6a5f4e3d2c1b0a9f8e7d6c5b4a3f2e1d0c9b8a7f6e5d4c3b2a1f0e9d"
+                    .to_string(),
+                address: "192.168.1.7".to_string(),
+                port: 53318,
+                protocol: "https".to_string(),
+                download: false,
+                announcement: false,
+                announce: false,
+            },
+        );
+        assert!(!NodeAnnounce::from(&device).is_announcement());
+    }
+
+    #[test]
+    fn parses_snake_case_names() {
+        let snake = fixtures::V2_1_DESKTOP_HTTPS
+            .replace("deviceModel", "device_model")
+            .replace("deviceType", "device_type");
+        assert_device(parse(&snake), v2_1_desktop());
+    }
+
+    #[test]
+    fn parses_either_announce_flag_alone() {
+        let only_announce = fixtures::V2_1_DESKTOP_HTTPS.replace("\"announcement\": true,", "");
+        let device = parse(&only_announce);
+        assert_device(
+            device.clone(),
+            NodeDevice {
+                announcement: false,
+                ..v2_1_desktop()
+            },
+        );
+        assert!(NodeAnnounce::from(&device).is_announcement());
+
+        let only_announcement = fixtures::V2_1_DESKTOP_HTTPS
+            .replace("\"announcement\": true,", "")
+            .replace("\"announce\"", "\"announcement\"");
+        let device = parse(&only_announcement);
+        assert_device(
+            device.clone(),
+            NodeDevice {
+                announce: false,
+                ..v2_1_desktop()
+            },
+        );
+        assert!(NodeAnnounce::from(&device).is_announcement());
+    }
+
+    #[test]
+    fn defaults_missing_model_type_and_flags() {
+        let bare = r#"{
+            "alias": "Fresh Pineapple",
+            "version": "2.1",
+            "fingerprint": "3c8b2a0f9d71e6c45b0a8f2e7d19c3b6a5e4f0d28c7b1a9e6f3d2c0b8a7e5f41",
+            "port": 53317,
+            "protocol": "https"
+        }"#;
+        assert_device(
+            parse(bare),
+            NodeDevice {
+                device_model: String::new(),
+                device_type: "unknown".to_string(),
+                download: false,
+                announcement: false,
+                announce: false,
+                ..v2_1_desktop()
+            },
+        );
+    }
+
+    #[test]
+    fn rejects_broken_v2_announce() {
+        // a version means v2, no falling back to v1 for the missing port
+        let json = fixtures::V2_0_MOBILE_HTTP.replace("\"port\": 53317,", "");
+        assert!(NodeAnnounce::parse(&json).is_err());
+    }
+}
@@ -0,0 +1,570 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, Cursor, Read, SeekFrom},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::debug;
+use parking_lot::Mutex;
+use rustls::ClientConfig;
+use tokio::{io::AsyncSeekExt, net::TcpStream, sync::watch, time};
+
+use crate::{
+    api::model::{Compression, FileInfo, FileRequest, FileResponse, UploadOffset},
+    tls,
+    util::{sha256_prefix, ProgressRead, ThrottledRead, TokenBucket},
+};
+
+use super::{
+    core::CoreActorHandle,
+    mission::transfer::{self, TransferEvent},
+    model::{api_url, normalize_protocol, NodeDevice, API_PATH},
+};
+
+const SEND_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// `file_type` of files we don't know better about.
+const DEFAULT_FILE_TYPE: &str = "application/octet-stream";
+
+/// A local file to send and the manifest entry announcing it.
+#[derive(Debug, Clone)]
+pub struct OutgoingFile {
+    pub path: PathBuf,
+    pub info: FileInfo,
+}
+
+/// Lists every file below `root` for sending the folder. Names are `/`
+/// separated and start with the folder's own name, so the receiver
+/// recreates it. Symlinks are skipped unless `follow_symlinks` is set, then
+/// each directory is still only entered once.
+pub async fn folder_files(root: &Path, follow_symlinks: bool) -> io::Result<Vec<OutgoingFile>> {
+    let base = root
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "folder has no name"))?;
+
+    let mut files = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![(root.to_path_buf(), base)];
+    while let Some((dir, name)) = pending.pop() {
+        if !visited.insert(tokio::fs::canonicalize(&dir).await?) {
+            continue;
+        }
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let entry_name = format!("{}/{}", name, entry.file_name().to_string_lossy());
+            let mut metadata = tokio::fs::symlink_metadata(&path).await?;
+            if metadata.file_type().is_symlink() {
+                if !follow_symlinks {
+                    debug!("skipping symlink {:?}", path);
+                    continue;
+                }
+                metadata = match tokio::fs::metadata(&path).await {
+                    Ok(metadata) => metadata,
+                    Err(err) => {
+                        debug!("skipping broken symlink {:?}: {}", path, err);
+                        continue;
+                    }
+                };
+            }
+            if metadata.is_dir() {
+                pending.push((path, entry_name));
+            } else if metadata.is_file() {
+                let info = FileInfo {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    file_name: entry_name,
+                    size: metadata.len() as i64,
+                    file_type: DEFAULT_FILE_TYPE.to_string(),
+                    sha256: None,
+                    preview: None,
+                };
+                files.push(OutgoingFile { path, info });
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// The `files` of a prepare-upload request announcing `files`.
+pub fn manifest(files: &[OutgoingFile]) -> HashMap<String, FileInfo> {
+    files
+        .iter()
+        .map(|file| (file.info.id.clone(), file.info.clone()))
+        .collect()
+}
+
+/// Why a request to a peer failed.
+#[derive(Debug)]
+enum SendError {
+    /// The peer answered with an error status.
+    Status(u16, String),
+    /// The connection failed or dropped, worth retrying.
+    Transport(String),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Status(code, msg) => write!(f, "peer answered {}: {}", code, msg),
+            SendError::Transport(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl From<ureq::Error> for SendError {
+    fn from(err: ureq::Error) -> Self {
+        match err {
+            ureq::Error::Status(code, response) => {
+                SendError::Status(code, response.into_string().unwrap_or_default())
+            }
+            ureq::Error::Transport(transport) => SendError::Transport(transport.to_string()),
+        }
+    }
+}
+
+type Query = Vec<(&'static str, String)>;
+
+/// Where a prepared session gets uploaded to. Every upload of the session
+/// draws from the same `throttle`, so they share the configured rate.
+/// Uploads are compressed once the peer agreed to `compression`.
+struct Peer {
+    protocol: String,
+    addr: SocketAddr,
+    tls: Option<Arc<ClientConfig>>,
+    throttle: Option<Arc<Mutex<TokenBucket>>>,
+    headers: Vec<(String, String)>,
+    compression: Option<Compression>,
+    /// Passed to prepare-upload, for peers that require a pin.
+    pin: Option<String>,
+    /// Set once the session is registered, see `OutgoingSessions`.
+    canceled: Option<watch::Receiver<bool>>,
+}
+
+impl Peer {
+    fn new(
+        target: &NodeDevice,
+        insecure_tls: bool,
+        max_upload_bytes_per_sec: Option<u64>,
+        headers: Vec<(String, String)>,
+    ) -> Result<Self, String> {
+        let addr = target
+            .socket_addr()
+            .ok_or_else(|| format!("invalid address {}", target.address))?;
+        let protocol = normalize_protocol(&target.protocol);
+        // https peers must present the certificate their fingerprint is the hash of
+        let tls = (protocol == "https")
+            .then(|| tls::client_config(Some(target.fingerprint.clone()), insecure_tls));
+        Ok(Peer {
+            protocol: protocol.to_string(),
+            addr,
+            tls,
+            throttle: max_upload_bytes_per_sec
+                .filter(|rate| *rate > 0)
+                .map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate)))),
+            headers,
+            compression: None,
+            pin: None,
+            canceled: None,
+        })
+    }
+
+    fn is_canceled(&self) -> bool {
+        self.canceled
+            .as_ref()
+            .is_some_and(|canceled| *canceled.borrow())
+    }
+
+    /// Posts `body` as the content of an upload, compressed when agreed on
+    /// and throttled when a rate is configured. The rate applies to the
+    /// compressed bytes.
+    async fn upload(&self, query: Query, body: Box<dyn Read + Send>) -> Result<String, SendError> {
+        let body: Box<dyn Read + Send> = match self.compression {
+            Some(Compression::Gzip) => Box::new(flate2::read::GzEncoder::new(
+                body,
+                flate2::Compression::fast(),
+            )),
+            None => body,
+        };
+        let body: Box<dyn Read + Send> = match &self.canceled {
+            Some(canceled) => Box::new(CancelableRead {
+                inner: body,
+                canceled: canceled.clone(),
+            }),
+            None => body,
+        };
+        let body: Box<dyn Read + Send> = match &self.throttle {
+            Some(bucket) => Box::new(ThrottledRead::new(body, bucket.clone())),
+            None => body,
+        };
+        let body = (body, "application/octet-stream");
+        self.request("POST", "/upload", query, Some(body), self.compression)
+            .await
+    }
+
+    /// Tells the peer we canceled the session.
+    async fn cancel(&self, session_id: &str) -> Result<String, SendError> {
+        let query = vec![("sessionId", session_id.to_string())];
+        self.request("POST", "/cancel", query, None, None).await
+    }
+
+    /// Asks how much of an interrupted upload the peer kept.
+    async fn probe(&self, query: Query) -> Result<UploadOffset, SendError> {
+        let body = self.request("GET", "/upload", query, None, None).await?;
+        serde_json::from_str(&body).map_err(|err| SendError::Transport(err.to_string()))
+    }
+
+    async fn post_json(
+        &self,
+        endpoint: &str,
+        query: Query,
+        message: String,
+    ) -> Result<String, SendError> {
+        let body: Box<dyn Read + Send> = Box::new(Cursor::new(message));
+        let body = (body, "application/json");
+        self.request("POST", endpoint, query, Some(body), None)
+            .await
+    }
+
+    /// Sends a request to `endpoint`, with a body of the given content type
+    /// and encoding, and returns the response body. There is no read
+    /// timeout, prepare-upload only answers once the user decided.
+    async fn request(
+        &self,
+        method: &'static str,
+        endpoint: &str,
+        query: Query,
+        body: Option<(Box<dyn Read + Send>, &'static str)>,
+        encoding: Option<Compression>,
+    ) -> Result<String, SendError> {
+        let api = api_url(&self.protocol, self.addr, API_PATH, endpoint);
+        let addr = self.addr;
+        let tls = self.tls.clone();
+        let headers = self.headers.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut builder = ureq::AgentBuilder::new()
+                .resolver(move |_: &str| Ok(vec![addr]))
+                .timeout_connect(SEND_CONNECT_TIMEOUT);
+            if let Some(tls) = tls {
+                builder = builder.tls_config(tls);
+            }
+            let mut request = builder.build().request(method, &api);
+            for (key, value) in &query {
+                request = request.query(key, value);
+            }
+            for (name, value) in &headers {
+                request = request.set(name, value);
+            }
+            if let Some(encoding) = encoding {
+                request = request.set("Content-Encoding", encoding.name());
+            }
+            let response = match body {
+                Some((body, content_type)) => {
+                    request.set("Content-Type", content_type).send(body)?
+                }
+                None => request.call()?,
+            };
+            response
+                .into_string()
+                .map_err(|err| SendError::Transport(err.to_string()))
+        })
+        .await
+        .map_err(|err| SendError::Transport(err.to_string()))?
+    }
+
+    /// Prepares a session for `files`, proposing `compression`, and returns
+    /// the peer's answer. Uploads are compressed from then on if the peer
+    /// agreed.
+    async fn prepare(
+        &mut self,
+        current: &NodeDevice,
+        files: HashMap<String, FileInfo>,
+        compression: Option<Compression>,
+    ) -> Result<FileResponse, String> {
+        let request = FileRequest {
+            info: current.to_sender_info(),
+            files,
+            compression: compression.map(|compression| compression.name().to_string()),
+        };
+        let message = serde_json::to_string(&request).map_err(|err| err.to_string())?;
+        let query = self.pin.iter().map(|pin| ("pin", pin.clone())).collect();
+        let body = self
+            .post_json("/prepare-upload", query, message)
+            .await
+            .map_err(|err| err.to_string())?;
+        let response: FileResponse = serde_json::from_str(&body).map_err(|err| err.to_string())?;
+        self.compression = compression.filter(|compression| {
+            response.compression.as_deref().and_then(Compression::parse) == Some(*compression)
+        });
+        if compression.is_some() && self.compression.is_none() {
+            debug!("peer declined compression, sending as is");
+        }
+        Ok(response)
+    }
+}
+
+/// `target` at the first of its known addresses taking connections, most
+/// recently seen first, for peers on several networks. Targets with a
+/// single address, or one that isn't among the known ones, are kept as
+/// they are, as is `target` when none of them answers.
+async fn reachable(core: &CoreActorHandle, target: &NodeDevice) -> NodeDevice {
+    let addresses = core
+        .device
+        .get_device_addresses(target.fingerprint.clone())
+        .await;
+    if addresses.len() < 2 || !addresses.contains(&target.address) {
+        return target.clone();
+    }
+    for address in addresses {
+        let candidate = NodeDevice {
+            address,
+            ..target.clone()
+        };
+        let addr = match candidate.socket_addr() {
+            Some(addr) => addr,
+            None => continue,
+        };
+        match time::timeout(SEND_CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+            Ok(Ok(_)) => return candidate,
+            _ => debug!("{} not reachable at {}", target, addr),
+        }
+    }
+    target.clone()
+}
+
+async fn connect(core: &CoreActorHandle, target: &NodeDevice) -> Result<Peer, String> {
+    let discovery = core.get_discovery_config().await;
+    let send = core.get_send_config().await;
+    let target = reachable(core, target).await;
+    let mut peer = Peer::new(
+        &target,
+        discovery.insecure_tls,
+        send.max_upload_bytes_per_sec,
+        discovery.headers.pairs(),
+    )?;
+    peer.pin = send.pin;
+    Ok(peer)
+}
+
+/// Sends `text` to `target` as a text message: a session with a single
+/// `text` item, whose upload body is the text itself. Returns once the peer
+/// accepted and received it.
+pub async fn send_text(
+    core: &CoreActorHandle,
+    target: &NodeDevice,
+    text: String,
+) -> Result<(), String> {
+    let info = FileInfo::from_text(&text)?;
+    let file_id = info.id.clone();
+    let current = core.device.get_current_device().await;
+    let mut peer = connect(core, target).await?;
+
+    let files = HashMap::from([(file_id.clone(), info)]);
+    let response = peer.prepare(&current, files, None).await?;
+    let token = response
+        .files
+        .get(&file_id)
+        .cloned()
+        .ok_or_else(|| "peer didn't accept the text".to_string())?;
+    debug!("text session {} accepted", response.session_id);
+
+    let query = vec![
+        ("sessionId", response.session_id),
+        ("fileId", file_id),
+        ("token", token),
+    ];
+    let body = Box::new(Cursor::new(text.into_bytes()));
+    peer.upload(query, body)
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(())
+}
+
+const UPLOAD_ATTEMPTS: u32 = 5;
+const SESSION_CANCELED: &str = "session canceled";
+const RESUME_DELAY: Duration = Duration::from_secs(1);
+
+/// Sends `files` to `target` in one session. Files the peer didn't accept
+/// are skipped. An upload whose connection drops is resumed where the peer
+/// left off, up to `UPLOAD_ATTEMPTS` times. Progress and the outcome of
+/// each file go out as [`TransferEvent`]s like those of received files.
+pub async fn send_files(
+    core: &CoreActorHandle,
+    target: &NodeDevice,
+    files: Vec<OutgoingFile>,
+) -> Result<(), String> {
+    send_files_with_compression(core, target, files, None).await
+}
+
+/// [`send_files`], compressing the uploads with `compression` if the peer
+/// supports it. Worth it for text and logs, not for media that's
+/// compressed already.
+pub async fn send_files_with_compression(
+    core: &CoreActorHandle,
+    target: &NodeDevice,
+    files: Vec<OutgoingFile>,
+    compression: Option<Compression>,
+) -> Result<(), String> {
+    let current = core.device.get_current_device().await;
+    let mut peer = connect(core, target).await?;
+    let response = peer
+        .prepare(&current, manifest(&files), compression)
+        .await?;
+    let session = core.mission.outgoing.register(response.session_id.clone());
+    let mut canceled = session.canceled.clone();
+    peer.canceled = Some(session.canceled.clone());
+
+    let transfer = &core.mission.transfer;
+    let session_id = &response.session_id;
+    let uploads = async {
+        for file in &files {
+            let Some(token) = response.files.get(&file.info.id) else {
+                debug!("{} not accepted, skipping", file.info.file_name);
+                continue;
+            };
+            let result = upload_file(&peer, transfer, session_id, file, token).await;
+            let session_id = session_id.clone();
+            let file_id = file.info.id.clone();
+            let event = match &result {
+                Ok(()) => TransferEvent::Completed {
+                    session_id,
+                    file_id,
+                },
+                Err(msg) => TransferEvent::Failed {
+                    session_id,
+                    file_id,
+                    msg: msg.clone(),
+                },
+            };
+            transfer.emit(event).await;
+            result?;
+        }
+        let session_id = session_id.clone();
+        transfer
+            .emit(TransferEvent::SessionCompleted { session_id })
+            .await;
+        Ok(())
+    };
+    let result = tokio::select! {
+        result = uploads => result,
+        _ = canceled.wait_for(|canceled| *canceled) => Err(SESSION_CANCELED.to_string()),
+    };
+    if peer.is_canceled() {
+        debug!("session {} canceled", response.session_id);
+        if let Err(err) = peer.cancel(&response.session_id).await {
+            debug!("telling {} about the cancel failed: {}", target, err);
+        }
+        return Err(SESSION_CANCELED.to_string());
+    }
+    result
+}
+
+async fn upload_file(
+    peer: &Peer,
+    transfer: &transfer::Handle,
+    session_id: &str,
+    file: &OutgoingFile,
+    token: &str,
+) -> Result<(), String> {
+    let query = |offset: u64| {
+        vec![
+            ("sessionId", session_id.to_string()),
+            ("fileId", file.info.id.clone()),
+            ("token", token.to_string()),
+            ("offset", offset.to_string()),
+        ]
+    };
+
+    let mut offset = 0;
+    for attempt in 1..=UPLOAD_ATTEMPTS {
+        if attempt > 1 {
+            // give the peer a moment to notice the dropped connection
+            time::sleep(RESUME_DELAY).await;
+            offset = match peer.probe(query(0)).await {
+                Ok(kept) => resume_offset(file, kept).await,
+                Err(SendError::Transport(err)) => {
+                    debug!("probing {} failed: {}", file.info.file_name, err);
+                    continue;
+                }
+                Err(err) => return Err(err.to_string()),
+            };
+        }
+
+        let mut reader = tokio::fs::File::open(&file.path)
+            .await
+            .map_err(|err| err.to_string())?;
+        reader
+            .seek(SeekFrom::Start(offset))
+            .await
+            .map_err(|err| err.to_string())?;
+        // read on the blocking pool along with the request
+        let reader = reader.into_std().await;
+        let progress = transfer
+            .track_upload(
+                session_id.to_string(),
+                file.info.id.clone(),
+                file.info.size.max(0) as u64,
+            )
+            .await;
+        let reader = ProgressRead::new(reader, progress).starting_at(offset as usize);
+        match peer.upload(query(offset), Box::new(reader)).await {
+            Ok(_) => return Ok(()),
+            Err(SendError::Transport(_)) if peer.is_canceled() => {
+                return Err(SESSION_CANCELED.to_string());
+            }
+            Err(SendError::Transport(err)) => {
+                debug!(
+                    "upload of {} dropped at attempt {}: {}",
+                    file.info.file_name, attempt, err
+                );
+            }
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+    Err(format!(
+        "upload of {} failed {} times",
+        file.info.file_name, UPLOAD_ATTEMPTS
+    ))
+}
+
+/// Fails reads once the session is canceled, so an upload running on the
+/// blocking pool stops instead of sending the rest of the file.
+struct CancelableRead<R> {
+    inner: R,
+    canceled: watch::Receiver<bool>,
+}
+
+impl<R: Read> Read for CancelableRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if *self.canceled.borrow() {
+            return Err(io::Error::other(SESSION_CANCELED));
+        }
+        self.inner.read(buf)
+    }
+}
+
+/// Where to continue given what the peer kept, which is only trusted when
+/// it matches the start of our file.
+async fn resume_offset(file: &OutgoingFile, kept: UploadOffset) -> u64 {
+    if kept.offset == 0 || kept.offset > file.info.size.max(0) as u64 {
+        return 0;
+    }
+    match sha256_prefix(&file.path, kept.offset).await {
+        Ok(ours) if kept.sha256.as_deref() == Some(ours.as_str()) => {
+            debug!("resuming {} at {}", file.info.file_name, kept.offset);
+            kept.offset
+        }
+        _ => {
+            debug!(
+                "{} kept by the peer doesn't match, restarting",
+                file.info.file_name
+            );
+            0
+        }
+    }
+}
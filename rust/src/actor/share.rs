@@ -0,0 +1,332 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::api::model::FileInfo;
+
+use super::send::OutgoingFile;
+
+/// Download sessions open at once. Past it the one used longest ago is
+/// closed.
+const MAX_SESSIONS: usize = 64;
+
+/// Download sessions unused for this long are closed.
+const SESSION_IDLE: Duration = Duration::from_secs(60 * 60);
+
+/// Files currently offered for download, with the pin guarding them and the
+/// sessions peers opened through prepare-download, with when each was last
+/// used.
+struct Offer {
+    files: HashMap<String, OutgoingFile>,
+    pin: Option<String>,
+    sessions: HashMap<String, Instant>,
+}
+
+impl Offer {
+    fn is_open(&self, session_id: &str, now: Instant) -> bool {
+        self.sessions
+            .get(session_id)
+            .is_some_and(|used| now.duration_since(*used) < SESSION_IDLE)
+    }
+
+    /// Closes idle sessions, and the one used longest ago when there's no
+    /// room for another.
+    fn make_room(&mut self, now: Instant) {
+        self.sessions
+            .retain(|_, used| now.duration_since(*used) < SESSION_IDLE);
+        if self.sessions.len() >= MAX_SESSIONS {
+            let oldest = self
+                .sessions
+                .iter()
+                .min_by_key(|(_, used)| **used)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                debug!("too many download sessions, closing {}", oldest);
+                self.sessions.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// What a peer gets to see of the offer before it has a session.
+#[derive(Debug, Clone)]
+pub struct OfferSummary {
+    pub pin: Option<String>,
+    pub files: HashMap<String, FileInfo>,
+}
+
+struct ShareActor {
+    receiver: mpsc::Receiver<ShareMessage>,
+    offer: Option<Offer>,
+}
+
+enum ShareMessage {
+    Offer {
+        files: Vec<OutgoingFile>,
+        pin: Option<String>,
+        respond_to: oneshot::Sender<()>,
+    },
+    Revoke {
+        respond_to: oneshot::Sender<bool>,
+    },
+    Summary {
+        respond_to: oneshot::Sender<Option<OfferSummary>>,
+    },
+    OpenSession {
+        session_id: Option<String>,
+        respond_to: oneshot::Sender<Option<String>>,
+    },
+    Resolve {
+        session_id: String,
+        file_id: String,
+        respond_to: oneshot::Sender<Option<OutgoingFile>>,
+    },
+}
+
+impl ShareActor {
+    fn new(receiver: mpsc::Receiver<ShareMessage>) -> Self {
+        ShareActor {
+            receiver,
+            offer: None,
+        }
+    }
+
+    fn handle_message(&mut self, msg: ShareMessage) {
+        match msg {
+            ShareMessage::Offer {
+                files,
+                pin,
+                respond_to,
+            } => {
+                let files = files
+                    .into_iter()
+                    .map(|file| (file.info.id.clone(), file))
+                    .collect::<HashMap<_, _>>();
+                debug!("offering {} files", files.len());
+                // sessions of an earlier offer don't carry over
+                self.offer = Some(Offer {
+                    files,
+                    pin,
+                    sessions: HashMap::new(),
+                });
+                let _ = respond_to.send(());
+            }
+            ShareMessage::Revoke { respond_to } => {
+                let _ = respond_to.send(self.offer.take().is_some());
+            }
+            ShareMessage::Summary { respond_to } => {
+                let summary = self.offer.as_ref().map(|offer| OfferSummary {
+                    pin: offer.pin.clone(),
+                    files: offer
+                        .files
+                        .iter()
+                        .map(|(id, file)| (id.clone(), file.info.clone()))
+                        .collect(),
+                });
+                let _ = respond_to.send(summary);
+            }
+            ShareMessage::OpenSession {
+                session_id,
+                respond_to,
+            } => {
+                let now = Instant::now();
+                let session = self.offer.as_mut().map(|offer| {
+                    let open = session_id.filter(|id| offer.is_open(id, now));
+                    let id = open.unwrap_or_else(|| {
+                        offer.make_room(now);
+                        uuid::Uuid::new_v4().to_string()
+                    });
+                    offer.sessions.insert(id.clone(), now);
+                    id
+                });
+                let _ = respond_to.send(session);
+            }
+            ShareMessage::Resolve {
+                session_id,
+                file_id,
+                respond_to,
+            } => {
+                let now = Instant::now();
+                let file = self
+                    .offer
+                    .as_mut()
+                    .filter(|offer| offer.is_open(&session_id, now))
+                    .and_then(|offer| {
+                        offer.sessions.insert(session_id, now);
+                        offer.files.get(&file_id).cloned()
+                    });
+                let _ = respond_to.send(file);
+            }
+        }
+    }
+}
+
+async fn run_share_actor(mut actor: ShareActor) {
+    while let Some(msg) = actor.receiver.recv().await {
+        actor.handle_message(msg);
+    }
+}
+
+/// Files offered for peers to pull through prepare-download and download,
+/// the reverse of them pushing files to us.
+#[derive(Clone)]
+pub struct ShareHandle {
+    sender: mpsc::Sender<ShareMessage>,
+}
+
+impl Default for ShareHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShareHandle {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel(8);
+        let actor = ShareActor::new(receiver);
+        tokio::spawn(run_share_actor(actor));
+
+        Self { sender }
+    }
+
+    /// Offers `files`, replacing any earlier offer. With a `pin`, peers have
+    /// to pass it to prepare-download.
+    pub async fn offer(&self, files: Vec<OutgoingFile>, pin: Option<String>) {
+        let (send, recv) = oneshot::channel();
+        let msg = ShareMessage::Offer {
+            files,
+            pin,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Withdraws the offer, also ending the sessions opened for it. Returns
+    /// whether anything was offered.
+    pub async fn revoke(&self) -> bool {
+        let (send, recv) = oneshot::channel();
+        let msg = ShareMessage::Revoke { respond_to: send };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// The files on offer, empty when there's no offer.
+    pub async fn list(&self) -> Vec<FileInfo> {
+        self.summary()
+            .await
+            .map(|summary| summary.files.into_values().collect())
+            .unwrap_or_default()
+    }
+
+    pub async fn summary(&self) -> Option<OfferSummary> {
+        let (send, recv) = oneshot::channel();
+        let msg = ShareMessage::Summary { respond_to: send };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// Opens a download session, or keeps `session_id` when it's open
+    /// already. `None` when nothing is offered. Sessions close after an
+    /// hour unused, and past `MAX_SESSIONS` the one used longest ago makes
+    /// room.
+    pub async fn open_session(&self, session_id: Option<String>) -> Option<String> {
+        let (send, recv) = oneshot::channel();
+        let msg = ShareMessage::OpenSession {
+            session_id,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+
+    /// The offered file `file_id`, if `session_id` is open.
+    pub async fn resolve(&self, session_id: String, file_id: String) -> Option<OutgoingFile> {
+        let (send, recv) = oneshot::channel();
+        let msg = ShareMessage::Resolve {
+            session_id,
+            file_id,
+            respond_to: send,
+        };
+
+        let _ = self.sender.send(msg).await;
+        recv.await.expect("Actor task has been killed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offer() -> Offer {
+        let file = OutgoingFile {
+            path: "/tmp/a.txt".into(),
+            info: FileInfo::from_text("hello").unwrap(),
+        };
+        Offer {
+            files: vec![(file.info.id.clone(), file)].into_iter().collect(),
+            pin: None,
+            sessions: HashMap::new(),
+        }
+    }
+
+    fn open(actor: &mut ShareActor, session_id: Option<String>) -> Option<String> {
+        let (send, mut recv) = oneshot::channel();
+        actor.handle_message(ShareMessage::OpenSession {
+            session_id,
+            respond_to: send,
+        });
+        recv.try_recv().unwrap()
+    }
+
+    fn actor(offer: Offer) -> ShareActor {
+        let (_, receiver) = mpsc::channel(1);
+        ShareActor {
+            receiver,
+            offer: Some(offer),
+        }
+    }
+
+    #[test]
+    fn sessions_are_capped() {
+        let mut actor = actor(offer());
+        let first = open(&mut actor, None).unwrap();
+        assert_eq!(open(&mut actor, Some(first.clone())), Some(first.clone()));
+        for _ in 1..MAX_SESSIONS {
+            open(&mut actor, None).unwrap();
+        }
+        let sessions = &actor.offer.as_ref().unwrap().sessions;
+        assert_eq!(sessions.len(), MAX_SESSIONS);
+        assert!(sessions.contains_key(&first));
+
+        let last = open(&mut actor, None).unwrap();
+        let sessions = &actor.offer.as_ref().unwrap().sessions;
+        assert_eq!(sessions.len(), MAX_SESSIONS);
+        assert!(sessions.contains_key(&last));
+        assert!(!sessions.contains_key(&first));
+        assert_ne!(open(&mut actor, Some(first.clone())), Some(first));
+    }
+
+    #[test]
+    fn idle_sessions_close() {
+        let mut offer = offer();
+        let now = Instant::now();
+        offer.sessions.insert("idle".to_string(), now);
+        let later = now + SESSION_IDLE;
+        offer.sessions.insert("used".to_string(), later);
+        assert!(!offer.is_open("idle", later));
+        assert!(offer.is_open("used", later));
+
+        offer.make_room(later);
+        assert!(!offer.sessions.contains_key("idle"));
+        assert!(offer.sessions.contains_key("used"));
+    }
+}
@@ -0,0 +1,229 @@
+use std::{fmt, io};
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::actor::mission::transfer::TaskError;
+
+/// Why a transfer request failed. Answered with the status code the
+/// LocalSend app expects and a `{ "message": ... }` body it can show.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransferError {
+    /// A pin is set and the request came without one.
+    PinRequired,
+    InvalidPin,
+    /// Too many wrong pins came from the sender's address lately.
+    PinLocked,
+    /// Malformed request, like an invalid manifest or a hash mismatch.
+    InvalidRequest(String),
+    /// The sender never registered with us.
+    NotRegistered,
+    /// Declined by the user or the transfer policy, or nothing is offered.
+    Rejected,
+    /// Another session is running.
+    Busy,
+    TooManySessions,
+    /// The files don't fit with the configured margin to spare.
+    InsufficientStorage {
+        needed: u64,
+        free: u64,
+    },
+    /// The session, file or token doesn't belong to a running session.
+    InvalidToken,
+    Canceled,
+    /// An interrupted upload can't continue at `offset`, `kept` bytes of it
+    /// are left.
+    ResumeConflict {
+        offset: u64,
+        kept: u64,
+    },
+    Internal(String),
+}
+
+impl TransferError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            TransferError::PinRequired | TransferError::InvalidPin => StatusCode::UNAUTHORIZED,
+            TransferError::PinLocked | TransferError::TooManySessions => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            TransferError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            TransferError::NotRegistered
+            | TransferError::Rejected
+            | TransferError::InvalidToken
+            | TransferError::Canceled => StatusCode::FORBIDDEN,
+            TransferError::Busy | TransferError::ResumeConflict { .. } => StatusCode::CONFLICT,
+            TransferError::InsufficientStorage { .. } => StatusCode::INSUFFICIENT_STORAGE,
+            TransferError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::PinRequired => write!(f, "pin required"),
+            TransferError::InvalidPin => write!(f, "invalid pin"),
+            TransferError::PinLocked => write!(f, "too many wrong pins, try again later"),
+            TransferError::InvalidRequest(msg) => write!(f, "{}", msg),
+            TransferError::NotRegistered => write!(f, "device not registered"),
+            TransferError::Rejected => write!(f, "rejected"),
+            TransferError::Busy => write!(f, "blocked by another session"),
+            TransferError::TooManySessions => write!(f, "too many sessions, try again later"),
+            TransferError::InsufficientStorage { needed, free } => write!(
+                f,
+                "not enough space, {} bytes needed, {} free",
+                needed, free
+            ),
+            TransferError::InvalidToken => write!(f, "invalid token"),
+            TransferError::Canceled => write!(f, "session canceled"),
+            TransferError::ResumeConflict { offset, kept } => {
+                write!(f, "can't resume at {}, {} bytes kept", offset, kept)
+            }
+            TransferError::Internal(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for TransferError {}
+
+impl IntoResponse for TransferError {
+    fn into_response(self) -> Response {
+        let body = Json(json!({ "message": self.to_string() }));
+        (self.status(), body).into_response()
+    }
+}
+
+impl From<TaskError> for TransferError {
+    fn from(err: TaskError) -> Self {
+        match err {
+            TaskError::NoSession | TaskError::InvalidToken => TransferError::InvalidToken,
+        }
+    }
+}
+
+impl From<io::Error> for TransferError {
+    fn from(err: io::Error) -> Self {
+        TransferError::Internal(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn respond(err: TransferError) -> (StatusCode, serde_json::Value) {
+        let response = err.into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    async fn assert_response(err: TransferError, status: StatusCode, message: &str) {
+        assert_eq!(err.status(), status, "{:?}", err);
+        let (actual, body) = respond(err.clone()).await;
+        assert_eq!(actual, status, "{:?}", err);
+        assert_eq!(body, json!({ "message": message }), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn pin_errors() {
+        use StatusCode as S;
+        assert_response(TransferError::PinRequired, S::UNAUTHORIZED, "pin required").await;
+        assert_response(TransferError::InvalidPin, S::UNAUTHORIZED, "invalid pin").await;
+        assert_response(
+            TransferError::PinLocked,
+            S::TOO_MANY_REQUESTS,
+            "too many wrong pins, try again later",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn request_errors() {
+        use StatusCode as S;
+        assert_response(
+            TransferError::InvalidRequest("bad manifest".to_string()),
+            S::BAD_REQUEST,
+            "bad manifest",
+        )
+        .await;
+        assert_response(
+            TransferError::NotRegistered,
+            S::FORBIDDEN,
+            "device not registered",
+        )
+        .await;
+        assert_response(TransferError::Rejected, S::FORBIDDEN, "rejected").await;
+        assert_response(TransferError::InvalidToken, S::FORBIDDEN, "invalid token").await;
+        assert_response(TransferError::Canceled, S::FORBIDDEN, "session canceled").await;
+    }
+
+    #[tokio::test]
+    async fn session_errors() {
+        use StatusCode as S;
+        assert_response(
+            TransferError::Busy,
+            S::CONFLICT,
+            "blocked by another session",
+        )
+        .await;
+        assert_response(
+            TransferError::TooManySessions,
+            S::TOO_MANY_REQUESTS,
+            "too many sessions, try again later",
+        )
+        .await;
+        assert_response(
+            TransferError::ResumeConflict {
+                offset: 10,
+                kept: 4,
+            },
+            S::CONFLICT,
+            "can't resume at 10, 4 bytes kept",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn server_errors() {
+        use StatusCode as S;
+        assert_response(
+            TransferError::InsufficientStorage {
+                needed: 2048,
+                free: 1024,
+            },
+            S::INSUFFICIENT_STORAGE,
+            "not enough space, 2048 bytes needed, 1024 free",
+        )
+        .await;
+        assert_response(
+            TransferError::Internal("disk on fire".to_string()),
+            S::INTERNAL_SERVER_ERROR,
+            "disk on fire",
+        )
+        .await;
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(
+            TransferError::from(TaskError::NoSession),
+            TransferError::InvalidToken
+        );
+        assert_eq!(
+            TransferError::from(TaskError::InvalidToken),
+            TransferError::InvalidToken
+        );
+        assert_eq!(
+            TransferError::from(io::Error::other("boom")),
+            TransferError::Internal("boom".to_string())
+        );
+    }
+}
@@ -1,7 +1,15 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, io, path::Path};
 
 use serde_derive::{Deserialize, Serialize};
 
+use crate::util::{safe_relative_path, sha256_file};
+
+/// `file_type` of an item carrying a text message instead of a file.
+pub const TEXT_FILE_TYPE: &str = "text";
+
+/// Largest text message we send or accept, in bytes.
+pub const MAX_TEXT_SIZE: usize = 64 * 1024;
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SenderInfo {
@@ -15,10 +23,82 @@ pub struct SenderInfo {
     pub download: bool,
 }
 
+/// Encoding of upload bodies. The sender proposes it in the prepare-upload
+/// manifest and only compresses when the receiver's answer confirms it,
+/// peers not knowing the field ignore it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+}
+
+impl Compression {
+    /// The name in manifests and the `Content-Encoding` of uploads.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Compression> {
+        match name.trim() {
+            name if name.eq_ignore_ascii_case("gzip") => Some(Compression::Gzip),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FileRequest {
     pub info: SenderInfo,
     pub files: HashMap<String, FileInfo>,
+    /// Proposed upload encoding, see [`Compression`]. A string so unknown
+    /// encodings are declined instead of failing the whole manifest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+}
+
+impl FileRequest {
+    /// Checks the manifest of a prepare-upload request before anyone is asked
+    /// to accept it.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.info.fingerprint.is_empty() {
+            return Err("sender fingerprint is missing".to_string());
+        }
+        if self.files.is_empty() {
+            return Err("no files to send".to_string());
+        }
+        for (id, file) in &self.files {
+            if *id != file.id {
+                return Err(format!("file {} is listed under id {}", file.id, id));
+            }
+            if file.file_name.contains('\0') || safe_relative_path(&file.file_name).is_none() {
+                return Err(format!("file {} has an invalid name", id));
+            }
+            if file.size < 0 {
+                return Err(format!("file {} has a negative size", id));
+            }
+            if file.is_text() && file.size as u64 > MAX_TEXT_SIZE as u64 {
+                return Err(format!("text {} is too long", id));
+            }
+            if let Some(hash) = &file.sha256 {
+                if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(format!("file {} has an invalid sha256", id));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Bytes the files of this request take on disk. Text items aren't
+    /// stored and don't count.
+    pub fn total_size(&self) -> u64 {
+        self.files
+            .values()
+            .filter(|file| !file.is_text())
+            .map(|file| file.size.max(0) as u64)
+            .sum()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -32,11 +112,44 @@ pub struct FileInfo {
     pub preview: Option<Vec<u8>>,
 }
 
+impl FileInfo {
+    /// Item for sending `text` as a message. The text travels as the upload
+    /// body and as the preview, which is what receivers show before
+    /// accepting.
+    pub fn from_text(text: &str) -> Result<FileInfo, String> {
+        if text.len() > MAX_TEXT_SIZE {
+            return Err(format!("text is longer than {} bytes", MAX_TEXT_SIZE));
+        }
+        let id = uuid::Uuid::new_v4().to_string();
+        Ok(FileInfo {
+            file_name: format!("{}.txt", id),
+            id,
+            size: text.len() as i64,
+            file_type: TEXT_FILE_TYPE.to_string(),
+            sha256: None,
+            preview: Some(text.as_bytes().to_vec()),
+        })
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.file_type == TEXT_FILE_TYPE
+    }
+
+    /// Sets `sha256` from the file's content, so the receiver can verify it.
+    pub async fn hash_from(&mut self, path: &Path) -> io::Result<()> {
+        self.sha256 = Some(sha256_file(path).await?);
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FileResponse {
     pub session_id: String,
     pub files: HashMap<String, String>,
+    /// The proposed encoding, if we accepted it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -45,4 +158,25 @@ pub struct UploadTask {
     pub session_id: String,
     pub file_id: String,
     pub token: String,
+    /// Where a resumed upload continues, the bytes before it already arrived.
+    #[serde(default)]
+    pub offset: u64,
+}
+
+/// Answer to prepare-download: who is offering and what.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadResponse {
+    pub info: SenderInfo,
+    pub session_id: String,
+    pub files: HashMap<String, FileInfo>,
+}
+
+/// How much of an interrupted upload the receiver kept, with the sha256 of
+/// those bytes so the sender can check they match its file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadOffset {
+    pub offset: u64,
+    pub sha256: Option<String>,
 }
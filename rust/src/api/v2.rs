@@ -1,42 +1,112 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use super::model::{FileRequest, FileResponse, UploadTask};
+use super::error::TransferError;
+use super::model::{
+    Compression, DownloadResponse, FileRequest, FileResponse, SenderInfo, UploadOffset, UploadTask,
+    MAX_TEXT_SIZE,
+};
 use axum::{
-    body::Bytes,
+    body::{self, Body, Bytes},
     extract::{ConnectInfo, Query, Request, State},
-    http::StatusCode,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, post},
     BoxError, Json, Router,
 };
-use futures::{Stream, TryStreamExt};
-use log::debug;
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use log::{debug, warn};
+use parking_lot::Mutex;
+use ring::digest;
 use serde_derive::Deserialize;
 use serde_json::{json, Value};
 use tokio::{
-    fs::File,
-    io::BufWriter,
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
     sync::{mpsc, watch},
+    time,
 };
-use tokio_util::io::StreamReader;
+use tokio_util::io::{ReaderStream, StreamReader};
 
 use crate::{
     actor::{
         core::CoreActorHandle,
-        mission::FileState,
+        discovery::source_allowed,
+        mission::{transfer, FileState, SessionPermit, TransferDecision},
         model::{Mission, MissionState, NodeAnnounce, NodeDevice},
     },
-    util::ProgressWriteAdapter,
+    util::{
+        free_space, part_path, part_target, safe_relative_path, scoped_ip_string, sha256_context,
+        sha256_prefix, unique_part_target, HashWriteAdapter, ProgressWriteAdapter,
+    },
 };
 
 async fn handle_register(
     State(state): State<Arc<AppState>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    Json(payload): Json<NodeAnnounce>,
-) -> Json<NodeAnnounce> {
-    let device = NodeDevice::from_announce(&payload, &addr.ip().to_string());
-    debug!("device {:?}", device);
+    headers: HeaderMap,
+    body: String,
+) -> Result<Json<NodeAnnounce>, (StatusCode, String)> {
+    let discovery_config = state.core.get_discovery_config().await;
+    if discovery_config.observe_only {
+        // answering would give us away, look like there's no endpoint
+        debug!("ignore register from {}, only observing", addr);
+        return Err((StatusCode::NOT_FOUND, String::new()));
+    }
+    if !source_allowed(&discovery_config, addr.ip()) {
+        debug!("reject register from {} outside the allowed subnets", addr);
+        return Err((StatusCode::FORBIDDEN, "source not allowed".to_string()));
+    }
+    if let Some(secret) = &discovery_config.secret {
+        let presented = headers.get(&secret.name).and_then(|v| v.to_str().ok());
+        if presented != Some(secret.value.as_str()) {
+            debug!("reject register from {}, secret mismatch", addr);
+            return Err((StatusCode::FORBIDDEN, "invalid secret".to_string()));
+        }
+    }
+
+    // v1 peers register with their own announce format
+    let payload = NodeAnnounce::parse(&body).map_err(|err| {
+        debug!("invalid register from {}: {}", addr, err);
+        (StatusCode::BAD_REQUEST, err.to_string())
+    })?;
+    if !payload.is_compatible() {
+        warn!(
+            "reject register from {} speaking protocol {}",
+            addr, payload.version
+        );
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("unsupported protocol {}", payload.version),
+        ));
+    }
+    let device = NodeDevice::from_announce(&payload, &scoped_ip_string(&addr));
+    if !discovery_config.allows_peer(&device) {
+        debug!(
+            "reject register from {}, rejected by the peer filter",
+            device
+        );
+        return Err((StatusCode::FORBIDDEN, "peer not allowed".to_string()));
+    }
+    debug!("device {} ({})", device, device.short_fingerprint());
     state.core.device.add_node_device(device).await;
-    Json(payload)
+
+    // reply with our own announce so the peer learns about us in turn
+    let current = state.core.device.get_current_device().await;
+    Ok(Json(current.to_announce()))
+}
+
+/// Our details, for peers that want them without registering or starting
+/// a session. Same fields as the `info` of a prepare-upload.
+async fn handle_info(State(state): State<Arc<AppState>>) -> Json<SenderInfo> {
+    let current = state.core.device.get_current_device().await;
+    Json(current.to_sender_info())
 }
 
 async fn get_devices(State(state): State<Arc<AppState>>) -> Json<Value> {
@@ -56,105 +126,512 @@ impl Drop for Guard {
     }
 }
 
+/// Decompresses a gzip body chunk by chunk. A body that isn't gzip, or ends
+/// early, fails with `InvalidData`.
+fn gunzip<S>(stream: S) -> impl Stream<Item = std::io::Result<Bytes>>
+where
+    S: Stream<Item = std::io::Result<Bytes>>,
+{
+    use std::io::Write;
+
+    let decoder = flate2::write::GzDecoder::new(Vec::new());
+    stream::unfold(
+        (Box::pin(stream), Some(decoder)),
+        |(mut stream, decoder)| async move {
+            let mut decoder = decoder?;
+            loop {
+                let chunk = match stream.next().await {
+                    Some(Ok(chunk)) => chunk,
+                    Some(Err(err)) => return Some((Err(err), (stream, None))),
+                    None => {
+                        let rest = decoder.finish().map(Bytes::from).map_err(invalid_data);
+                        return Some((rest, (stream, None)));
+                    }
+                };
+                if let Err(err) = decoder.write_all(&chunk) {
+                    return Some((Err(invalid_data(err)), (stream, None)));
+                }
+                let decoded = std::mem::take(decoder.get_mut());
+                if !decoded.is_empty() {
+                    return Some((Ok(Bytes::from(decoded)), (stream, Some(decoder))));
+                }
+            }
+        },
+    )
+}
+
+fn invalid_data(err: std::io::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+}
+
 async fn stream_to_file<S, E>(
-    dir: &str,
-    file_name: &str,
+    file_path: &Path,
     stream: S,
     progress: watch::Sender<usize>,
-) -> Result<(), (StatusCode, String)>
+    sha256: Option<&str>,
+    offset: u64,
+    compression: Option<Compression>,
+) -> Result<(), TransferError>
 where
     S: Stream<Item = Result<Bytes, E>>,
     E: Into<BoxError>,
 {
     async {
         // Convert the stream into an `AsyncRead`.
-        let body_with_io_error =
-            stream.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err));
+        let body_with_io_error = stream.map_err(std::io::Error::other);
+        let body_with_io_error = match compression {
+            Some(Compression::Gzip) => gunzip(body_with_io_error).left_stream(),
+            None => body_with_io_error.right_stream(),
+        };
         let body_reader = StreamReader::new(body_with_io_error);
         futures::pin_mut!(body_reader);
 
         // Create the file. `File` implements `AsyncWrite`.
-        let file_path = std::path::Path::new(dir).join(file_name);
         let store_dir = file_path.parent().unwrap();
-        if store_dir.exists() == false {
+        if !store_dir.exists() {
             tokio::fs::create_dir_all(store_dir).await?;
         }
 
-        let file = BufWriter::new(File::create(file_path).await?);
-        let mut writer = ProgressWriteAdapter::new(file, progress);
+        let mut writer = if offset > 0 {
+            // the hash covers the whole file, so start from what's there
+            let context = match sha256 {
+                Some(_) => Some(sha256_context(file_path, offset).await?),
+                None => None,
+            };
+            let file = OpenOptions::new().append(true).open(file_path).await?;
+            let writer = ProgressWriteAdapter::new(BufWriter::new(file), progress)
+                .starting_at(offset as usize);
+            HashWriteAdapter::resume(writer, context)
+        } else {
+            let file = BufWriter::new(File::create(file_path).await?);
+            let writer = ProgressWriteAdapter::new(file, progress);
+            HashWriteAdapter::new(writer, sha256.is_some())
+        };
 
         // Copy the body into the file.
         tokio::io::copy(&mut body_reader, &mut writer).await?;
+        writer.flush().await?;
 
-        Ok::<_, std::io::Error>(())
+        Ok::<_, std::io::Error>(writer.finish())
     }
     .await
-    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+    .map_err(|err| match compression {
+        Some(compression) if err.kind() == std::io::ErrorKind::InvalidData => {
+            TransferError::InvalidRequest(format!("invalid {} body: {}", compression.name(), err))
+        }
+        _ => TransferError::from(err),
+    })
+    .and_then(|actual| match (sha256, actual) {
+        (Some(expected), Some(actual)) if !expected.eq_ignore_ascii_case(&actual) => {
+            Err(TransferError::InvalidRequest(format!(
+                "sha256 mismatch, expected {} got {}",
+                expected, actual
+            )))
+        }
+        _ => Ok(()),
+    })
+}
+
+/// A file being received. When it fails the partial file is removed and the
+/// task failed. When the request future is dropped mid-stream, usually
+/// because the sender lost its connection, the partial file is kept for
+/// `resume_window` and only then the task failed, unless it was resumed.
+struct PartialUpload {
+    /// Part file the bytes go to, see `part_path`.
+    path: PathBuf,
+    token: String,
+    attempt: u32,
+    resume_window: Duration,
+    transfer: transfer::Handle,
+    done: bool,
+}
+
+impl PartialUpload {
+    async fn finish(mut self) {
+        self.done = true;
+        let target = part_target(&self.path);
+        self.transfer
+            .complete(self.token.clone(), self.path.clone(), target)
+            .await;
+    }
+
+    async fn discard(mut self) {
+        self.done = true;
+        let _ = tokio::fs::remove_file(&self.path).await;
+    }
+
+    async fn fail(mut self, msg: String) {
+        self.done = true;
+        let _ = tokio::fs::remove_file(&self.path).await;
+        self.transfer
+            .state_task(self.token.clone(), FileState::Fail { msg })
+            .await;
+    }
+}
+
+impl Drop for PartialUpload {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        debug!("upload of {:?} interrupted", self.path);
+        let transfer = self.transfer.clone();
+        let token = self.token.clone();
+        let attempt = self.attempt;
+        let path = self.path.clone();
+        let resume_window = self.resume_window;
+        tokio::spawn(async move {
+            transfer.interrupt(token.clone(), attempt, path).await;
+            time::sleep(resume_window).await;
+            transfer.expire(token, attempt).await;
+        });
+    }
 }
 
 async fn handle_upload(
     State(state): State<Arc<AppState>>,
     task: Query<UploadTask>,
     request: Request,
-) -> Result<(), (StatusCode, String)> {
+) -> Result<(), TransferError> {
     let task: UploadTask = task.0;
     debug!("handle_upload {:?}", task);
 
     let handle = state.core.mission.transfer.clone();
-    let store_path = state.core.get_config().await.store_path;
+    let receive = state.core.get_receive_config().await;
+
+    let task_handle = handle
+        .start_task(task.session_id, task.file_id, task.token.clone())
+        .await?;
+    let store_path = match task_handle.store_path.clone() {
+        Some(path) => path,
+        None => PathBuf::from(state.core.get_config().await.store_path),
+    };
 
-    let res = handle.start_task(task.token.clone()).await;
+    let compression = match upload_encoding(request.headers(), task_handle.compression) {
+        Ok(Some(_)) if task_handle.info.is_text() => Err("text can't be compressed".to_string()),
+        result => result,
+    };
+    let compression = match compression {
+        Ok(compression) => compression,
+        Err(msg) => {
+            handle
+                .state_task(task.token, FileState::Fail { msg: msg.clone() })
+                .await;
+            return Err(TransferError::InvalidRequest(msg));
+        }
+    };
 
-    match res {
-        Ok((tx, file)) => {
-            let file_name = file.file_name.clone();
-            // ...
-            let body_stream = request.into_body().into_data_stream();
+    if task_handle.info.is_text() {
+        return receive_text(handle, task.token, request.into_body()).await;
+    }
 
-            let res = stream_to_file(&store_path, &file_name, body_stream, tx).await;
+    let resumable = task_handle.partial.is_some();
+    let upload = PartialUpload {
+        path: match (
+            task_handle.partial,
+            safe_relative_path(&task_handle.info.file_name),
+        ) {
+            (Some(partial), _) => partial,
+            // never overwrite what's there, e.g. from an earlier transfer
+            (None, Some(path)) => part_path(&unique_part_target(&store_path.join(path))),
+            (None, None) => {
+                let msg = format!("invalid file name {}", task_handle.info.file_name);
+                handle
+                    .state_task(task.token, FileState::Fail { msg: msg.clone() })
+                    .await;
+                return Err(TransferError::InvalidRequest(msg));
+            }
+        },
+        token: task.token,
+        attempt: task_handle.attempt,
+        resume_window: receive.resume_window,
+        transfer: handle,
+        done: false,
+    };
 
-            match res {
-                Ok(_) => {
-                    handle
-                        .state_task(task.token.clone(), FileState::Finish)
-                        .await;
-                    Ok(())
-                }
-                Err(e) => {
-                    handle
-                        .state_task(task.token, FileState::Fail { msg: e.1.clone() })
-                        .await;
-                    Err(e)
-                }
+    if task.offset > 0 {
+        let kept = match tokio::fs::metadata(&upload.path).await {
+            Ok(metadata) if resumable => metadata.len(),
+            _ => 0,
+        };
+        if kept != task.offset {
+            // dropping the upload keeps the partial file around for another try
+            return Err(TransferError::ResumeConflict {
+                offset: task.offset,
+                kept,
+            });
+        }
+    }
+
+    let body_stream = request.into_body().into_data_stream();
+    let sha256 = task_handle
+        .info
+        .sha256
+        .as_deref()
+        .filter(|_| receive.verify_sha256);
+    let progress = task_handle.progress;
+    let offset = task.offset;
+    let mut canceled = task_handle.canceled;
+    let result = tokio::select! {
+        result = stream_to_file(&upload.path, body_stream, progress, sha256, offset, compression) => result,
+        true = async { canceled.wait_for(|canceled| *canceled).await.is_ok() } => {
+            // the mission is gone already, only the partial file is left
+            upload.discard().await;
+            return Err(TransferError::Canceled);
+        }
+    };
+    match result {
+        Ok(_) => {
+            upload.finish().await;
+            Ok(())
+        }
+        Err(e) => {
+            upload.fail(e.to_string()).await;
+            Err(e)
+        }
+    }
+}
+
+/// The encoding of an upload body, which has to be the one agreed on in
+/// prepare-upload, if any.
+fn upload_encoding(
+    headers: &HeaderMap,
+    agreed: Option<Compression>,
+) -> Result<Option<Compression>, String> {
+    let value = match headers.get(header::CONTENT_ENCODING) {
+        Some(value) => value.to_str().unwrap_or_default().trim(),
+        None => return Ok(None),
+    };
+    if value.is_empty() || value.eq_ignore_ascii_case("identity") {
+        return Ok(None);
+    }
+    match Compression::parse(value) {
+        Some(compression) if agreed == Some(compression) => Ok(Some(compression)),
+        _ => Err(format!("upload encoding {} wasn't agreed on", value)),
+    }
+}
+
+/// Tells a sender about to resume how many bytes of the file we kept. The
+/// `offset` of the query is ignored.
+async fn upload_offset(
+    State(state): State<Arc<AppState>>,
+    Query(task): Query<UploadTask>,
+) -> Result<Json<UploadOffset>, TransferError> {
+    let partial = state
+        .core
+        .mission
+        .transfer
+        .partial(task.session_id, task.file_id, task.token)
+        .await?;
+
+    let mut kept = UploadOffset {
+        offset: 0,
+        sha256: None,
+    };
+    if let Some(path) = partial {
+        if let Ok(metadata) = tokio::fs::metadata(&path).await {
+            kept.offset = metadata.len();
+            kept.sha256 = sha256_prefix(&path, kept.offset).await.ok();
+        }
+    }
+    Ok(Json(kept))
+}
+
+/// Reads the body of a text item, capped at `MAX_TEXT_SIZE`, and passes the
+/// text on instead of storing it.
+async fn receive_text(
+    transfer: transfer::Handle,
+    token: String,
+    body: Body,
+) -> Result<(), TransferError> {
+    let text = match body::to_bytes(body, MAX_TEXT_SIZE).await {
+        Ok(bytes) => String::from_utf8(bytes.to_vec())
+            .map_err(|_| TransferError::InvalidRequest("text is not utf-8".to_string())),
+        // also what exceeding the limit looks like
+        Err(err) => Err(TransferError::InvalidRequest(format!(
+            "failed to read text: {}",
+            err
+        ))),
+    };
+    match text {
+        Ok(text) => {
+            transfer.receive_text(token, text).await;
+            Ok(())
+        }
+        Err(err) => {
+            transfer
+                .state_task(
+                    token,
+                    FileState::Fail {
+                        msg: err.to_string(),
+                    },
+                )
+                .await;
+            Err(err)
+        }
+    }
+}
+
+const MAX_PIN_ATTEMPTS: u32 = 3;
+const PIN_LOCKOUT: Duration = Duration::from_secs(30);
+
+struct PinAttempts {
+    failures: u32,
+    /// A lockout after the last wrong pin, the entry is dropped then.
+    expires: Instant,
+}
+
+/// Counts wrong pins per sender address and locks it out for a while after
+/// too many, so the pin can't be brute forced. Entries expire, so senders
+/// going through many addresses can't grow it without bound.
+#[derive(Default)]
+struct PinGuard {
+    attempts: Mutex<HashMap<IpAddr, PinAttempts>>,
+}
+
+impl PinGuard {
+    fn check(&self, ip: IpAddr, given: Option<&str>, pin: &str) -> Result<(), TransferError> {
+        let mut attempts = self.attempts.lock();
+        let now = Instant::now();
+        attempts.retain(|_, entry| entry.expires > now);
+        if attempts
+            .get(&ip)
+            .is_some_and(|entry| entry.failures >= MAX_PIN_ATTEMPTS)
+        {
+            return Err(TransferError::PinLocked);
+        }
+
+        match given {
+            // the first request usually comes without one, that's the prompt
+            None => Err(TransferError::PinRequired),
+            Some(given) if pin_matches(given, pin) => {
+                attempts.remove(&ip);
+                Ok(())
+            }
+            Some(_) => {
+                let entry = attempts.entry(ip).or_insert(PinAttempts {
+                    failures: 0,
+                    expires: now,
+                });
+                entry.failures += 1;
+                entry.expires = now + PIN_LOCKOUT;
+                Err(TransferError::InvalidPin)
             }
         }
-        Err(_) => todo!(),
     }
 }
 
+/// Compares in constant time, so the pin can't be guessed from how long
+/// refusing takes. Hashing first keeps the length from showing too, ring
+/// has deprecated its constant time compare.
+fn pin_matches(given: &str, pin: &str) -> bool {
+    let given = digest::digest(&digest::SHA256, given.as_bytes());
+    let pin = digest::digest(&digest::SHA256, pin.as_bytes());
+    let diff = given
+        .as_ref()
+        .iter()
+        .zip(pin.as_ref())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+    diff == 0
+}
+
+#[derive(Deserialize)]
+struct PinQuery {
+    pin: Option<String>,
+}
+
 async fn prepare_upload(
     State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<PinQuery>,
     Json(payload): Json<FileRequest>,
-) -> Result<Json<FileResponse>, (StatusCode, String)> {
+) -> Result<Json<FileResponse>, TransferError> {
     debug!("prepare_upload {:?}", payload);
 
-    let device = state
+    let receive = state.core.get_receive_config().await;
+    let max_sessions = receive.max_concurrent_sessions;
+    if let Some(pin) = receive.pin {
+        if let Err(err) = state.pin_guard.check(addr.ip(), query.pin.as_deref(), &pin) {
+            debug!("prepare_upload from {} refused: {}", addr, err);
+            return Err(err);
+        }
+    }
+
+    if let Err(err) = payload.validate() {
+        debug!("invalid prepare_upload: {}", err);
+        return Err(TransferError::InvalidRequest(err));
+    }
+
+    let store_path = state.core.get_config().await.store_path;
+    check_free_space(Path::new(&store_path), &payload, receive.free_space_margin)?;
+
+    let device = match state
         .core
         .device
         .get_device(payload.info.fingerprint.clone())
-        .await;
+        .await
+    {
+        Some(device) => device,
+        None => {
+            debug!("mission rejected");
+            return Err(TransferError::NotRegistered);
+        }
+    };
 
-    if device.is_none() {
-        debug!("mission rejected");
-        return Err((
-            StatusCode::FORBIDDEN,
-            "device not registerd, mission rejected".to_string(),
-        ));
+    let permit = match state.core.mission.sessions.try_acquire(max_sessions) {
+        Some(permit) => permit,
+        None => {
+            debug!("mission rejected, too many sessions");
+            return Err(TransferError::TooManySessions);
+        }
+    };
+
+    let timeout = receive.decision_timeout;
+    let mut files = payload.files.clone();
+    let mut store_dir = None;
+    if let Some(policy) = &receive.policy {
+        let decision = with_timeout(timeout, policy.decide(&payload, &device))
+            .await
+            .unwrap_or_else(|| {
+                debug!("no decision in time");
+                TransferDecision::Reject
+            });
+        match decision {
+            TransferDecision::AcceptAll => {}
+            TransferDecision::AcceptSome(ids) => files.retain(|id, _| ids.contains(id)),
+            TransferDecision::AcceptInto { dir, files: ids } => {
+                if let Some(ids) = ids {
+                    files.retain(|id, _| ids.contains(id));
+                }
+                store_dir = Some(dir);
+            }
+            TransferDecision::Reject => files.clear(),
+        }
+        if files.is_empty() {
+            debug!("mission rejected by policy");
+            return Err(TransferError::Rejected);
+        }
+    }
+    if let Some(dir) = &store_dir {
+        if let Err(err) = tokio::fs::create_dir_all(dir).await {
+            debug!("can't create {:?}: {}", dir, err);
+            return Err(TransferError::Internal(format!(
+                "can't store files: {}",
+                err
+            )));
+        }
+        // may be on another volume than the store path
+        check_free_space(dir, &payload, receive.free_space_margin)?;
     }
 
     debug!("mission incoming");
 
-    let mission = Mission::new(payload.files, device.unwrap());
+    let mut mission = Mission::new(files, device);
+    mission.store_path = store_dir;
+    mission.compression = payload.compression.as_deref().and_then(Compression::parse);
+    if mission.compression.is_none() && payload.compression.is_some() {
+        debug!("declining unknown compression {:?}", payload.compression);
+    }
     let id = mission.id.clone();
 
     let (tx, mut rx) = mpsc::channel(8);
@@ -163,47 +640,185 @@ async fn prepare_upload(
     let state_clone = state.clone();
 
     tokio::spawn(async move {
-        while let Some(flag) = rx.recv().await {
+        if let Some(flag) = rx.recv().await {
             if flag {
                 debug!("client side close");
                 state_clone.core.mission.pending.cancel(id).await;
             } else {
                 debug!("normal complete");
             }
-            break;
         }
     });
 
-    let result = pending_mission(state, mission).await;
+    let decided = receive.policy.is_some();
+    let transfer = state.core.mission.transfer.clone();
+    let result = pending_mission(state, mission, permit, decided, timeout).await;
+    if let (Ok(accepted), Some(idle)) = (&result, receive.session_timeout) {
+        tokio::spawn(watch_session(transfer, accepted.session_id.clone(), idle));
+    }
     let _ = tx.send(false).await;
     result
 }
 
+/// Refuses a request whose files don't fit on the store volume with
+/// `margin` bytes to spare.
+fn check_free_space(
+    store_path: &Path,
+    request: &FileRequest,
+    margin: u64,
+) -> Result<(), TransferError> {
+    let unknown = request
+        .files
+        .values()
+        .filter(|file| !file.is_text() && file.size == 0)
+        .count();
+    if unknown > 0 {
+        debug!("{} files without a declared size", unknown);
+    }
+
+    let free = match free_space(store_path) {
+        Some(free) => free,
+        None => {
+            debug!("free space of {:?} unknown, accepting", store_path);
+            return Ok(());
+        }
+    };
+    let needed = request.total_size();
+    if needed.saturating_add(margin) > free {
+        debug!("mission rejected, needs {} bytes, {} free", needed, free);
+        return Err(TransferError::InsufficientStorage { needed, free });
+    }
+    Ok(())
+}
+
+/// Fails session `session_id` once it's been idle for `idle`, checking as
+/// often as that until it ends.
+async fn watch_session(transfer: transfer::Handle, session_id: String, idle: Duration) {
+    loop {
+        time::sleep(idle).await;
+        if !transfer.expire_idle(session_id.clone(), idle).await {
+            break;
+        }
+    }
+}
+
+/// Runs `future` to completion, or for at most `timeout` when one is set.
+async fn with_timeout<F: Future>(timeout: Option<Duration>, future: F) -> Option<F::Output> {
+    match timeout {
+        Some(timeout) => time::timeout(timeout, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
 async fn pending_mission(
     state: Arc<AppState>,
     mission: Mission,
-) -> Result<Json<FileResponse>, (StatusCode, String)> {
-    let mut state_rx = state.core.mission.pending.add(mission.clone()).await;
+    permit: SessionPermit,
+    accepted: bool,
+    timeout: Option<Duration>,
+) -> Result<Json<FileResponse>, TransferError> {
+    let pending = &state.core.mission.pending;
+    let mut state_rx = pending.add(mission.clone(), permit).await;
+    if accepted {
+        pending.accept(mission.id.clone()).await;
+    }
 
-    let _ = state_rx.changed().await;
+    if with_timeout(timeout, state_rx.changed()).await.is_none() {
+        debug!("mission {} not accepted in time", mission.id);
+        pending.cancel(mission.id.clone()).await;
+        return Err(TransferError::Rejected);
+    }
 
     let result = match *state_rx.borrow_and_update() {
         MissionState::Transfering => Ok(Json(FileResponse {
             session_id: mission.id,
             files: mission.id_token_map,
+            compression: mission
+                .compression
+                .map(|compression| compression.name().to_string()),
         })),
         MissionState::Busy => {
             debug!("core is resolving another mission");
-            Err((StatusCode::CONFLICT, "mission rejected".to_string()))
+            Err(TransferError::Busy)
         }
         _state => {
             debug!("mission rejected {:?}", _state);
-            Err((StatusCode::FORBIDDEN, "mission rejected".to_string()))
+            Err(TransferError::Rejected)
         }
     };
     result
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PrepareDownloadQuery {
+    session_id: Option<String>,
+    pin: Option<String>,
+}
+
+/// Opens a session on the files we offer, guarded by the offer's pin the
+/// same way uploads are by the receive pin.
+async fn prepare_download(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<PrepareDownloadQuery>,
+) -> Result<Json<DownloadResponse>, TransferError> {
+    let Some(offer) = state.core.share.summary().await else {
+        debug!("prepare_download from {}, nothing offered", addr);
+        return Err(TransferError::Rejected);
+    };
+    if let Some(pin) = &offer.pin {
+        if let Err(err) = state.pin_guard.check(addr.ip(), query.pin.as_deref(), pin) {
+            debug!("prepare_download from {} refused: {}", addr, err);
+            return Err(err);
+        }
+    }
+
+    // the offer may have been revoked in between
+    let session_id = state
+        .core
+        .share
+        .open_session(query.session_id)
+        .await
+        .ok_or(TransferError::Rejected)?;
+    let current = state.core.device.get_current_device().await;
+    Ok(Json(DownloadResponse {
+        info: current.to_sender_info(),
+        session_id,
+        files: offer.files,
+    }))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DownloadQuery {
+    session_id: String,
+    file_id: String,
+}
+
+async fn handle_download(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<DownloadQuery>,
+) -> Result<Response, TransferError> {
+    let file = state
+        .core
+        .share
+        .resolve(query.session_id, query.file_id)
+        .await
+        .ok_or(TransferError::InvalidToken)?;
+    let handle = File::open(&file.path).await.map_err(|err| {
+        debug!("offered file {:?} unreadable: {}", file.path, err);
+        TransferError::from(err)
+    })?;
+    let len = handle.metadata().await?.len();
+
+    let headers = [
+        (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+        (header::CONTENT_LENGTH, len.to_string()),
+    ];
+    Ok((headers, Body::from_stream(ReaderStream::new(handle))).into_response())
+}
+
 #[derive(Deserialize)]
 struct SessionId {
     #[serde(alias = "sessionId")]
@@ -211,25 +826,107 @@ struct SessionId {
 }
 
 async fn cancel(State(state): State<Arc<AppState>>, session_id: Query<SessionId>) {
-    let handle = state.core.mission.transfer.clone();
-    handle.cancel(session_id.id.clone()).await;
+    state
+        .core
+        .mission
+        .cancel_session(session_id.id.clone())
+        .await;
 }
 
 struct AppState {
     core: CoreActorHandle,
+    pin_guard: PinGuard,
 }
 
 pub fn app(core: CoreActorHandle) -> Router {
-    let shared_state = Arc::new(AppState { core });
+    let shared_state = Arc::new(AppState {
+        core,
+        pin_guard: PinGuard::default(),
+    });
     let api_v2 = Router::new()
+        .route("/info", get(handle_info))
         .route("/devices", get(get_devices))
         .route("/register", post(handle_register))
         .route("/prepare-upload", post(prepare_upload))
-        .route("/upload", post(handle_upload))
-        .route("/cancel/:session_id", post(cancel))
+        .route("/upload", post(handle_upload).get(upload_offset))
+        .route("/cancel", post(cancel))
+        .route("/prepare-download", post(prepare_download))
+        .route("/download", get(handle_download))
+        .with_state(shared_state.clone());
+    // legacy peers only get to register, so both sides see each other
+    let api_v1 = Router::new()
+        .route("/register", post(handle_register))
         .with_state(shared_state);
 
-    let app = Router::new().nest("/v2", api_v2);
+    Router::new().nest("/v2", api_v2).nest("/v1", api_v1)
+}
 
-    app
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    #[test]
+    fn pin_guard_locks_out_after_wrong_pins() {
+        let guard = PinGuard::default();
+        let ip = IpAddr::from([192, 168, 1, 7]);
+        assert_eq!(
+            guard.check(ip, None, "1234"),
+            Err(TransferError::PinRequired)
+        );
+        for _ in 0..MAX_PIN_ATTEMPTS {
+            assert_eq!(
+                guard.check(ip, Some("0000"), "1234"),
+                Err(TransferError::InvalidPin)
+            );
+        }
+        assert_eq!(
+            guard.check(ip, Some("1234"), "1234"),
+            Err(TransferError::PinLocked)
+        );
+        // others aren't affected
+        let other = IpAddr::from([192, 168, 1, 8]);
+        assert_eq!(guard.check(other, Some("1234"), "1234"), Ok(()));
+    }
+
+    #[test]
+    fn pin_guard_forgets_on_success() {
+        let guard = PinGuard::default();
+        let ip = IpAddr::from([192, 168, 1, 7]);
+        for _ in 0..MAX_PIN_ATTEMPTS - 1 {
+            let _ = guard.check(ip, Some("0000"), "1234");
+        }
+        assert_eq!(guard.check(ip, Some("1234"), "1234"), Ok(()));
+        assert!(guard.attempts.lock().is_empty());
+    }
+
+    #[test]
+    fn pin_guard_drops_expired_entries() {
+        let guard = PinGuard::default();
+        for i in 0..1000u16 {
+            let ip = IpAddr::from(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, i));
+            let _ = guard.check(ip, Some("0000"), "1234");
+        }
+        // a prompt without a pin isn't recorded
+        let _ = guard.check(IpAddr::from([10, 0, 0, 1]), None, "1234");
+        assert_eq!(guard.attempts.lock().len(), 1000);
+
+        let past = Instant::now() - Duration::from_secs(1);
+        for entry in guard.attempts.lock().values_mut() {
+            entry.expires = past;
+        }
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        assert_eq!(guard.check(ip, Some("1234"), "1234"), Ok(()));
+        assert!(guard.attempts.lock().is_empty());
+    }
+
+    #[test]
+    fn pins_compare_whole() {
+        assert!(pin_matches("1234", "1234"));
+        assert!(!pin_matches("1235", "1234"));
+        assert!(!pin_matches("123", "1234"));
+        assert!(!pin_matches("12345", "1234"));
+        assert!(!pin_matches("", "1234"));
+    }
 }
@@ -0,0 +1,165 @@
+//! Synchronous wrappers around [`CoreActorHandle`] for callers without a
+//! tokio runtime, like command line tools and FFI consumers. [`Core`] owns
+//! the runtime the actors run on, so the server keeps going between calls.
+
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+
+use tokio::runtime::Runtime;
+
+use crate::actor::{
+    core::{CoreActorHandle, CoreConfig, DiscoveryConfig, ReceiveConfig, SendConfig},
+    discovery::{DiscoveryError, DiscoveryMetrics},
+    model::NodeDevice,
+    send::OutgoingFile,
+};
+use crate::api::model::Compression;
+
+pub struct Core {
+    runtime: Runtime,
+    handle: CoreActorHandle,
+}
+
+impl Core {
+    /// Starts the actors for `device` on a runtime of their own. The server
+    /// isn't started until [`Core::start`].
+    pub fn new(device: NodeDevice, config: CoreConfig) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let handle = {
+            let _guard = runtime.enter();
+            CoreActorHandle::new(device, config)
+        };
+        Ok(Core { runtime, handle })
+    }
+
+    /// The async handle, for the parts not wrapped here. Its futures have to
+    /// run on [`Core::runtime`].
+    pub fn handle(&self) -> &CoreActorHandle {
+        &self.handle
+    }
+
+    pub fn runtime(&self) -> &Runtime {
+        &self.runtime
+    }
+
+    pub fn start(&self) {
+        self.runtime.block_on(self.handle.start());
+    }
+
+    pub fn shutdown(&self) {
+        self.runtime.block_on(self.handle.shutdown());
+    }
+
+    pub fn get_config(&self) -> CoreConfig {
+        self.runtime.block_on(self.handle.get_config())
+    }
+
+    pub fn change_config(&self, config: CoreConfig) {
+        self.runtime.block_on(self.handle.change_config(config));
+    }
+
+    pub fn get_discovery_config(&self) -> DiscoveryConfig {
+        self.runtime.block_on(self.handle.get_discovery_config())
+    }
+
+    pub fn change_discovery_config(&self, config: DiscoveryConfig) {
+        self.runtime
+            .block_on(self.handle.change_discovery_config(config));
+    }
+
+    pub fn get_receive_config(&self) -> ReceiveConfig {
+        self.runtime.block_on(self.handle.get_receive_config())
+    }
+
+    pub fn change_receive_config(&self, config: ReceiveConfig) {
+        self.runtime
+            .block_on(self.handle.change_receive_config(config));
+    }
+
+    pub fn get_send_config(&self) -> SendConfig {
+        self.runtime.block_on(self.handle.get_send_config())
+    }
+
+    pub fn change_send_config(&self, config: SendConfig) {
+        self.runtime
+            .block_on(self.handle.change_send_config(config));
+    }
+
+    pub fn get_current_device(&self) -> NodeDevice {
+        self.runtime
+            .block_on(self.handle.device.get_current_device())
+    }
+
+    pub fn set_current_device(&self, device: NodeDevice) {
+        self.runtime
+            .block_on(self.handle.device.set_current_device(device));
+    }
+
+    /// See [`CoreActorHandle::discover`].
+    pub fn discover(&self) {
+        self.runtime.block_on(self.handle.discover());
+    }
+
+    /// See [`CoreActorHandle::discover_collect`].
+    pub fn discover_collect(&self, window: Duration, clear: bool) -> HashMap<String, NodeDevice> {
+        self.runtime
+            .block_on(self.handle.discover_collect(window, clear))
+    }
+
+    /// See [`CoreActorHandle::scan`].
+    pub fn scan(&self, window: Duration) -> Result<HashMap<String, NodeDevice>, DiscoveryError> {
+        self.runtime.block_on(self.handle.scan(window))
+    }
+
+    /// The known devices by fingerprint.
+    pub fn get_devices(&self) -> HashMap<String, NodeDevice> {
+        self.runtime.block_on(self.handle.device.get_device_map())
+    }
+
+    pub fn get_device(&self, fingerprint: String) -> Option<NodeDevice> {
+        self.runtime
+            .block_on(self.handle.device.get_device(fingerprint))
+    }
+
+    /// See [`CoreActorHandle::probe`].
+    pub fn probe(&self, target: &NodeDevice, timeout: Duration) -> bool {
+        self.runtime.block_on(self.handle.probe(target, timeout))
+    }
+
+    pub fn discovery_metrics(&self) -> DiscoveryMetrics {
+        self.runtime.block_on(self.handle.discovery_metrics())
+    }
+
+    pub fn accept_pending(&self, id: String) {
+        self.runtime
+            .block_on(self.handle.mission.pending.accept(id));
+    }
+
+    pub fn cancel_pending(&self, id: String) {
+        self.runtime
+            .block_on(self.handle.mission.pending.cancel(id));
+    }
+
+    pub fn send_text(&self, target: &NodeDevice, text: String) -> Result<(), String> {
+        self.runtime.block_on(self.handle.send_text(target, text))
+    }
+
+    pub fn send_files(&self, target: &NodeDevice, files: Vec<OutgoingFile>) -> Result<(), String> {
+        self.runtime.block_on(self.handle.send_files(target, files))
+    }
+
+    pub fn send_files_with_compression(
+        &self,
+        target: &NodeDevice,
+        files: Vec<OutgoingFile>,
+        compression: Compression,
+    ) -> Result<(), String> {
+        self.runtime.block_on(
+            self.handle
+                .send_files_with_compression(target, files, compression),
+        )
+    }
+}
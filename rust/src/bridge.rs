@@ -1,17 +1,11 @@
-use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
-    str::FromStr as _,
-};
-
-use axum::extract::path;
 use lazy_static::lazy_static;
 use log::debug;
-use tokio::{net::UdpSocket, sync::OnceCell};
+use tokio::sync::OnceCell;
 
 use crate::{
     actor::{
         core::{CoreActorHandle, CoreConfig},
-        mission::{MissionInfo, MISSION_NOTIFY},
+        mission::MissionInfo,
         model::NodeDevice,
     },
     frb_generated::StreamSink,
@@ -36,7 +30,7 @@ pub async fn listen_server_state(s: StreamSink<bool>) {
     let mut rx = _get_core().listen().await;
     loop {
         let _ = rx.changed().await;
-        let data = rx.borrow().clone();
+        let data = *rx.borrow();
         let _ = s.add(data);
     }
 }
@@ -63,16 +57,23 @@ pub async fn change_config(config: CoreConfig) {
 }
 
 pub async fn listen_device(s: StreamSink<Vec<NodeDevice>>) {
-    let mut rx = _get_core().device.listen().await;
+    let mut rx = _get_core().device.listen_shared().await;
+    let mut data = rx.borrow_and_update().clone();
     loop {
-        let _ = rx.changed().await;
-        let data = rx.borrow().clone();
-        let _ = s.add(data);
+        let _ = s.add(
+            data.iter()
+                .map(|device| NodeDevice::clone(device))
+                .collect(),
+        );
+        if rx.changed().await.is_err() {
+            break;
+        }
+        data = rx.borrow().clone();
     }
 }
 
 pub async fn listen_mission(s: StreamSink<Option<MissionInfo>>) {
-    let mut rx = MISSION_NOTIFY.listen().await;
+    let mut rx = _get_core().mission.notify.listen().await;
     loop {
         let _ = rx.changed().await;
         debug!("mission change");
@@ -90,13 +91,13 @@ pub async fn listen_task_progress(s: StreamSink<usize>) {
         .unwrap();
     loop {
         let _ = rx.changed().await;
-        let data = rx.borrow().clone();
+        let data = *rx.borrow();
         let _ = s.add(data);
     }
 }
 
 pub async fn clear_mission() {
-    MISSION_NOTIFY.clear().await;
+    _get_core().mission.notify.clear().await;
 }
 
 pub async fn cancel_pending(id: String) {
@@ -112,31 +113,5 @@ pub fn create_log_stream(s: StreamSink<LogEntry>) {
 }
 
 pub async fn announce() {
-    let config = _get_core().get_config().await;
-    let interface_addr = Ipv4Addr::from_str(&config.interface_addr).unwrap();
-    let multicast_addr = Ipv4Addr::from_str(&config.multicast_addr).unwrap();
-    let multicast_port = config.multicast_port;
-
-    _get_core().device.clear_devices().await;
-
-    let send_socket: UdpSocket = UdpSocket::bind((interface_addr, multicast_port + 2))
-        .await
-        .expect("couldn't bind to address");
-
-    send_socket
-        .join_multicast_v4(multicast_addr, interface_addr)
-        .expect("failed to join multicast");
-
-    let current = _get_core().device.get_current_device().await;
-    let s_message = serde_json::to_string(&current).unwrap();
-
-    let buf = s_message.as_bytes();
-    for _ in 1..3 {
-        let _ = send_socket
-            .send_to(
-                buf,
-                SocketAddr::new(IpAddr::from(multicast_addr), multicast_port),
-            )
-            .await;
-    }
+    _get_core().discover().await;
 }
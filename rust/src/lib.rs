@@ -1,6 +1,9 @@
-mod actor;
-mod api;
+pub mod actor;
+pub mod api;
+#[cfg(feature = "blocking")]
+pub mod blocking;
 pub mod bridge;
 mod frb_generated;
 mod logger;
+pub mod tls;
 mod util; /* AUTO INJECTED BY flutter_rust_bridge. This line may not be accurate, and you can change it according to your needs. */
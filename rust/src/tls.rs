@@ -0,0 +1,492 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+
+use crate::util::hex_string;
+use ring::{
+    digest,
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms},
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme,
+};
+
+pub mod stream;
+
+const CERT_FILE: &str = "cert.der";
+const KEY_FILE: &str = "key.der";
+
+/// Self-signed certificate this node serves https with. LocalSend peers
+/// identify each other by the SHA-256 of the certificate, so `fingerprint`
+/// is what goes into our announces.
+pub struct TlsIdentity {
+    pub cert: Vec<u8>,
+    key: Vec<u8>,
+    pub fingerprint: String,
+}
+
+impl TlsIdentity {
+    /// Creates a fresh ECDSA P-256 key and a certificate for it, valid for
+    /// ten years.
+    pub fn generate() -> Result<Self, String> {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+            .map_err(|_| "failed to generate key".to_string())?;
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+                .map_err(|_| "failed to load generated key".to_string())?;
+
+        let serial: [u8; 16] = rand::random();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|err| err.to_string())?
+            .as_secs() as i64;
+        let day = 24 * 60 * 60;
+        let tbs = der::tbs_certificate(
+            &serial,
+            "LocalSend User",
+            now - day,
+            now + 3650 * day,
+            key_pair.public_key().as_ref(),
+        );
+        let signature = key_pair
+            .sign(&rng, &tbs)
+            .map_err(|_| "failed to sign certificate".to_string())?;
+        let cert = der::certificate(&tbs, signature.as_ref());
+
+        Ok(TlsIdentity {
+            fingerprint: fingerprint_of(&cert),
+            cert,
+            key: pkcs8.as_ref().to_vec(),
+        })
+    }
+
+    /// Reads the identity [`TlsIdentity::save`] left in `dir`.
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let read = |name: &str| {
+            fs::read(dir.join(name)).map_err(|err| format!("can't read {}: {}", name, err))
+        };
+        let cert = read(CERT_FILE)?;
+        let key = read(KEY_FILE)?;
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &key, &SystemRandom::new())
+            .map_err(|_| format!("invalid key in {:?}", dir))?;
+        Ok(TlsIdentity {
+            fingerprint: fingerprint_of(&cert),
+            cert,
+            key,
+        })
+    }
+
+    /// Writes the certificate and key to `dir` as DER, the key only readable
+    /// by us where the platform allows.
+    pub fn save(&self, dir: &Path) -> Result<(), String> {
+        fs::create_dir_all(dir).map_err(|err| format!("can't create {:?}: {}", dir, err))?;
+        // the certificate goes last, a missing one means nothing was saved
+        write_private(&dir.join(KEY_FILE), &self.key)
+            .map_err(|err| format!("can't write {}: {}", KEY_FILE, err))?;
+        fs::write(dir.join(CERT_FILE), &self.cert)
+            .map_err(|err| format!("can't write {}: {}", CERT_FILE, err))
+    }
+
+    /// The identity kept in `dir`, so the fingerprint stays the same across
+    /// restarts. On first run a new one is generated and saved there.
+    pub fn load_or_generate(dir: &Path) -> Result<Self, String> {
+        if dir.join(CERT_FILE).exists() {
+            return Self::load(dir);
+        }
+        let identity = Self::generate()?;
+        identity.save(dir)?;
+        info!("saved new identity {} to {:?}", identity.fingerprint, dir);
+        Ok(identity)
+    }
+
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>, String> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|err| err.to_string())?
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from(self.cert.clone())],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.key.clone())),
+            )
+            .map_err(|err| err.to_string())?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    fs::write(path, contents)
+}
+
+/// SHA-256 of a DER encoded certificate as lowercase hex, the form used as
+/// fingerprint by https peers.
+pub fn fingerprint_of(cert: &[u8]) -> String {
+    hex_string(digest::digest(&digest::SHA256, cert).as_ref())
+}
+
+/// Pins the server certificate to the fingerprint the peer announced
+/// instead of validating a chain, as LocalSend certificates are self-signed.
+#[derive(Debug)]
+struct FingerprintVerifier {
+    fingerprint: Option<String>,
+    insecure: bool,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let actual = fingerprint_of(end_entity.as_ref());
+        match &self.fingerprint {
+            Some(expected) if expected.eq_ignore_ascii_case(&actual) => {
+                Ok(ServerCertVerified::assertion())
+            }
+            _ if self.insecure => {
+                warn!("accepting unpinned certificate {}", actual);
+                Ok(ServerCertVerified::assertion())
+            }
+            _ => Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            )),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Client config for talking to an https peer. The connection is refused
+/// unless the peer's certificate hashes to `fingerprint`, or `insecure` is
+/// set.
+pub fn client_config(fingerprint: Option<String>, insecure: bool) -> Arc<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = FingerprintVerifier {
+        fingerprint,
+        insecure,
+        algorithms: provider.signature_verification_algorithms,
+    };
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default protocol versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+/// Just enough DER to write an X.509 v3 certificate.
+mod der {
+    const OID_ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+    const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+    const OID_PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+    const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+    pub(super) fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        let len = content.len();
+        if len < 0x80 {
+            out.push(len as u8);
+        } else {
+            let bytes = len.to_be_bytes();
+            let skip = bytes.iter().take_while(|b| **b == 0).count();
+            out.push(0x80 | (bytes.len() - skip) as u8);
+            out.extend_from_slice(&bytes[skip..]);
+        }
+        out.extend_from_slice(content);
+        out
+    }
+
+    fn sequence(parts: &[&[u8]]) -> Vec<u8> {
+        tlv(0x30, &parts.concat())
+    }
+
+    fn oid(value: &[u8]) -> Vec<u8> {
+        tlv(0x06, value)
+    }
+
+    fn unsigned_integer(value: &[u8]) -> Vec<u8> {
+        // DER wants the minimal encoding, with a zero byte keeping it positive
+        let skip = value.iter().take_while(|b| **b == 0).count();
+        let mut content = value[skip..].to_vec();
+        if content.first().is_none_or(|b| b & 0x80 != 0) {
+            content.insert(0, 0);
+        }
+        tlv(0x02, &content)
+    }
+
+    fn bit_string(value: &[u8]) -> Vec<u8> {
+        tlv(0x03, &[&[0][..], value].concat())
+    }
+
+    fn name(common_name: &str) -> Vec<u8> {
+        let attribute = sequence(&[&oid(OID_COMMON_NAME), &tlv(0x0c, common_name.as_bytes())]);
+        sequence(&[&tlv(0x31, &attribute)])
+    }
+
+    /// UTCTime before 2050, GeneralizedTime after, as RFC 5280 requires.
+    pub(super) fn time(unix: i64) -> Vec<u8> {
+        let days = unix.div_euclid(86400);
+        let secs = unix.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        let clock = format!(
+            "{:02}{:02}{:02}{:02}{:02}Z",
+            month,
+            day,
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        );
+        if (1950..2050).contains(&year) {
+            tlv(0x17, format!("{:02}{}", year % 100, clock).as_bytes())
+        } else {
+            tlv(0x18, format!("{:04}{}", year, clock).as_bytes())
+        }
+    }
+
+    // Howard Hinnant's days to civil date conversion
+    pub(super) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        (year, month, day)
+    }
+
+    fn signature_algorithm() -> Vec<u8> {
+        sequence(&[&oid(OID_ECDSA_WITH_SHA256)])
+    }
+
+    pub fn tbs_certificate(
+        serial: &[u8],
+        common_name: &str,
+        not_before: i64,
+        not_after: i64,
+        public_key: &[u8],
+    ) -> Vec<u8> {
+        let version = tlv(0xa0, &tlv(0x02, &[2]));
+        let validity = sequence(&[&time(not_before), &time(not_after)]);
+        let key_info = sequence(&[
+            &sequence(&[&oid(OID_EC_PUBLIC_KEY), &oid(OID_PRIME256V1)]),
+            &bit_string(public_key),
+        ]);
+        sequence(&[
+            &version,
+            &unsigned_integer(serial),
+            &signature_algorithm(),
+            &name(common_name),
+            &validity,
+            &name(common_name),
+            &key_info,
+        ])
+    }
+
+    pub fn certificate(tbs: &[u8], signature: &[u8]) -> Vec<u8> {
+        sequence(&[tbs, &signature_algorithm(), &bit_string(signature)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use rustls::{ClientConnection, ServerConnection};
+
+    use super::*;
+
+    /// Runs a TLS handshake between the two configs in memory.
+    fn handshake(
+        server: Arc<ServerConfig>,
+        client: Arc<ClientConfig>,
+    ) -> Result<(), rustls::Error> {
+        let name = ServerName::try_from("localsend").unwrap();
+        let mut client = ClientConnection::new(client, name)?;
+        let mut server = ServerConnection::new(server)?;
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut buf.as_slice()).unwrap();
+            server.process_new_packets()?;
+
+            let mut buf = Vec::new();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut buf.as_slice()).unwrap();
+            client.process_new_packets()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn generated_identity_serves_tls() {
+        let identity = TlsIdentity::generate().unwrap();
+        assert_eq!(fingerprint_of(&identity.cert), identity.fingerprint);
+        assert_eq!(identity.fingerprint.len(), 64);
+
+        let server = identity.server_config().unwrap();
+        let client = client_config(Some(identity.fingerprint.clone()), false);
+        handshake(server, client).unwrap();
+    }
+
+    #[test]
+    fn identities_differ() {
+        let a = TlsIdentity::generate().unwrap();
+        let b = TlsIdentity::generate().unwrap();
+        assert_ne!(a.fingerprint, b.fingerprint);
+    }
+
+    #[test]
+    fn saved_identity_loads_back() {
+        let dir = std::env::temp_dir().join(format!("tls_identity_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let identity = TlsIdentity::load_or_generate(&dir).unwrap();
+        let loaded = TlsIdentity::load(&dir).unwrap();
+        assert_eq!(loaded.fingerprint, identity.fingerprint);
+        assert_eq!(loaded.cert, identity.cert);
+        let again = TlsIdentity::load_or_generate(&dir).unwrap();
+        assert_eq!(again.fingerprint, identity.fingerprint);
+
+        // the loaded key still matches the certificate
+        let client = client_config(Some(identity.fingerprint.clone()), false);
+        handshake(loaded.server_config().unwrap(), client).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_refuses_a_broken_key() {
+        let dir = std::env::temp_dir().join(format!("tls_broken_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        TlsIdentity::generate().unwrap().save(&dir).unwrap();
+        fs::write(dir.join(KEY_FILE), b"not a key").unwrap();
+        assert!(TlsIdentity::load(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pinned_fingerprint_is_accepted() {
+        let identity = TlsIdentity::generate().unwrap();
+        let server = identity.server_config().unwrap();
+        handshake(
+            server.clone(),
+            client_config(Some(identity.fingerprint.clone()), false),
+        )
+        .unwrap();
+        // announces may carry the hex in upper case
+        let upper = identity.fingerprint.to_uppercase();
+        handshake(server, client_config(Some(upper), false)).unwrap();
+    }
+
+    #[test]
+    fn other_fingerprint_is_refused() {
+        let identity = TlsIdentity::generate().unwrap();
+        let other = TlsIdentity::generate().unwrap().fingerprint;
+        let err = handshake(
+            identity.server_config().unwrap(),
+            client_config(Some(other), false),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)
+        );
+    }
+
+    #[test]
+    fn missing_fingerprint_is_refused() {
+        let identity = TlsIdentity::generate().unwrap();
+        let err = handshake(
+            identity.server_config().unwrap(),
+            client_config(None, false),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)
+        );
+    }
+
+    #[test]
+    fn insecure_accepts_any_certificate() {
+        let identity = TlsIdentity::generate().unwrap();
+        let server = identity.server_config().unwrap();
+        handshake(server.clone(), client_config(None, true)).unwrap();
+        let other = TlsIdentity::generate().unwrap().fingerprint;
+        handshake(server, client_config(Some(other), true)).unwrap();
+    }
+
+    #[test]
+    fn dates_convert_to_civil() {
+        assert_eq!(der::civil_from_days(0), (1970, 1, 1));
+        assert_eq!(der::civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(der::civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(der::civil_from_days(29220), (2050, 1, 1));
+    }
+
+    #[test]
+    fn times_switch_encoding_at_2050() {
+        // 2049-12-31 23:59:59 and 2050-01-01 00:00:00
+        assert_eq!(der::time(2524607999), b"\x17\x0d491231235959Z".to_vec());
+        assert_eq!(der::time(2524608000), b"\x18\x0f20500101000000Z".to_vec());
+    }
+
+    #[test]
+    fn lengths_use_the_long_form_past_127() {
+        assert_eq!(der::tlv(0x04, &[0; 127])[..2], [0x04, 0x7f]);
+        assert_eq!(der::tlv(0x04, &[0; 128])[..3], [0x04, 0x81, 0x80]);
+        assert_eq!(der::tlv(0x04, &[0; 256])[..4], [0x04, 0x82, 0x01, 0x00]);
+    }
+}
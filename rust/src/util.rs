@@ -1,10 +1,15 @@
-use std::io::Result;
+use std::io::{Read, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use parking_lot::Mutex;
 use pin_project_lite::pin_project;
-use tokio::io::AsyncWrite;
+use ring::digest;
+use tokio::io::{AsyncReadExt, AsyncWrite};
 use tokio::sync::watch::Sender;
 use tokio::time::{interval, Interval};
 
@@ -27,6 +32,12 @@ impl<R: AsyncWrite> ProgressWriteAdapter<R> {
             tx,
         }
     }
+
+    /// Counts from `offset` instead of zero, for resumed writes.
+    pub fn starting_at(mut self, offset: usize) -> Self {
+        self.interval_bytes = offset;
+        self
+    }
 }
 
 impl<R: AsyncWrite> AsyncWrite for ProgressWriteAdapter<R> {
@@ -34,13 +45,8 @@ impl<R: AsyncWrite> AsyncWrite for ProgressWriteAdapter<R> {
         let this = self.project();
 
         let result = this.inner.poll_write(cx, buf);
-        match result {
-            Poll::Ready(ref res) => {
-                if res.is_ok() {
-                    *this.interval_bytes = *this.interval_bytes + res.as_ref().clone().unwrap();
-                }
-            }
-            _ => {}
+        if let Poll::Ready(Ok(size)) = &result {
+            *this.interval_bytes += size;
         }
 
         match this.interval.poll_tick(cx) {
@@ -64,3 +70,682 @@ impl<R: AsyncWrite> AsyncWrite for ProgressWriteAdapter<R> {
         self.project().inner.poll_shutdown(cx)
     }
 }
+
+pin_project! {
+    /// Hashes everything written through it with SHA-256, or passes writes
+    /// straight through when created disabled.
+    pub struct HashWriteAdapter<W: AsyncWrite> {
+        #[pin]
+        inner: W,
+        context: Option<digest::Context>,
+    }
+}
+
+impl<W: AsyncWrite> HashWriteAdapter<W> {
+    pub fn new(inner: W, enabled: bool) -> Self {
+        Self {
+            inner,
+            context: enabled.then(|| digest::Context::new(&digest::SHA256)),
+        }
+    }
+
+    /// Continues hashing from `context`, e.g. one fed with the bytes a resumed
+    /// write appends to. `None` passes writes straight through.
+    pub fn resume(inner: W, context: Option<digest::Context>) -> Self {
+        Self { inner, context }
+    }
+
+    /// Lowercase hex digest of the written bytes, `None` when disabled.
+    pub fn finish(self) -> Option<String> {
+        self.context
+            .map(|context| hex_string(context.finish().as_ref()))
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for HashWriteAdapter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.project();
+
+        let result = this.inner.poll_write(cx, buf);
+        if let (Poll::Ready(Ok(size)), Some(context)) = (&result, this.context.as_mut()) {
+            context.update(&buf[..*size]);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+/// Token bucket limiting a byte rate. Holds at most 100ms worth of bytes,
+/// so waits stay short and a throttled reader keeps reporting progress.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        TokenBucket {
+            rate: bytes_per_sec.max(1),
+            tokens: 0.0,
+            last: Instant::now(),
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        (self.rate as f64 / 10.0).max(1.0)
+    }
+
+    /// Takes up to `wanted` bytes worth of tokens and returns how many were
+    /// granted, or how long to wait when the bucket is empty.
+    pub fn take(&mut self, wanted: usize) -> std::result::Result<usize, Duration> {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate as f64;
+        self.tokens = (self.tokens + refill).min(self.capacity());
+        self.last = now;
+        if self.tokens < 1.0 {
+            return Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.rate as f64,
+            ));
+        }
+        let granted = (wanted as f64).min(self.tokens.floor());
+        self.tokens -= granted;
+        Ok(granted as usize)
+    }
+}
+
+/// Blocking reader that only reads as fast as a shared [`TokenBucket`]
+/// allows, for request bodies sent from the blocking pool.
+pub struct ThrottledRead<R> {
+    inner: R,
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl<R: Read> ThrottledRead<R> {
+    pub fn new(inner: R, bucket: Arc<Mutex<TokenBucket>>) -> Self {
+        Self { inner, bucket }
+    }
+}
+
+impl<R: Read> Read for ThrottledRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let granted = self.bucket.lock().take(buf.len());
+            match granted {
+                Ok(granted) => {
+                    let size = self.inner.read(&mut buf[..granted])?;
+                    // hand back what the inner reader didn't use
+                    self.bucket.lock().tokens += (granted - size) as f64;
+                    return Ok(size);
+                }
+                Err(wait) => std::thread::sleep(wait),
+            }
+        }
+    }
+}
+
+/// Blocking reader counting the bytes read through it into `tx`, at most
+/// every 100ms and once more at the end, for request bodies sent from the
+/// blocking pool.
+pub struct ProgressRead<R> {
+    inner: R,
+    bytes: usize,
+    last_sent: Instant,
+    tx: Sender<usize>,
+}
+
+impl<R: Read> ProgressRead<R> {
+    pub fn new(inner: R, tx: Sender<usize>) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            last_sent: Instant::now(),
+            tx,
+        }
+    }
+
+    /// Counts from `offset` instead of zero, for resumed reads.
+    pub fn starting_at(mut self, offset: usize) -> Self {
+        self.bytes = offset;
+        self
+    }
+}
+
+impl<R: Read> Read for ProgressRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let size = self.inner.read(buf)?;
+        self.bytes += size;
+        if size == 0 || self.last_sent.elapsed() >= Duration::from_millis(100) {
+            self.last_sent = Instant::now();
+            let bytes = self.bytes;
+            // the end may be read more than once
+            self.tx.send_if_modified(|sent| {
+                let changed = *sent != bytes;
+                *sent = bytes;
+                changed
+            });
+        }
+        Ok(size)
+    }
+}
+
+/// SHA-256 of a file as lowercase hex, read in chunks so large files don't
+/// end up in memory. Meant for filling `FileInfo::sha256` before sending.
+pub async fn sha256_file(path: &Path) -> Result<String> {
+    sha256_prefix(path, u64::MAX).await
+}
+
+/// Like [`sha256_file`] but only over the first `len` bytes, which is how
+/// both ends of a resumed upload check they agree on what arrived.
+pub async fn sha256_prefix(path: &Path, len: u64) -> Result<String> {
+    let context = sha256_context(path, len).await?;
+    Ok(hex_string(context.finish().as_ref()))
+}
+
+/// SHA-256 state after the first `len` bytes of a file.
+pub async fn sha256_context(path: &Path, len: u64) -> Result<digest::Context> {
+    let file = tokio::fs::File::open(path).await?;
+    let mut file = file.take(len);
+    let mut context = digest::Context::new(&digest::SHA256);
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let size = file.read(&mut buf).await?;
+        if size == 0 {
+            break;
+        }
+        context.update(&buf[..size]);
+    }
+    Ok(context)
+}
+
+pub fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Names Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Makes a single path component declared by a sender safe to create on any
+/// platform. Characters Windows doesn't allow become `_` and trailing dots
+/// and spaces are dropped, as Windows would. Empty, `.`, `..` and reserved
+/// device names are refused.
+pub fn sanitize_file_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' | '/' | '\\' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim_end_matches(['.', ' ']);
+    if name.is_empty() {
+        return None;
+    }
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// Turns a file name declared by a sender into a path relative to the store
+/// directory. Folder sends use `/` separated names, each part sanitized with
+/// [`sanitize_file_name`], but nothing may climb out of the store directory
+/// or point at an absolute location.
+pub fn safe_relative_path(name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for part in name.split(['/', '\\']) {
+        if part.is_empty() || part == "." {
+            continue;
+        }
+        path.push(sanitize_file_name(part)?);
+    }
+    if path.as_os_str().is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+/// `path` if nothing is there yet, otherwise the first free of `name (1).ext`,
+/// `name (2).ext` and so on.
+pub fn unique_path(path: &Path) -> PathBuf {
+    unique_path_by(path, Path::exists)
+}
+
+/// Like `unique_path`, also skipping names whose part file is taken, so two
+/// uploads of the same name don't write to one part file.
+pub fn unique_part_target(path: &Path) -> PathBuf {
+    unique_path_by(path, |candidate| {
+        candidate.exists() || part_path(candidate).exists()
+    })
+}
+
+/// Where a file bound for `path` is written until its session completes.
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// The path a part file from `part_path` is bound for.
+pub fn part_target(part: &Path) -> PathBuf {
+    part.with_extension("")
+}
+
+fn unique_path_by(path: &Path, taken: impl Fn(&Path) -> bool) -> PathBuf {
+    if !taken(path) {
+        return path.to_path_buf();
+    }
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, ext)))
+        .find(|candidate| !taken(candidate))
+        .unwrap()
+}
+
+/// Formats the ip of `addr`, appending the zone index for scoped IPv6 addresses
+/// (e.g. `fe80::1%3`) so link-local peers stay reachable.
+pub fn scoped_ip_string(addr: &SocketAddr) -> String {
+    match addr {
+        SocketAddr::V6(v6) if v6.scope_id() != 0 => format!("{}%{}", v6.ip(), v6.scope_id()),
+        _ => addr.ip().to_string(),
+    }
+}
+
+/// Parses an address produced by [`scoped_ip_string`], returning the ip and its
+/// zone index (`0` when none is given).
+pub fn parse_scoped_ip(s: &str) -> Option<(IpAddr, u32)> {
+    match s.split_once('%') {
+        Some((ip, scope)) => {
+            let ip = ip.parse::<std::net::Ipv6Addr>().ok()?;
+            let scope = scope.parse::<u32>().ok()?;
+            Some((IpAddr::V6(ip), scope))
+        }
+        None => Some((s.parse::<IpAddr>().ok()?, 0)),
+    }
+}
+
+/// A range of addresses in CIDR notation, like `192.168.1.0/24`. A bare
+/// address parses as a range holding only itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Self> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        (prefix <= max).then_some(IpNet { addr, prefix })
+    }
+
+    /// Whether `ip` is in the range. v4 addresses mapped into v6, as dual
+    /// stack sockets report them, match v4 ranges.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("invalid subnet {}", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        IpNet::new(addr, prefix).ok_or_else(invalid)
+    }
+}
+
+impl std::fmt::Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// An address assigned to a local network interface.
+#[derive(Debug, Clone)]
+pub struct InterfaceAddr {
+    pub index: u32,
+    /// Like `eth0`, `en0` or `wlan0`.
+    pub name: String,
+    pub addr: IpAddr,
+    pub loopback: bool,
+}
+
+/// Lists the addresses of every interface that is up. Unix only, elsewhere
+/// the list is empty.
+#[cfg(unix)]
+pub fn interface_addrs() -> Vec<InterfaceAddr> {
+    let mut addrs = Vec::new();
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: the list is only read until freeifaddrs and every pointer is
+    // null checked before use.
+    unsafe {
+        if libc::getifaddrs(&mut ifap) != 0 {
+            return addrs;
+        }
+        let mut cur = ifap;
+        while !cur.is_null() {
+            let ifa = &*cur;
+            cur = ifa.ifa_next;
+
+            let flags = ifa.ifa_flags as libc::c_int;
+            if ifa.ifa_addr.is_null() || flags & libc::IFF_UP == 0 {
+                continue;
+            }
+            let addr = match (*ifa.ifa_addr).sa_family as libc::c_int {
+                libc::AF_INET => {
+                    let sin = &*(ifa.ifa_addr as *const libc::sockaddr_in);
+                    IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)))
+                }
+                libc::AF_INET6 => {
+                    let sin6 = &*(ifa.ifa_addr as *const libc::sockaddr_in6);
+                    IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr))
+                }
+                _ => continue,
+            };
+            addrs.push(InterfaceAddr {
+                index: libc::if_nametoindex(ifa.ifa_name),
+                name: std::ffi::CStr::from_ptr(ifa.ifa_name)
+                    .to_string_lossy()
+                    .into_owned(),
+                addr,
+                loopback: flags & libc::IFF_LOOPBACK != 0,
+            });
+        }
+        libc::freeifaddrs(ifap);
+    }
+    addrs
+}
+
+#[cfg(not(unix))]
+pub fn interface_addrs() -> Vec<InterfaceAddr> {
+    static UNSUPPORTED: std::sync::Once = std::sync::Once::new();
+    UNSUPPORTED.call_once(|| log::warn!("network interfaces can't be listed on this platform"));
+    Vec::new()
+}
+
+/// The current IPv4 address of the interface called `name`, for following
+/// an interface whose address is handed out dynamically. A link local one
+/// is only taken when the interface has nothing else. Always `None` off
+/// unix, see `interface_addrs`.
+pub fn interface_ipv4_by_name(name: &str) -> Option<Ipv4Addr> {
+    let v4_addrs = interface_addrs()
+        .into_iter()
+        .filter(|iface| iface.name == name)
+        .filter_map(|iface| match iface.addr {
+            IpAddr::V4(ip) => Some(ip),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    v4_addrs
+        .iter()
+        .find(|ip| !ip.is_link_local())
+        .or_else(|| v4_addrs.first())
+        .copied()
+}
+
+/// Bytes available to us on the volume holding `path`, which doesn't have
+/// to exist yet. `None` when it can't be determined.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let existing = path.ancestors().find(|dir| dir.exists())?;
+    let c_path = CString::new(existing.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: c_path is a valid nul terminated string and stat outlives the call.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        // the ULARGE_INTEGER out parameters are plain u64s
+        fn GetDiskFreeSpaceExW(
+            directory: *const u16,
+            free_to_caller: *mut u64,
+            total: *mut u64,
+            total_free: *mut u64,
+        ) -> i32;
+    }
+
+    let existing = path.ancestors().find(|dir| dir.exists())?;
+    let wide = existing
+        .as_os_str()
+        .encode_wide()
+        .chain(Some(0))
+        .collect::<Vec<u16>>();
+    let mut free = 0u64;
+    // SAFETY: wide is nul terminated, free outlives the call and the other
+    // outputs are optional.
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            wide.as_ptr(),
+            &mut free,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    (ok != 0).then_some(free)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    static UNSUPPORTED: std::sync::Once = std::sync::Once::new();
+    UNSUPPORTED.call_once(|| log::warn!("free space can't be checked on this platform"));
+    None
+}
+
+/// Picks the LAN address to announce on when none is configured: the first
+/// private IPv4 address of a non loopback interface, falling back to the
+/// address the OS would route the internet through. With `link_local`, a
+/// 169.254.x.x address comes next after the private ones, for links without
+/// dhcp. Where `interface_addrs` lists nothing only the fallback is tried.
+pub fn default_interface_v4(link_local: bool) -> Option<Ipv4Addr> {
+    let usable = |ip: &Ipv4Addr| !ip.is_loopback() && !ip.is_link_local() && !ip.is_unspecified();
+    let v4_addrs = || {
+        interface_addrs()
+            .into_iter()
+            .filter(|iface| !iface.loopback)
+            .filter_map(|iface| match iface.addr {
+                IpAddr::V4(ip) => Some(ip),
+                _ => None,
+            })
+    };
+
+    v4_addrs()
+        .find(|ip| usable(ip) && ip.is_private())
+        .or_else(|| {
+            v4_addrs()
+                .find(Ipv4Addr::is_link_local)
+                .filter(|_| link_local)
+        })
+        .or_else(|| {
+            // connecting a udp socket sends nothing but selects the route
+            let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+            socket.connect((Ipv4Addr::new(8, 8, 8, 8), 80)).ok()?;
+            match socket.local_addr().ok()?.ip() {
+                IpAddr::V4(ip) if usable(&ip) => Some(ip),
+                _ => None,
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Component;
+
+    fn stays_inside(path: &Path) -> bool {
+        path.components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    }
+
+    #[test]
+    fn sanitize_file_name_refuses_dots_and_reserved_names() {
+        for name in [
+            "",
+            ".",
+            "..",
+            "...",
+            " ",
+            ". .",
+            "CON",
+            "con",
+            "Con.txt",
+            "com1.txt",
+            "COM1",
+            "lpt9.tar.gz",
+            "NUL ",
+            "aux .txt",
+            "prn.",
+        ] {
+            assert_eq!(sanitize_file_name(name), None, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn sanitize_file_name_replaces_what_windows_forbids() {
+        let cases = [
+            ("a.txt", "a.txt"),
+            ("C:\\x", "C__x"),
+            ("a/b", "a_b"),
+            ("what?*.txt", "what__.txt"),
+            ("<tag>|\"q\"", "_tag___q_"),
+            ("bell\u{7}\n\t.txt", "bell___.txt"),
+            ("\0hidden", "_hidden"),
+            ("trailing. . ", "trailing"),
+            ("trailing dots...", "trailing dots"),
+            ("console.log", "console.log"),
+            ("com10.txt", "com10.txt"),
+            (" leading", " leading"),
+            ("ünïcødé ☃.txt", "ünïcødé ☃.txt"),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(
+                sanitize_file_name(name).as_deref(),
+                Some(expected),
+                "{:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn safe_relative_path_never_leaves_the_store() {
+        let cases = [
+            ("../../etc/passwd", None),
+            ("a/../../b", None),
+            ("..\\..\\windows\\system32", None),
+            ("/etc/passwd", Some("etc/passwd")),
+            ("\\\\server\\share\\x", Some("server/share/x")),
+            ("C:\\x", Some("C_/x")),
+            ("C:/Windows/win.ini", Some("C_/Windows/win.ini")),
+            ("folder/./sub//a.txt", Some("folder/sub/a.txt")),
+            ("folder/CON/a.txt", None),
+            ("folder/sub./a.txt", Some("folder/sub/a.txt")),
+            ("", None),
+            ("/", None),
+            ("./.", None),
+        ];
+        for (name, expected) in cases {
+            let path = safe_relative_path(name);
+            assert_eq!(
+                path,
+                expected.map(|expected| expected.split('/').collect::<PathBuf>()),
+                "{:?}",
+                name
+            );
+            if let Some(path) = path {
+                assert!(stays_inside(&path), "{:?} gave {:?}", name, path);
+            }
+        }
+    }
+
+    #[test]
+    fn safe_relative_path_of_control_characters() {
+        let path = safe_relative_path("dir\u{1b}[31m/a\rb.txt").unwrap();
+        assert_eq!(path, ["dir_[31m", "a_b.txt"].iter().collect::<PathBuf>());
+        assert!(stays_inside(&path));
+    }
+
+    #[test]
+    fn unique_path_skips_taken_names() {
+        let dir = std::env::temp_dir().join(format!("unique_path_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let target = dir.join("a.txt");
+        assert_eq!(unique_path(&target), target);
+        std::fs::write(&target, b"").unwrap();
+        assert_eq!(unique_path(&target), dir.join("a (1).txt"));
+        std::fs::write(dir.join("a (1).txt"), b"").unwrap();
+        assert_eq!(unique_path(&target), dir.join("a (2).txt"));
+
+        let bare = dir.join("README");
+        std::fs::write(&bare, b"").unwrap();
+        assert_eq!(unique_path(&bare), dir.join("README (1)"));
+
+        // a part file in the way counts as taken for uploads
+        std::fs::write(part_path(&dir.join("b.txt")), b"").unwrap();
+        assert_eq!(unique_path(&dir.join("b.txt")), dir.join("b.txt"));
+        assert_eq!(
+            unique_part_target(&dir.join("b.txt")),
+            dir.join("b (1).txt")
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}